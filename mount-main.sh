@@ -1,7 +1,7 @@
 set -euxo pipefail
 
 cargo build
-RUST_BACKTRACE=full RUST_LOG=trace ./target/debug/nimbus --local-storage storage-main --mount-directory mount-main --config config/main.toml
+RUST_BACKTRACE=full RUST_LOG=trace ./target/debug/nimbus mount --local-storage storage-main --mount-directory mount-main --config config/main.toml
 
 # cargo build --release
-# RUST_LOG=error ./target/release/nimbus --local-storage storage --mount-directory mount
+# RUST_LOG=error ./target/release/nimbus mount --local-storage storage --mount-directory mount
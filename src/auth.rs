@@ -0,0 +1,454 @@
+use chrono::prelude::*;
+use futures_util::{Stream, StreamExt};
+use hmac::{Hmac, Mac};
+use log::warn;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use warp::http::{HeaderMap, Method};
+use warp::hyper::body::{Buf, Bytes};
+use warp::path::FullPath;
+use warp::{Filter, Rejection};
+
+use crate::config::Config;
+
+pub const MACHINE_HEADER: &str = "x-nimbus-machine";
+pub const TIMESTAMP_HEADER: &str = "x-nimbus-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-nimbus-signature";
+pub const NONCE_HEADER: &str = "x-nimbus-nonce";
+
+/// How far apart (in seconds) our clock and a peer's may be before requests are refused
+pub const MAX_CLOCK_SKEW: i64 = 60;
+/// Largest body a peer request may carry (none of ours come close)
+pub const MAX_BODY: usize = 16 * 1024;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Shared secret per peer machine name
+pub type PeerSecrets = Arc<RwLock<HashMap<String, String>>>;
+
+fn secrets(config: &Config) -> HashMap<String, String> {
+    config
+        .network
+        .iter()
+        .filter_map(|(name, peer)| {
+            if peer.secret.is_none() {
+                warn!("peer {} has no secret, its requests will be rejected", name);
+            }
+            peer.secret.clone().map(|secret| (name.clone(), secret))
+        })
+        .collect()
+}
+
+pub fn peer_secrets(config: &Config) -> PeerSecrets {
+    Arc::new(RwLock::new(secrets(config)))
+}
+
+// Peers missing from a reloaded config are refused from the next request on
+pub fn set_peer_secrets(peer_secrets: &PeerSecrets, config: &Config) {
+    *peer_secrets.write().expect("lock failed") = secrets(config);
+}
+
+fn mac(secret: &str, parts: &[&[u8]]) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any size");
+    for part in parts {
+        mac.update(part);
+        mac.update(b"\n");
+    }
+    mac
+}
+
+fn verify(mac: HmacSha256, signature: &str) -> bool {
+    match hex::decode(signature) {
+        Ok(signature) => mac.verify_slice(&signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+// Unique per request, so two identical requests within a second are told apart from a replay
+pub fn nonce() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("{:x}-{:x}", now, COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+pub fn sign_request(
+    secret: &str,
+    method: &str,
+    path: &str,
+    machine: &str,
+    timestamp: i64,
+    nonce: &str,
+    body: &[u8],
+) -> String {
+    let timestamp = timestamp.to_string();
+    let parts: [&[u8]; 6] = [
+        method.as_bytes(),
+        path.as_bytes(),
+        machine.as_bytes(),
+        timestamp.as_bytes(),
+        nonce.as_bytes(),
+        body,
+    ];
+    hex::encode(mac(secret, &parts).finalize().into_bytes())
+}
+
+// Responses are bound to the request they answer, so they can't be replayed elsewhere
+pub fn sign_response(secret: &str, request_signature: &str, status: u16, body: &[u8]) -> String {
+    let status = status.to_string();
+    let parts: [&[u8]; 3] = [request_signature.as_bytes(), status.as_bytes(), body];
+    hex::encode(mac(secret, &parts).finalize().into_bytes())
+}
+
+pub fn verify_response(
+    secret: &str,
+    request_signature: &str,
+    status: u16,
+    body: &[u8],
+    signature: &str,
+) -> bool {
+    let status = status.to_string();
+    let parts: [&[u8]; 3] = [request_signature.as_bytes(), status.as_bytes(), body];
+    verify(mac(secret, &parts), signature)
+}
+
+// Announcements are signed with the cluster secret, so strangers on the LAN can't join
+pub fn sign_announcement(secret: &str, payload: &[u8]) -> String {
+    hex::encode(mac(secret, &[payload]).finalize().into_bytes())
+}
+
+pub fn verify_announcement(secret: &str, payload: &[u8], signature: &str) -> bool {
+    verify(mac(secret, &[payload]), signature)
+}
+
+#[derive(Debug)]
+pub struct Unauthorized(pub String);
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// The request body was refused before its signature was checked
+#[derive(Debug)]
+pub enum BadBody {
+    /// Longer than MAX_BODY (or claiming to be)
+    TooLarge,
+    Unreadable(String),
+}
+
+impl warp::reject::Reject for BadBody {}
+
+/// Signatures accepted within the clock skew window, so a request can't be replayed
+#[derive(Clone, Debug, Default)]
+pub struct SeenSignatures(Arc<Mutex<HashMap<String, i64>>>);
+
+impl SeenSignatures {
+    // False if the signature was used before; forgets those too old to pass the skew check
+    fn first_use(&self, signature: &str, timestamp: i64) -> bool {
+        let now = Utc::now().timestamp();
+        let mut seen = self.0.lock().expect("lock failed");
+        seen.retain(|_, timestamp| (now - *timestamp).abs() <= MAX_CLOCK_SKEW);
+        seen.insert(signature.to_string(), timestamp).is_none()
+    }
+}
+
+/// A peer whose request signature checked out
+#[derive(Clone, Debug)]
+pub struct AuthenticatedPeer {
+    pub machine: String,
+    secret: String,
+    signature: String,
+}
+
+impl AuthenticatedPeer {
+    pub fn sign(&self, status: u16, body: &[u8]) -> String {
+        sign_response(&self.secret, &self.signature, status, body)
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Result<&'a str, Unauthorized> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| Unauthorized(format!("missing {} header", name)))
+}
+
+fn check(
+    secrets: &PeerSecrets,
+    seen: &SeenSignatures,
+    method: &Method,
+    path: &FullPath,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<AuthenticatedPeer, Unauthorized> {
+    let machine = header(headers, MACHINE_HEADER)?;
+    let signature = header(headers, SIGNATURE_HEADER)?;
+    let timestamp: i64 = header(headers, TIMESTAMP_HEADER)?
+        .parse()
+        .map_err(|_| Unauthorized("malformed timestamp".to_string()))?;
+    if (Utc::now().timestamp() - timestamp).abs() > MAX_CLOCK_SKEW {
+        return Err(Unauthorized(format!("stale request from {}", machine)));
+    }
+
+    let secret = secrets
+        .read()
+        .expect("lock failed")
+        .get(machine)
+        .cloned()
+        .ok_or_else(|| Unauthorized(format!("unknown peer {}", machine)))?;
+    let nonce = header(headers, NONCE_HEADER)?;
+    let signed_timestamp = timestamp.to_string();
+    let parts: [&[u8]; 6] = [
+        method.as_str().as_bytes(),
+        path.as_str().as_bytes(),
+        machine.as_bytes(),
+        signed_timestamp.as_bytes(),
+        nonce.as_bytes(),
+        body,
+    ];
+    if !verify(mac(&secret, &parts), signature) {
+        return Err(Unauthorized(format!("bad signature from {}", machine)));
+    }
+    if !seen.first_use(signature, timestamp) {
+        return Err(Unauthorized(format!("replayed request from {}", machine)));
+    }
+    Ok(AuthenticatedPeer {
+        machine: machine.to_string(),
+        secret,
+        signature: signature.to_string(),
+    })
+}
+
+// Reads at most MAX_BODY bytes, whatever the headers claim, as nothing is checked yet
+async fn read_body<S, B>(length: Option<u64>, stream: S) -> Result<Bytes, Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    if length.map_or(false, |length| length > MAX_BODY as u64) {
+        return Err(warp::reject::custom(BadBody::TooLarge));
+    }
+    let mut stream = Box::pin(stream);
+    let mut body = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        let mut chunk =
+            chunk.map_err(|err| warp::reject::custom(BadBody::Unreadable(err.to_string())))?;
+        if body.len() + chunk.remaining() > MAX_BODY {
+            return Err(warp::reject::custom(BadBody::TooLarge));
+        }
+        body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+    Ok(Bytes::from(body))
+}
+
+fn limited_body() -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    warp::header::optional::<u64>("content-length")
+        .and(warp::body::stream())
+        .and_then(read_body)
+}
+
+// Extracts the authenticated peer along with the raw body it signed
+pub fn authenticate(
+    secrets: PeerSecrets,
+) -> impl Filter<Extract = (AuthenticatedPeer, Bytes), Error = Rejection> + Clone {
+    let seen = SeenSignatures::default();
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and(limited_body())
+        .and_then(
+            move |method: Method, path: FullPath, headers: HeaderMap, body: Bytes| {
+                let secrets = secrets.clone();
+                let seen = seen.clone();
+                async move {
+                    match check(&secrets, &seen, &method, &path, &headers, &body) {
+                        Ok(peer) => Ok((peer, body)),
+                        Err(unauthorized) => {
+                            warn!("rejected request: {}", unauthorized.0);
+                            Err(warp::reject::custom(unauthorized))
+                        }
+                    }
+                }
+            },
+        )
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "auth-test-secret";
+
+    fn secrets() -> PeerSecrets {
+        Arc::new(RwLock::new(HashMap::from([(
+            "peer".to_string(),
+            SECRET.to_string(),
+        )])))
+    }
+
+    fn request(
+        timestamp: i64,
+        nonce: &str,
+        body: &[u8],
+        signature: &str,
+    ) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .method("POST")
+            .path("/v1/projects/project/lock")
+            .header(MACHINE_HEADER, "peer")
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(NONCE_HEADER, nonce)
+            .header(SIGNATURE_HEADER, signature)
+            .body(body.to_vec())
+    }
+
+    fn signed(timestamp: i64, nonce: &str, body: &[u8]) -> warp::test::RequestBuilder {
+        let signature = sign_request(
+            SECRET,
+            "POST",
+            "/v1/projects/project/lock",
+            "peer",
+            timestamp,
+            nonce,
+            body,
+        );
+        request(timestamp, nonce, body, &signature)
+    }
+
+    fn unauthorized(rejection: Rejection) -> String {
+        match rejection.find() {
+            Some(Unauthorized(reason)) => reason.clone(),
+            _ => panic!("not unauthorized: {:?}", rejection),
+        }
+    }
+
+    #[tokio::test]
+    async fn accepts_signed_requests() {
+        let body = br#"{"machine":"peer"}"#;
+        let (peer, received) = signed(Utc::now().timestamp(), "nonce", body)
+            .filter(&authenticate(secrets()))
+            .await
+            .unwrap();
+        assert_eq!(peer.machine, "peer");
+        assert_eq!(&received[..], body);
+
+        // and the reply is signed for the request it answers
+        let signature = peer.sign(200, b"reply");
+        assert!(verify_response(
+            SECRET,
+            &peer.signature,
+            200,
+            b"reply",
+            &signature
+        ));
+        assert!(!verify_response(
+            SECRET,
+            &peer.signature,
+            200,
+            b"other",
+            &signature
+        ));
+        assert!(!verify_response(
+            SECRET,
+            &peer.signature,
+            409,
+            b"reply",
+            &signature
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_signatures() {
+        let now = Utc::now().timestamp();
+        let filter = authenticate(secrets());
+        let forged = sign_request(
+            "other",
+            "POST",
+            "/v1/projects/project/lock",
+            "peer",
+            now,
+            "nonce",
+            b"",
+        );
+        let rejection = request(now, "nonce", b"", &forged)
+            .filter(&filter)
+            .await
+            .unwrap_err();
+        assert_eq!(unauthorized(rejection), "bad signature from peer");
+
+        // signed for another body
+        let signature = sign_request(
+            SECRET,
+            "POST",
+            "/v1/projects/project/lock",
+            "peer",
+            now,
+            "nonce",
+            b"",
+        );
+        let rejection = request(now, "nonce", b"tampered", &signature)
+            .filter(&filter)
+            .await
+            .unwrap_err();
+        assert_eq!(unauthorized(rejection), "bad signature from peer");
+        let rejection = request(now, "nonce", b"", "not hex")
+            .filter(&filter)
+            .await
+            .unwrap_err();
+        assert_eq!(unauthorized(rejection), "bad signature from peer");
+    }
+
+    #[tokio::test]
+    async fn rejects_stale_requests() {
+        let stale = Utc::now().timestamp() - MAX_CLOCK_SKEW - 1;
+        let rejection = signed(stale, "nonce", b"")
+            .filter(&authenticate(secrets()))
+            .await
+            .unwrap_err();
+        assert_eq!(unauthorized(rejection), "stale request from peer");
+    }
+
+    #[tokio::test]
+    async fn rejects_replayed_requests() {
+        let now = Utc::now().timestamp();
+        let filter = authenticate(secrets());
+        assert!(signed(now, "1", b"once").filter(&filter).await.is_ok());
+        let rejection = signed(now, "1", b"once").filter(&filter).await.unwrap_err();
+        assert_eq!(unauthorized(rejection), "replayed request from peer");
+        // the same request sent again within the second carries another nonce
+        assert!(signed(now, "2", b"once").filter(&filter).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejects_oversize_bodies_before_checking_them() {
+        let body = vec![b'x'; MAX_BODY + 1];
+        let rejection = signed(Utc::now().timestamp(), "nonce", &body)
+            .filter(&authenticate(secrets()))
+            .await
+            .unwrap_err();
+        assert!(matches!(rejection.find(), Some(BadBody::TooLarge)));
+    }
+
+    #[test]
+    fn forgets_signatures_past_the_skew_window() {
+        let seen = SeenSignatures::default();
+        let old = Utc::now().timestamp() - MAX_CLOCK_SKEW - 1;
+        assert!(seen.first_use("old", old));
+        assert!(seen.first_use("new", Utc::now().timestamp()));
+        assert!(!seen.0.lock().unwrap().contains_key("old"));
+        assert!(!seen.first_use("new", Utc::now().timestamp()));
+    }
+
+    #[test]
+    fn verifies_announcements() {
+        let signature = sign_announcement(SECRET, b"announcement");
+        assert!(verify_announcement(SECRET, b"announcement", &signature));
+        assert!(!verify_announcement("other", b"announcement", &signature));
+        assert!(!verify_announcement(SECRET, b"tampered", &signature));
+    }
+}
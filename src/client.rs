@@ -0,0 +1,285 @@
+use chrono::prelude::*;
+use log::warn;
+use reqwest::{Method, StatusCode, Url};
+use serde::Serialize;
+use std::time::Duration;
+
+use crate::auth::{
+    nonce, sign_request, verify_response, MACHINE_HEADER, NONCE_HEADER, SIGNATURE_HEADER,
+    TIMESTAMP_HEADER,
+};
+use crate::config::Config;
+use crate::index::LockResponse;
+use crate::server::{PeerRequest, Pong};
+use crate::status::SyncState;
+use crate::tls::{peer_client, uses_tls};
+
+/// How long a peer may take to push a project over to us, on top of answering
+pub const SYNC_TIMEOUT: Duration = Duration::from_secs(3600);
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The peer is not in [network] or has no secret configured
+    UnknownPeer(String),
+    /// The TLS settings of the peer could not be loaded
+    Tls(std::io::Error),
+    Unreachable(reqwest::Error),
+    /// The reply was not signed with the secret we share with the peer
+    BadSignature,
+    Unexpected(StatusCode, String),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LockReply {
+    Granted(LockResponse),
+    /// The lock is held by someone else (or we don't hold what we tried to release)
+    Conflict(LockResponse),
+    UnknownProject,
+}
+
+/// Talks to a single peer, signing every request and checking every reply
+#[derive(Clone)]
+pub struct PeerClient {
+    /// Our own machine name
+    machine: String,
+    peer: String,
+    base_url: String,
+    secret: String,
+    client: reqwest::Client,
+}
+
+impl PeerClient {
+    pub fn new(config: &Config, peer: &str) -> Result<PeerClient, ClientError> {
+        let network = config
+            .network
+            .get(peer)
+            .ok_or_else(|| ClientError::UnknownPeer(peer.to_string()))?;
+        let secret = network
+            .secret
+            .clone()
+            .ok_or_else(|| ClientError::UnknownPeer(peer.to_string()))?;
+        let scheme = if uses_tls(network) { "https" } else { "http" };
+        Ok(PeerClient {
+            machine: config.machine.name.clone(),
+            peer: peer.to_string(),
+            base_url: format!("{}://{}", scheme, network.endpoint),
+            secret,
+            client: peer_client(network).map_err(ClientError::Tls)?,
+        })
+    }
+
+    // Clients for every peer we can talk to, skipping (and logging) the others
+    pub fn all(config: &Config) -> Vec<PeerClient> {
+        config
+            .network
+            .keys()
+            .filter_map(|peer| match PeerClient::new(config, peer) {
+                Ok(client) => Some(client),
+                Err(err) => {
+                    warn!("unable to talk to peer {}: {:?}", peer, err);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    async fn call<T: Serialize>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&T>,
+    ) -> Result<(StatusCode, Vec<u8>), ClientError> {
+        self.call_within(method, path, body, None).await
+    }
+
+    // timeout replaces the client's REQUEST_TIMEOUT
+    async fn call_within<T: Serialize>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&T>,
+        timeout: Option<Duration>,
+    ) -> Result<(StatusCode, Vec<u8>), ClientError> {
+        let url = Url::parse(&format!("{}{}", self.base_url, path))
+            .map_err(|_| ClientError::UnknownPeer(self.peer.clone()))?;
+        let body = match body {
+            Some(body) => serde_json::to_vec(body).expect("Could not serialize request"),
+            None => Vec::new(),
+        };
+        let timestamp = Utc::now().timestamp();
+        let nonce = nonce();
+        // sign the path the way the server will see it (percent-encoded)
+        let signature = sign_request(
+            &self.secret,
+            method.as_str(),
+            url.path(),
+            &self.machine,
+            timestamp,
+            &nonce,
+            &body,
+        );
+
+        let mut request = self
+            .client
+            .request(method, url)
+            .header(MACHINE_HEADER, &self.machine)
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(NONCE_HEADER, &nonce)
+            .header(SIGNATURE_HEADER, &signature)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body);
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+        let response = request.send().await.map_err(ClientError::Unreachable)?;
+        let status = response.status();
+        let response_signature = response
+            .headers()
+            .get(SIGNATURE_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let body = response
+            .bytes()
+            .await
+            .map_err(ClientError::Unreachable)?
+            .to_vec();
+
+        match response_signature {
+            Some(response_signature)
+                if verify_response(
+                    &self.secret,
+                    &signature,
+                    status.as_u16(),
+                    &body,
+                    &response_signature,
+                ) =>
+            {
+                Ok((status, body))
+            }
+            // unsigned replies only ever carry an error (e.g. the peer rejected our signature)
+            None if !status.is_success() => Err(ClientError::Unexpected(
+                status,
+                String::from_utf8_lossy(&body).into_owned(),
+            )),
+            _ => Err(ClientError::BadSignature),
+        }
+    }
+
+    async fn lock_call(
+        &self,
+        method: Method,
+        path: &str,
+        request: Option<PeerRequest>,
+    ) -> Result<LockReply, ClientError> {
+        let (status, body) = self.call(method, path, request.as_ref()).await?;
+        let lock_response = || {
+            serde_json::from_slice::<LockResponse>(&body).map_err(|_| {
+                ClientError::Unexpected(status, String::from_utf8_lossy(&body).into_owned())
+            })
+        };
+        match status {
+            StatusCode::OK => Ok(LockReply::Granted(lock_response()?)),
+            StatusCode::CONFLICT => Ok(LockReply::Conflict(lock_response()?)),
+            StatusCode::NOT_FOUND => Ok(LockReply::UnknownProject),
+            _ => Err(ClientError::Unexpected(
+                status,
+                String::from_utf8_lossy(&body).into_owned(),
+            )),
+        }
+    }
+
+    fn peer_request(&self, epoch: Option<u64>) -> Option<PeerRequest> {
+        Some(PeerRequest {
+            machine: self.machine.clone(),
+            epoch,
+            steal: false,
+        })
+    }
+
+    pub async fn acquire_project_lock(&self, project: &str) -> Result<LockReply, ClientError> {
+        let path = format!("/v1/projects/{}/lock", project);
+        self.lock_call(Method::POST, &path, self.peer_request(None))
+            .await
+    }
+
+    // Our vote request when taking the lock through a quorum, at the proposed epoch
+    pub async fn propose_project_lock(
+        &self,
+        project: &str,
+        epoch: u64,
+        steal: bool,
+    ) -> Result<LockReply, ClientError> {
+        let path = format!("/v1/projects/{}/lock", project);
+        let request = PeerRequest {
+            machine: self.machine.clone(),
+            epoch: Some(epoch),
+            steal,
+        };
+        self.lock_call(Method::POST, &path, Some(request)).await
+    }
+
+    pub async fn release_project_lock(
+        &self,
+        project: &str,
+        epoch: u64,
+    ) -> Result<LockReply, ClientError> {
+        let path = format!("/v1/projects/{}/release", project);
+        self.lock_call(Method::POST, &path, self.peer_request(Some(epoch)))
+            .await
+    }
+
+    pub async fn project_lock(&self, project: &str) -> Result<LockReply, ClientError> {
+        let path = format!("/v1/projects/{}/lock", project);
+        self.lock_call(Method::GET, &path, None).await
+    }
+
+    pub async fn acquire_index_lock(&self) -> Result<LockReply, ClientError> {
+        self.lock_call(Method::POST, "/v1/index/lock", self.peer_request(None))
+            .await
+    }
+
+    pub async fn release_index_lock(&self, epoch: u64) -> Result<LockReply, ClientError> {
+        self.lock_call(
+            Method::POST,
+            "/v1/index/release",
+            self.peer_request(Some(epoch)),
+        )
+        .await
+    }
+
+    // Signed both ways, so an answer means the peer is really there
+    pub async fn ping(&self) -> Result<Pong, ClientError> {
+        let (status, body) = self
+            .call(Method::GET, "/v1/ping", None::<&PeerRequest>)
+            .await?;
+        let unexpected =
+            || ClientError::Unexpected(status, String::from_utf8_lossy(&body).into_owned());
+        if status != StatusCode::OK {
+            return Err(unexpected());
+        }
+        serde_json::from_slice(&body).map_err(|_| unexpected())
+    }
+
+    // Asks the peer to push its copy of project over to us
+    pub async fn request_sync(&self, project: &str) -> Result<SyncState, ClientError> {
+        let path = format!("/v1/projects/{}/sync", project);
+        let (status, body) = self
+            .call_within(
+                Method::POST,
+                &path,
+                self.peer_request(None).as_ref(),
+                Some(SYNC_TIMEOUT),
+            )
+            .await?;
+        let unexpected =
+            || ClientError::Unexpected(status, String::from_utf8_lossy(&body).into_owned());
+        if status != StatusCode::OK {
+            return Err(unexpected());
+        }
+        serde_json::from_slice(&body).map_err(|_| unexpected())
+    }
+}
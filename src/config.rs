@@ -1,33 +1,677 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
+use std::time::Duration;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+use crate::error::{NimbusError, Result};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum MachineMode {
     DevelopmentMode,
     BackupMode,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
 pub struct MachineConfig {
     pub name: String,
     pub mode: MachineMode,
     pub endpoint: String,
+    /// Default number of seconds a project lock is kept after its last reference is dropped
+    #[serde(default = "default_grace_period")]
+    pub grace_period: u64,
+    /// Where the local control socket lives (defaults to next to local_storage)
+    #[serde(default)]
+    pub control_socket: Option<PathBuf>,
+    /// Serve the peer API over TLS instead of plain HTTP
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+pub fn default_grace_period() -> u64 {
+    60
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate (chain) presented to peers
+    pub cert: PathBuf,
+    /// PEM private key of the certificate
+    pub key: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectConfig {
+    /// Overrides the machine's grace period for this project
+    pub grace_period: Option<u64>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
 pub struct NetworkMachineConfig {
+    /// Has to match X in [network.X] (optional, it only guards against copy-paste mistakes)
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Copies a project from {HERE} (our copy) to {THERE} (the peer's copy)
     pub command: String,
     pub endpoint: String,
+    /// The peer's local storage, as {THERE} paths are built from it
+    #[serde(default)]
+    pub storage: Option<PathBuf>,
+    /// Shared secret this peer signs its requests (and our replies) with
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// PEM CA certificate the peer's TLS certificate must chain up to
+    #[serde(default)]
+    pub ca: Option<PathBuf>,
+    /// SHA-256 fingerprint (hex) the peer's TLS certificate must match
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Where the projects are stored on disk (--local-storage overrides it)
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct MountConfig {
+    /// Where nimbus is mounted (--mount-directory overrides it)
+    #[serde(default)]
+    pub directory: Option<PathBuf>,
+    /// Options as passed to `mount -o` (--option replaces them)
+    #[serde(default = "default_mount_options")]
+    pub options: Vec<String>,
+}
+
+fn default_mount_options() -> Vec<String> {
+    vec!["noatime".to_string(), "default_permissions".to_string()]
+}
+
+impl Default for MountConfig {
+    fn default() -> MountConfig {
+        MountConfig {
+            directory: None,
+            options: default_mount_options(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct TuningConfig {
+    /// How long the kernel may cache file attributes, in milliseconds
+    #[serde(default = "default_ttl")]
+    pub attr_ttl_ms: u64,
+    /// How long the kernel may cache name lookups, in milliseconds
+    #[serde(default = "default_ttl")]
+    pub entry_ttl_ms: u64,
+    /// Capacity of the buffer writes sit in until the next flush, in bytes
+    #[serde(default = "default_write_buffer")]
+    pub write_buffer: usize,
+    /// How often processes working inside projects are checked on, in milliseconds
+    #[serde(default = "default_polling_interval")]
+    pub polling_interval_ms: u64,
+    /// How long a lock lent to a peer stays valid without being renewed, in seconds
+    #[serde(default = "default_lock_lease")]
+    pub lock_lease: u64,
+    /// How often every peer is pinged, in milliseconds
+    #[serde(default = "default_probe_interval")]
+    pub probe_interval_ms: u64,
+    /// How long a ping may take before the peer counts as down, in milliseconds
+    #[serde(default = "default_probe_timeout")]
+    pub probe_timeout_ms: u64,
+}
+
+fn default_ttl() -> u64 {
+    1000
+}
+
+fn default_write_buffer() -> usize {
+    4096 * 32
+}
+
+fn default_polling_interval() -> u64 {
+    1000
+}
+
+fn default_lock_lease() -> u64 {
+    300
+}
+
+fn default_probe_interval() -> u64 {
+    5000
+}
+
+fn default_probe_timeout() -> u64 {
+    2000
+}
+
+impl Default for TuningConfig {
+    fn default() -> TuningConfig {
+        TuningConfig {
+            attr_ttl_ms: default_ttl(),
+            entry_ttl_ms: default_ttl(),
+            write_buffer: default_write_buffer(),
+            polling_interval_ms: default_polling_interval(),
+            lock_lease: default_lock_lease(),
+            probe_interval_ms: default_probe_interval(),
+            probe_timeout_ms: default_probe_timeout(),
+        }
+    }
+}
+
+impl TuningConfig {
+    pub fn attr_ttl(&self) -> Duration {
+        Duration::from_millis(self.attr_ttl_ms)
+    }
+
+    pub fn entry_ttl(&self) -> Duration {
+        Duration::from_millis(self.entry_ttl_ms)
+    }
+
+    pub fn polling_interval(&self) -> Duration {
+        Duration::from_millis(self.polling_interval_ms)
+    }
+
+    pub fn lock_lease(&self) -> Duration {
+        Duration::from_secs(self.lock_lease)
+    }
+
+    pub fn probe_interval(&self) -> Duration {
+        Duration::from_millis(self.probe_interval_ms)
+    }
+
+    pub fn probe_timeout(&self) -> Duration {
+        Duration::from_millis(self.probe_timeout_ms)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// Only instances announcing the same cluster are picked up
+    pub cluster: String,
+    /// Shared by the cluster: signs announcements and authenticates discovered peers
+    pub secret: String,
+    /// Copies a project to a discovered peer, like [network.X].command
+    pub command: String,
+    /// Multicast group (or broadcast address) announcements are sent to
+    #[serde(default = "default_discovery_address")]
+    pub address: String,
+    /// Local address of the interface to announce on (127.0.0.1 keeps it on this machine)
+    #[serde(default = "default_discovery_interface")]
+    pub interface: String,
+    /// Seconds between announcements; peers silent for three of them are dropped
+    #[serde(default = "default_discovery_interval")]
+    pub interval: u64,
+    /// PEM CA certificate discovered peers serving TLS must chain up to
+    #[serde(default)]
+    pub ca: Option<PathBuf>,
+}
+
+fn default_discovery_address() -> String {
+    "239.255.77.77:7477".to_string()
+}
+
+fn default_discovery_interface() -> String {
+    "0.0.0.0".to_string()
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+fn default_discovery_interval() -> u64 {
+    5
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub machine: MachineConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub mount: MountConfig,
+    #[serde(default)]
+    pub tuning: TuningConfig,
+    /// Find peers on the local network instead of (or on top of) listing them
+    #[serde(default)]
+    pub discovery: Option<DiscoveryConfig>,
+    #[serde(default)]
     pub network: HashMap<String, NetworkMachineConfig>,
+    #[serde(default)]
+    pub projects: HashMap<String, ProjectConfig>,
+}
+
+pub fn read_config(config_path: PathBuf) -> Result<Config> {
+    let contents = std::fs::read_to_string(&config_path)?;
+    let config = toml::from_str(&contents).map_err(|err| {
+        NimbusError::ConfigInvalid(format!(
+            "{}: {}",
+            config_path.display(),
+            parse_problem(&err, &contents)
+        ))
+    })?;
+    let problems = validate(&config, &contents);
+    if !problems.is_empty() {
+        let problems: Vec<String> = problems
+            .into_iter()
+            .map(|problem| format!("{}: {}", config_path.display(), problem))
+            .collect();
+        return Err(NimbusError::ConfigInvalid(problems.join("\n")));
+    }
+    Ok(config)
+}
+
+// Index of the first c in text outside of quotes
+fn find_unquoted(text: &str, c: char) -> Option<usize> {
+    let mut quote = None;
+    for (index, found) in text.char_indices() {
+        match (quote, found) {
+            (None, '"' | '\'') => quote = Some(found),
+            (Some(open), found) if found == open => quote = None,
+            (None, found) if found == c => return Some(index),
+            _ => (),
+        }
+    }
+    None
+}
+
+// The parts of a dotted key (or table name), unquoted: a."b.c" is [a, b.c]
+fn key_path(key: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut rest = key;
+    while let Some(dot) = find_unquoted(rest, '.') {
+        parts.push(&rest[..dot]);
+        rest = &rest[dot + 1..];
+    }
+    parts.push(rest);
+    parts
+        .into_iter()
+        .map(|part| {
+            part.trim()
+                .trim_matches(|c| c == '"' || c == '\'')
+                .to_string()
+        })
+        .collect()
+}
+
+// Whether the inline table value (or one nested in it) sets key
+fn inline_sets(value: &str, key: &[String]) -> bool {
+    value.split([',', '{']).any(|pair| {
+        find_unquoted(pair, '=').map_or(false, |equals| key_path(&pair[..equals]) == key)
+    })
+}
+
+// Line (1-based) of key in [table], or of where table is first defined, for pointing at
+// problems; follows quoted and dotted keys and inline tables
+fn line_of(contents: &str, table: &str, key: Option<&str>) -> Option<usize> {
+    let mut target = key_path(table);
+    target.extend(key.map(str::to_string));
+    let mut current: Vec<String> = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            let name = line.trim_start_matches('[');
+            current = key_path(&name[..find_unquoted(name, ']').unwrap_or(name.len())]);
+            if current == target {
+                return Some(number + 1);
+            }
+            continue;
+        }
+        let equals = match find_unquoted(line, '=') {
+            Some(equals) if !line.starts_with('#') => equals,
+            _ => continue,
+        };
+        let mut path = current.clone();
+        path.extend(key_path(&line[..equals]));
+        let value = line[equals + 1..].trim_start();
+        let found = if key.is_some() {
+            path == target
+                || (target.starts_with(&path)
+                    && value.starts_with('{')
+                    && inline_sets(value, &target[path.len()..]))
+        } else {
+            path.starts_with(&target)
+        };
+        if found {
+            return Some(number + 1);
+        }
+    }
+    None
+}
+
+// Text between the first pair of backticks after prefix
+fn quoted_after<'a>(message: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = &message[message.find(prefix)? + prefix.len()..];
+    rest.split('`').nth(1)
+}
+
+// toml only knows which table a bad key is in (and points at the start of the next one),
+// so find the key ourselves
+fn parse_problem(err: &toml::de::Error, contents: &str) -> String {
+    let message = err.to_string();
+    let line = match (
+        quoted_after(&message, "unknown field"),
+        quoted_after(&message, "for key"),
+    ) {
+        (Some(field), Some(table)) => line_of(contents, table, Some(field)),
+        (None, Some(key)) => match key.rsplit_once('.') {
+            Some((table, key)) => line_of(contents, table, Some(key)),
+            None => line_of(contents, key, None),
+        },
+        _ => None,
+    };
+    match (line, message.rfind(" at line ")) {
+        (Some(line), Some(position)) => format!("{} at line {}", &message[..position], line),
+        _ => message,
+    }
+}
+
+// An ip:port, or a host:port resolved when connecting (or binding)
+fn check_endpoint(endpoint: &str) -> std::result::Result<(), String> {
+    if endpoint.parse::<SocketAddr>().is_ok() {
+        return Ok(());
+    }
+    let (host, port) = endpoint
+        .rsplit_once(':')
+        .ok_or_else(|| "expected host:port".to_string())?;
+    port.parse::<u16>()
+        .map_err(|err| format!("bad port {:?}: {}", port, err))?;
+    let valid = |label: &str| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    if !host.trim_end_matches('.').split('.').all(valid) {
+        return Err(format!("bad host {:?}", host));
+    }
+    Ok(())
+}
+
+fn problem_at(contents: &str, table: &str, key: Option<&str>, problem: String) -> String {
+    match line_of(contents, table, key) {
+        Some(line) => format!("{} at line {}", problem, line),
+        None => problem,
+    }
+}
+
+// Everything that parses but can't work, with the line it is on
+fn validate(config: &Config, contents: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    if let Err(err) = check_endpoint(&config.machine.endpoint) {
+        problems.push(problem_at(
+            contents,
+            "machine",
+            Some("endpoint"),
+            format!(
+                "machine.endpoint {:?} is not an address: {}",
+                config.machine.endpoint, err
+            ),
+        ));
+    }
+
+    let tuning = &config.tuning;
+    for (key, value) in [
+        ("write_buffer", tuning.write_buffer as u64),
+        ("polling_interval_ms", tuning.polling_interval_ms),
+        ("lock_lease", tuning.lock_lease),
+        ("probe_interval_ms", tuning.probe_interval_ms),
+        ("probe_timeout_ms", tuning.probe_timeout_ms),
+    ] {
+        if value == 0 {
+            problems.push(problem_at(
+                contents,
+                "tuning",
+                Some(key),
+                format!("tuning.{} has to be greater than 0", key),
+            ));
+        }
+    }
+
+    if let Some(discovery) = &config.discovery {
+        problems.extend(validate_discovery(discovery, contents));
+    }
+
+    let mut names: Vec<&String> = config.network.keys().collect();
+    names.sort();
+    for name in names {
+        let peer = &config.network[name];
+        let table = format!("network.{}", name);
+        if name == &config.machine.name {
+            problems.push(problem_at(
+                contents,
+                &table,
+                None,
+                format!("[{}] has the same name as this machine", table),
+            ));
+        }
+        if let Some(given) = &peer.name {
+            if given != name {
+                problems.push(problem_at(
+                    contents,
+                    &table,
+                    Some("name"),
+                    format!("[{}].name is {:?}, expected {:?}", table, given, name),
+                ));
+            }
+        }
+        if let Err(err) = check_endpoint(&peer.endpoint) {
+            problems.push(problem_at(
+                contents,
+                &table,
+                Some("endpoint"),
+                format!(
+                    "{}.endpoint {:?} is not an address: {}",
+                    table, peer.endpoint, err
+                ),
+            ));
+        }
+        for placeholder in ["{HERE}", "{THERE}"] {
+            if !peer.command.contains(placeholder) {
+                problems.push(problem_at(
+                    contents,
+                    &table,
+                    Some("command"),
+                    format!("{}.command does not contain {}", table, placeholder),
+                ));
+            }
+        }
+    }
+    problems
 }
 
-pub fn read_config(config_path: PathBuf) -> Config {
-    toml::from_str(&std::fs::read_to_string(config_path).expect("Unable to read config"))
-        .expect("Unable to parse config")
+fn validate_discovery(discovery: &DiscoveryConfig, contents: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    match discovery.address.parse::<SocketAddr>() {
+        Ok(SocketAddr::V4(_)) => (),
+        Ok(SocketAddr::V6(_)) => problems.push(problem_at(
+            contents,
+            "discovery",
+            Some("address"),
+            "discovery.address has to be an IPv4 address".to_string(),
+        )),
+        Err(err) => problems.push(problem_at(
+            contents,
+            "discovery",
+            Some("address"),
+            format!(
+                "discovery.address {:?} is not an address: {}",
+                discovery.address, err
+            ),
+        )),
+    }
+    if let Err(err) = discovery.interface.parse::<Ipv4Addr>() {
+        problems.push(problem_at(
+            contents,
+            "discovery",
+            Some("interface"),
+            format!(
+                "discovery.interface {:?} is not an IPv4 address: {}",
+                discovery.interface, err
+            ),
+        ));
+    }
+    if discovery.interval == 0 {
+        problems.push(problem_at(
+            contents,
+            "discovery",
+            Some("interval"),
+            "discovery.interval has to be greater than 0".to_string(),
+        ));
+    }
+    for placeholder in ["{HERE}", "{THERE}"] {
+        if !discovery.command.contains(placeholder) {
+            problems.push(problem_at(
+                contents,
+                "discovery",
+                Some("command"),
+                format!("discovery.command does not contain {}", placeholder),
+            ));
+        }
+    }
+    problems
+}
+
+// The configuration with every default filled in, as nimbus sees it
+pub fn effective_config(config: &Config) -> Result<String> {
+    toml::to_string_pretty(config)
+        .map_err(|err| NimbusError::ConfigInvalid(format!("unable to print config: {}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MACHINE: &str = r#"[machine]
+name = "a"
+mode = "DevelopmentMode"
+endpoint = "127.0.0.1:5000"
+"#;
+
+    // What read_config makes of contents
+    fn read(contents: &str) -> std::result::Result<Config, String> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nimbus.toml");
+        std::fs::write(&path, contents).unwrap();
+        read_config(path).map_err(|err| err.to_string())
+    }
+
+    fn peer(name: &str, endpoint: &str) -> String {
+        format!(
+            "\n[network.{}]\ncommand = \"cp -r {{HERE}} {{THERE}}\"\nendpoint = \"{}\"\n",
+            name, endpoint
+        )
+    }
+
+    #[test]
+    fn reads_a_valid_config() {
+        let config = read(&format!("{}{}", MACHINE, peer("b", "127.0.0.1:5001"))).unwrap();
+        assert_eq!(config.machine.name, "a");
+        assert_eq!(config.machine.grace_period, default_grace_period());
+        assert_eq!(config.network["b"].endpoint, "127.0.0.1:5001");
+    }
+
+    #[test]
+    fn accepts_host_names_as_endpoints() {
+        for endpoint in [
+            "laptop.local:5000",
+            "nas:80",
+            "[::1]:5000",
+            "node-2.example.com.:443",
+        ] {
+            let config = read(&format!("{}{}", MACHINE, peer("b", endpoint))).unwrap();
+            assert_eq!(config.network["b"].endpoint, endpoint);
+        }
+        for endpoint in [
+            "laptop.local",
+            "laptop:port",
+            ":5000",
+            "::1:5000",
+            "a b:5000",
+        ] {
+            let err = read(&format!("{}{}", MACHINE, peer("b", endpoint))).unwrap_err();
+            assert!(err.contains("network.b.endpoint"), "{}", err);
+            assert!(err.ends_with("at line 8"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn points_at_unknown_keys() {
+        let err = read(&format!("{}bogus = 1\n", MACHINE)).unwrap_err();
+        assert!(err.contains("unknown field `bogus`"), "{}", err);
+        assert!(err.ends_with("at line 5"), "{}", err);
+
+        let err = read(&format!("{}{}bogus = 1\n", MACHINE, peer("b", "b:1"))).unwrap_err();
+        assert!(err.ends_with("at line 9"), "{}", err);
+
+        let err = read(&format!("{}\n[tuning]\n\"bogus\" = 1\n", MACHINE)).unwrap_err();
+        assert!(err.contains("unknown field `bogus`"), "{}", err);
+        assert!(err.ends_with("at line 7"), "{}", err);
+    }
+
+    #[test]
+    fn points_into_quoted_dotted_and_inline_tables() {
+        let quoted = format!(
+            "{}\n[network.\"b\"]\ncommand = \"cp -r {{HERE}} {{THERE}}\"\n\"endpoint\" = \"b:1\"\nbogus = 1\n",
+            MACHINE
+        );
+        assert!(read(&quoted).unwrap_err().ends_with("at line 9"));
+
+        let dotted = format!(
+            "{}\n[network]\nb.command = \"cp -r {{HERE}} {{THERE}}\"\nb.endpoint = \"b:1\"\nb.bogus = 1\n",
+            MACHINE
+        );
+        assert!(read(&dotted).unwrap_err().ends_with("at line 9"));
+
+        let inline = format!(
+            "{}\n[network]\nb = {{ command = \"cp -r {{HERE}} {{THERE}}\", endpoint = \"b:1\" }}\nc = {{ command = \"cp\", endpoint = \"c:1\", bogus = 1 }}\n",
+            MACHINE
+        );
+        assert!(read(&inline).unwrap_err().ends_with("at line 8"));
+
+        assert_eq!(line_of(&dotted, "network.b", None), Some(7));
+        assert_eq!(line_of(&dotted, "network.b", Some("endpoint")), Some(8));
+        assert_eq!(line_of(&inline, "network.c", Some("command")), Some(8));
+        assert_eq!(line_of(&quoted, "network.b", Some("endpoint")), Some(8));
+        assert_eq!(line_of(&quoted, "network.c", None), None);
+    }
+
+    #[test]
+    fn refuses_a_peer_named_differently() {
+        let contents = format!("{}{}name = \"c\"\n", MACHINE, peer("b", "b:1"));
+        let err = read(&contents).unwrap_err();
+        assert!(
+            err.ends_with("[network.b].name is \"c\", expected \"b\" at line 9"),
+            "{}",
+            err
+        );
+
+        let err = read(&format!("{}{}", MACHINE, peer("a", "a:1"))).unwrap_err();
+        assert!(
+            err.ends_with("[network.a] has the same name as this machine at line 6"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn refuses_commands_without_placeholders() {
+        let contents = format!(
+            "{}\n[network.b]\ncommand = \"rsync -a {{HERE}} b:\"\nendpoint = \"b:1\"\n",
+            MACHINE
+        );
+        let err = read(&contents).unwrap_err();
+        assert!(
+            err.ends_with("network.b.command does not contain {THERE} at line 7"),
+            "{}",
+            err
+        );
+        assert!(!err.contains("{HERE}"), "{}", err);
+    }
 }
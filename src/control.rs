@@ -0,0 +1,456 @@
+use log::{info, warn};
+use rustc_hash::FxHashSet;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Notify;
+
+use crate::health::{PeerHealth, Prober, Reachability};
+use crate::index::{CanonicalProjectName, Index, LockError, LockResponse, LockStatus::*};
+use crate::quorum::Quorum;
+use crate::reload::Reloader;
+use crate::status::{ProjectStatus, Status};
+use crate::sync::Syncer;
+use crate::tracker::ProjectTracker;
+
+/// One line of JSON per request on the control socket
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum ControlRequest {
+    Status,
+    Pin {
+        project: String,
+    },
+    Unpin {
+        project: String,
+    },
+    Lock {
+        project: String,
+    },
+    Release {
+        project: String,
+    },
+    /// Asks for confirmation first if the holder is unreachable (unless confirmed)
+    Steal {
+        project: String,
+        #[serde(default)]
+        confirmed: bool,
+    },
+    /// Reachability of every peer
+    Peers,
+    /// Pushes the project to every peer, or pulls it from `from`
+    Sync {
+        project: String,
+        #[serde(default)]
+        from: Option<String>,
+    },
+    /// Re-reads the config without unmounting
+    Reload,
+    /// Unmounts and stops the daemon
+    Unmount,
+}
+
+/// One line of JSON per response on the control socket
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlResponse {
+    Status(Vec<ProjectStatus>),
+    Lock(LockResponse),
+    Peers(Vec<PeerHealth>),
+    /// Send the request again, confirmed, if the user agrees
+    Confirm(String),
+    Done(String),
+    Error(String),
+}
+
+// Sits next to local_storage rather than inside it, where only projects live
+pub fn default_socket_path(local_storage: &Path) -> PathBuf {
+    let mut name = local_storage.file_name().unwrap_or_default().to_os_string();
+    name.push(".sock");
+    local_storage.with_file_name(name)
+}
+
+/// Handles the commands local tooling sends over the control socket
+#[derive(Clone)]
+pub struct Control {
+    /// Takes the locks asked for, with the peers' agreement
+    quorum: Quorum,
+    index: Arc<Mutex<Index>>,
+    tracker: ProjectTracker,
+    status: Status,
+    syncer: Syncer,
+    reloader: Reloader,
+    prober: Prober,
+    /// Projects pinned because they were locked from here, until they are released from here
+    locked: Arc<Mutex<FxHashSet<CanonicalProjectName>>>,
+    /// Wakes up the main task to unmount
+    shutdown: Arc<Notify>,
+}
+
+impl Control {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        quorum: Quorum,
+        index: Arc<Mutex<Index>>,
+        tracker: ProjectTracker,
+        status: Status,
+        syncer: Syncer,
+        reloader: Reloader,
+        prober: Prober,
+        shutdown: Arc<Notify>,
+    ) -> Control {
+        Control {
+            quorum,
+            index,
+            tracker,
+            status,
+            syncer,
+            reloader,
+            prober,
+            locked: Arc::new(Mutex::new(FxHashSet::default())),
+            shutdown,
+        }
+    }
+
+    fn known_project(&self, project: &str) -> Result<CanonicalProjectName, ControlResponse> {
+        let project = PathBuf::from(project);
+        let index = self.index.lock().expect("lock failed");
+        if index.project_lock.contains_key(&project) {
+            Ok(project)
+        } else {
+            Err(ControlResponse::Error(format!(
+                "unknown project {:?}",
+                project
+            )))
+        }
+    }
+
+    pub async fn handle(&self, request: ControlRequest) -> ControlResponse {
+        info!("control request {:?}", request);
+        match request {
+            ControlRequest::Status => ControlResponse::Status(self.status.projects()),
+            ControlRequest::Pin { project } => match self.known_project(&project) {
+                Ok(project) if self.tracker.pin(project.clone()) => {
+                    ControlResponse::Done(format!("pinned {:?}", project))
+                }
+                Ok(project) => ControlResponse::Error(format!("{:?} is already pinned", project)),
+                Err(response) => response,
+            },
+            ControlRequest::Unpin { project } => {
+                let project = PathBuf::from(project);
+                if self.tracker.unpin(&project) {
+                    ControlResponse::Done(format!("unpinned {:?}", project))
+                } else {
+                    ControlResponse::Error(format!("{:?} is not pinned", project))
+                }
+            }
+            ControlRequest::Release { project } => match self.known_project(&project) {
+                Ok(project) => self.release_lock(project).await,
+                Err(response) => response,
+            },
+            ControlRequest::Lock { project } => self.take_lock(project, false).await,
+            ControlRequest::Steal { project, confirmed } => {
+                match self.check_steal(&project).await {
+                    Err(response) => response,
+                    Ok(Some(question)) if !confirmed => ControlResponse::Confirm(question),
+                    Ok(_) => self.take_lock(project, true).await,
+                }
+            }
+            ControlRequest::Peers => ControlResponse::Peers(self.prober.peers()),
+            ControlRequest::Sync { project, from } => {
+                let project = PathBuf::from(project);
+                let result = match &from {
+                    Some(peer) => self.syncer.pull(&project, peer).await,
+                    None => self.syncer.push_all(&project).await,
+                };
+                match result {
+                    Ok(()) => ControlResponse::Done(format!("synced {:?}", project)),
+                    Err(err) => ControlResponse::Error(err.to_string()),
+                }
+            }
+            ControlRequest::Reload => match self.reloader.reload() {
+                Ok(reload) => ControlResponse::Done(reload.to_string()),
+                Err(err) => ControlResponse::Error(err.to_string()),
+            },
+            ControlRequest::Unmount => {
+                self.shutdown.notify_one();
+                ControlResponse::Done("unmounting".to_string())
+            }
+        }
+    }
+
+    // Stealing from a holder that is up would split the project, from one that is down
+    // only needs the user to agree; returns what to ask them, if anything. A holder that
+    // wasn't pinged yet is pinged first, and one that can't be is never stolen from
+    async fn check_steal(&self, project: &str) -> Result<Option<String>, ControlResponse> {
+        let project = PathBuf::from(project);
+        let holder = match self
+            .index
+            .lock()
+            .expect("lock failed")
+            .project_lock
+            .get(&project)
+        {
+            Some(SomeoneHasLock(holder)) => holder.clone(),
+            _ => return Ok(None),
+        };
+        let reachability = match self.prober.reachability(&holder) {
+            Reachability::Unknown => self.prober.probe_peer(&holder).await,
+            reachability => reachability,
+        };
+        match reachability {
+            Reachability::Up => Err(ControlResponse::Error(format!(
+                "{} is reachable, ask it to release {:?} instead",
+                holder, project
+            ))),
+            Reachability::Unknown => Err(ControlResponse::Error(format!(
+                "unable to tell whether {} is up, not stealing {:?} from it",
+                holder, project
+            ))),
+            Reachability::Down => Ok(Some(format!(
+                "{:?} is locked by {}, which is {:?}; steal it anyway?",
+                project, holder, reachability
+            ))),
+        }
+    }
+
+    // A lock taken from here is pinned, or nothing would use the project and the tracker
+    // would hand it back once the grace period is over
+    async fn take_lock(&self, project: String, steal: bool) -> ControlResponse {
+        let project = PathBuf::from(project);
+        match self.quorum.acquire(&project, steal).await {
+            Ok(response) => {
+                if self.tracker.pin(project.clone()) {
+                    self.locked.lock().expect("lock failed").insert(project);
+                }
+                ControlResponse::Lock(response)
+            }
+            Err(LockError::UnknownProject) => {
+                ControlResponse::Error(format!("unknown project {:?}", project))
+            }
+            Err(LockError::Held(response)) | Err(LockError::NotHeld(response)) => {
+                ControlResponse::Error(format!(
+                    "{:?} is locked by {}",
+                    project,
+                    response.holder.unwrap_or_default()
+                ))
+            }
+            Err(LockError::NoQuorum { agreed, needed }) => ControlResponse::Error(format!(
+                "not locking {:?}: only {} of the {} machines needed agreed",
+                project, agreed, needed
+            )),
+        }
+    }
+
+    // Unpins what take_lock pinned (but not what was pinned on its own), and keeps it
+    // pinned if the lock stays
+    async fn release_lock(&self, project: CanonicalProjectName) -> ControlResponse {
+        let pinned = self.locked.lock().expect("lock failed").remove(&project)
+            && self.tracker.unpin(&project);
+        if self.tracker.release_now(&project) {
+            self.quorum.release(&project).await;
+            return ControlResponse::Done(format!("released {:?}", project));
+        }
+        if pinned && self.tracker.pin(project.clone()) {
+            self.locked
+                .lock()
+                .expect("lock failed")
+                .insert(project.clone());
+        }
+        ControlResponse::Error(format!(
+            "not releasing {:?}: we don't hold it or it is still in use",
+            project
+        ))
+    }
+
+    async fn connection(&self, stream: UnixStream) -> io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            let response = match serde_json::from_str(&line) {
+                Ok(request) => self.handle(request).await,
+                Err(err) => ControlResponse::Error(format!("malformed request: {}", err)),
+            };
+            let mut response = serde_json::to_vec(&response)?;
+            response.push(b'\n');
+            writer.write_all(&response).await?;
+        }
+        Ok(())
+    }
+}
+
+// Binds inside a directory only we can enter and moves the socket into place once it is
+// 0600, so nobody else can connect in between (the umask is shared with the filesystem)
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    let mut staging = path.as_os_str().to_os_string();
+    staging.push(".new");
+    let staging = PathBuf::from(staging);
+    let bound = staging.join("socket");
+    // left behind by a run that died while binding
+    if fs::symlink_metadata(&staging).is_ok() {
+        let _ = fs::remove_file(&bound);
+        fs::remove_dir(&staging)?;
+    }
+    fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let listener = UnixListener::bind(&bound).and_then(|listener| {
+        fs::set_permissions(&bound, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&bound);
+    fs::remove_dir(&staging)?;
+    listener
+}
+
+// Only the user nimbus runs as (the one who mounted it) may use the socket
+pub async fn serve(control: Control, path: PathBuf) -> io::Result<()> {
+    // a socket left behind by a previous run would make bind fail
+    if let Ok(metadata) = fs::symlink_metadata(&path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} exists and is not a socket", path),
+            ));
+        }
+        fs::remove_file(&path)?;
+    }
+    let listener = bind(&path)?;
+    info!("control socket listening at {:?}", path);
+
+    let uid = nix::unistd::getuid().as_raw();
+    loop {
+        let (stream, _) = listener.accept().await?;
+        match stream.peer_cred() {
+            Ok(credentials) if credentials.uid() == uid => (),
+            Ok(credentials) => {
+                warn!("refused control connection from uid {}", credentials.uid());
+                continue;
+            }
+            Err(err) => {
+                warn!("refused control connection without credentials: {:?}", err);
+                continue;
+            }
+        }
+        let control = control.clone();
+        tokio::spawn(async move {
+            if let Err(err) = control.connection(stream).await {
+                warn!("control connection failed: {:?}", err);
+            }
+        });
+    }
+}
+
+// Sends a single request to the daemon listening at path (blocking, for the CLI)
+pub fn request(path: &Path, request: &ControlRequest) -> io::Result<ControlResponse> {
+    let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    Ok(serde_json::from_str(&response)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> serde_json::Result<ControlRequest> {
+        serde_json::from_str(line)
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let project = || "project".to_string();
+        let requests = [
+            ControlRequest::Status,
+            ControlRequest::Pin { project: project() },
+            ControlRequest::Unpin { project: project() },
+            ControlRequest::Lock { project: project() },
+            ControlRequest::Release { project: project() },
+            ControlRequest::Steal {
+                project: project(),
+                confirmed: true,
+            },
+            ControlRequest::Peers,
+            ControlRequest::Sync {
+                project: project(),
+                from: Some("b".to_string()),
+            },
+            ControlRequest::Reload,
+            ControlRequest::Unmount,
+        ];
+        for request in requests {
+            let line = serde_json::to_string(&request).unwrap();
+            assert!(!line.contains('\n'), "{}", line);
+            assert_eq!(parse(&line).unwrap(), request);
+        }
+    }
+
+    #[test]
+    fn requests_are_tagged_by_command() {
+        assert_eq!(
+            parse(r#"{"command":"status"}"#).unwrap(),
+            ControlRequest::Status
+        );
+        assert_eq!(
+            parse(r#"{"command":"steal","project":"p"}"#).unwrap(),
+            ControlRequest::Steal {
+                project: "p".to_string(),
+                confirmed: false
+            }
+        );
+        assert_eq!(
+            parse(r#"{"command":"sync","project":"p"}"#).unwrap(),
+            ControlRequest::Sync {
+                project: "p".to_string(),
+                from: None
+            }
+        );
+        assert!(parse(r#"{"command":"format"}"#).is_err());
+        assert!(parse(r#"{"command":"lock"}"#).is_err());
+        assert!(parse(r#"{"project":"p"}"#).is_err());
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        let responses = [
+            ControlResponse::Status(Vec::new()),
+            ControlResponse::Lock(LockResponse {
+                holder: Some("a".to_string()),
+                epoch: 3,
+                expires: None,
+            }),
+            ControlResponse::Peers(Vec::new()),
+            ControlResponse::Confirm("steal it anyway?".to_string()),
+            ControlResponse::Done("released".to_string()),
+            ControlResponse::Error("unknown project".to_string()),
+        ];
+        for response in responses {
+            let line = serde_json::to_string(&response).unwrap();
+            assert_eq!(
+                serde_json::from_str::<ControlResponse>(&line).unwrap(),
+                response
+            );
+        }
+        assert_eq!(
+            serde_json::to_string(&ControlResponse::Done("unmounting".to_string())).unwrap(),
+            r#"{"done":"unmounting"}"#
+        );
+    }
+
+    #[test]
+    fn sockets_sit_next_to_local_storage() {
+        assert_eq!(
+            default_socket_path(Path::new("/home/me/.nimbus/storage")),
+            Path::new("/home/me/.nimbus/storage.sock")
+        );
+    }
+}
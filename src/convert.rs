@@ -1,47 +1,48 @@
 use log::{debug, error, info, trace, warn};
 use std::fs;
 use std::fs::{File, OpenOptions};
+use std::io;
 use std::ops::Add;
 use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
 
 use std::time::{Duration, UNIX_EPOCH};
 
-use libc::{c_int, ENOENT, ENOSYS, EPERM, O_ACCMODE, O_APPEND, O_RDONLY, O_RDWR, O_WRONLY}; // O_EXEC, O_SEARCH,
+use libc::{c_int, EINVAL, ENOENT, ENOSYS, EPERM, O_ACCMODE, O_APPEND, O_RDONLY, O_RDWR, O_WRONLY}; // O_EXEC, O_SEARCH,
 
-use fuser::{FileAttr, ReplyAttr, ReplyDirectory};
+use fuser::{FileAttr, MountOption, ReplyAttr, ReplyDirectory};
 
-pub fn convert_file_type(file_type: std::fs::FileType) -> fuser::FileType {
+// Anything else is not a file type FUSE can report
+pub fn convert_file_type(file_type: std::fs::FileType) -> io::Result<fuser::FileType> {
     if file_type.is_file() {
-        fuser::FileType::RegularFile
+        Ok(fuser::FileType::RegularFile)
     } else if file_type.is_dir() {
-        fuser::FileType::Directory
+        Ok(fuser::FileType::Directory)
     } else if file_type.is_symlink() {
-        fuser::FileType::Symlink
+        Ok(fuser::FileType::Symlink)
     } else if file_type.is_block_device() {
-        fuser::FileType::BlockDevice
+        Ok(fuser::FileType::BlockDevice)
     } else if file_type.is_char_device() {
-        fuser::FileType::CharDevice
+        Ok(fuser::FileType::CharDevice)
     } else if file_type.is_fifo() {
-        fuser::FileType::NamedPipe
+        Ok(fuser::FileType::NamedPipe)
     } else if file_type.is_socket() {
-        fuser::FileType::Socket
+        Ok(fuser::FileType::Socket)
     } else {
-        unimplemented!();
+        Err(io::Error::from_raw_os_error(EINVAL))
     }
 }
 
-pub fn convert_metadata(metadata: &fs::Metadata) -> FileAttr {
-    // todo: better error handling
+pub fn convert_metadata(metadata: &fs::Metadata) -> io::Result<FileAttr> {
     // info!("returned size: {}", metadata.len());
-    FileAttr {
+    Ok(FileAttr {
         ino: metadata.ino(),
         size: metadata.len(),
         blocks: metadata.blocks(),
-        atime: metadata.accessed().unwrap(),
-        mtime: metadata.modified().unwrap(),
+        atime: metadata.accessed()?,
+        mtime: metadata.modified()?,
         ctime: UNIX_EPOCH.add(Duration::new(metadata.ctime() as u64, 0)),
-        crtime: metadata.created().unwrap(), // unsupported (for macOS)
-        kind: convert_file_type(metadata.file_type()),
+        crtime: metadata.created().unwrap_or(UNIX_EPOCH), // unsupported (for macOS)
+        kind: convert_file_type(metadata.file_type())?,
         perm: metadata.permissions().mode().try_into().expect("Overflow"),
         nlink: metadata.nlink().try_into().expect("Overflow"),
         uid: metadata.uid(),
@@ -49,12 +50,13 @@ pub fn convert_metadata(metadata: &fs::Metadata) -> FileAttr {
         rdev: metadata.rdev().try_into().expect("Overflow"),
         blksize: metadata.blksize().try_into().expect("Overflow"),
         flags: 0, // unsupported so far (for macOS)
-    }
+    })
 }
 
 // todo: handle truncate flag
 // todo: O_EXEC, O_SEARCH
-pub fn parse_flag_options<'a>(flags: i32) -> (OpenOptions, bool) {
+// An access mode userspace made up (O_ACCMODE is 3) is EINVAL, as for open(2)
+pub fn parse_flag_options(flags: i32) -> io::Result<(OpenOptions, bool)> {
     let mut open_options = OpenOptions::new();
     let use_write_buffer = match flags & O_ACCMODE {
         O_RDONLY => {
@@ -73,13 +75,61 @@ pub fn parse_flag_options<'a>(flags: i32) -> (OpenOptions, bool) {
             open_options.append(true);
             false
         }
-        other => unimplemented!("Unimplemented flag ({})!", other & O_ACCMODE),
-        // O_EXEC => {
-        //     unimplemented!("Open with O_EXEC flag is unimplemented!")
-        // }
-        // O_SEARCH => {
-        //     unimplemented!("Open with O_SEARCH flag is unimplemented!")
-        // }
+        other => {
+            warn!("unsupported access mode {}", other);
+            return Err(io::Error::from_raw_os_error(EINVAL));
+        } // O_EXEC => {
+          //     unimplemented!("Open with O_EXEC flag is unimplemented!")
+          // }
+          // O_SEARCH => {
+          //     unimplemented!("Open with O_SEARCH flag is unimplemented!")
+          // }
     };
-    (open_options, use_write_buffer)
+    Ok((open_options, use_write_buffer))
+}
+
+// Same names as `mount -o`, anything fuser has no variant for is passed on as is
+pub fn parse_mount_option(option: &str) -> MountOption {
+    match option {
+        "auto_unmount" => MountOption::AutoUnmount,
+        "allow_other" => MountOption::AllowOther,
+        "allow_root" => MountOption::AllowRoot,
+        "default_permissions" => MountOption::DefaultPermissions,
+        "dev" => MountOption::Dev,
+        "nodev" => MountOption::NoDev,
+        "suid" => MountOption::Suid,
+        "nosuid" => MountOption::NoSuid,
+        "ro" => MountOption::RO,
+        "rw" => MountOption::RW,
+        "exec" => MountOption::Exec,
+        "noexec" => MountOption::NoExec,
+        "atime" => MountOption::Atime,
+        "noatime" => MountOption::NoAtime,
+        "dirsync" => MountOption::DirSync,
+        "sync" => MountOption::Sync,
+        "async" => MountOption::Async,
+        option => match option.split_once('=') {
+            Some(("fsname", name)) => MountOption::FSName(name.to_string()),
+            Some(("subtype", name)) => MountOption::Subtype(name.to_string()),
+            _ => MountOption::CUSTOM(option.to_string()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_an_invalid_access_mode() {
+        let error = parse_flag_options(O_ACCMODE).err().unwrap();
+        assert_eq!(error.raw_os_error(), Some(EINVAL));
+    }
+
+    #[test]
+    fn buffers_write_only_handles() {
+        for (flags, buffered) in [(O_RDONLY, false), (O_WRONLY, true), (O_RDWR, false)] {
+            assert_eq!(parse_flag_options(flags).unwrap().1, buffered);
+        }
+    }
 }
@@ -0,0 +1,261 @@
+use chrono::prelude::*;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+
+use crate::auth::{sign_announcement, verify_announcement, MAX_CLOCK_SKEW};
+use crate::config::{DiscoveryConfig, MachineConfig, NetworkMachineConfig};
+
+/// Peers found on the local network, by machine name
+pub type DiscoveredPeers = HashMap<String, NetworkMachineConfig>;
+
+/// What every instance periodically tells the local network about itself
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+struct Announcement {
+    cluster: String,
+    machine: String,
+    endpoint: String,
+    /// The announcer's local storage, as {THERE} paths are built from it
+    storage: Option<PathBuf>,
+    /// Whether the peer API is served over TLS
+    tls: bool,
+    timestamp: i64,
+}
+
+/// An announcement along with the signature over its exact bytes
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    payload: String,
+    signature: String,
+}
+
+struct Sighting {
+    peer: NetworkMachineConfig,
+    last: Instant,
+}
+
+/// Announces this machine on the local network and listens for the announcements of others
+pub struct Discovery {
+    config: DiscoveryConfig,
+    /// Ours, sent every interval (with a fresh timestamp)
+    announcement: Announcement,
+    socket: UdpSocket,
+    target: SocketAddr,
+    seen: HashMap<String, Sighting>,
+    /// Timestamp and signature of the newest announcement of every machine, kept past
+    /// expiry so an announcement replayed from elsewhere can't redirect the machine
+    newest: HashMap<String, (i64, String)>,
+    peers: watch::Sender<DiscoveredPeers>,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+// Several instances may share a machine (and the port), which is how discovery is tested
+fn bind(config: &DiscoveryConfig) -> io::Result<(UdpSocket, SocketAddr)> {
+    let target: SocketAddrV4 = config
+        .address
+        .parse()
+        .map_err(|err| invalid(format!("bad discovery.address: {}", err)))?;
+    let interface: Ipv4Addr = config
+        .interface
+        .parse()
+        .map_err(|err| invalid(format!("bad discovery.interface: {}", err)))?;
+
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, target.port()).into())?;
+    if target.ip().is_multicast() {
+        socket.join_multicast_v4(target.ip(), &interface)?;
+        socket.set_multicast_if_v4(&interface)?;
+        socket.set_multicast_loop_v4(true)?;
+    } else {
+        socket.set_broadcast(true)?;
+    }
+    Ok((UdpSocket::from_std(socket.into())?, target.into()))
+}
+
+impl Discovery {
+    // Has to be called from within the runtime
+    pub fn new(
+        config: &DiscoveryConfig,
+        machine: &MachineConfig,
+        storage: PathBuf,
+    ) -> io::Result<Discovery> {
+        let (socket, target) = bind(config)?;
+        let (peers, _) = watch::channel(DiscoveredPeers::new());
+        Ok(Discovery {
+            config: config.clone(),
+            announcement: Announcement {
+                cluster: config.cluster.clone(),
+                machine: machine.name.clone(),
+                endpoint: machine.endpoint.clone(),
+                storage: Some(storage),
+                tls: machine.tls.is_some(),
+                timestamp: 0,
+            },
+            socket,
+            target,
+            seen: HashMap::new(),
+            newest: HashMap::new(),
+            peers,
+        })
+    }
+
+    // Sees every change to the discovered peers
+    pub fn subscribe(&self) -> watch::Receiver<DiscoveredPeers> {
+        self.peers.subscribe()
+    }
+
+    pub async fn run(mut self) -> io::Result<()> {
+        info!(
+            "discovering peers of cluster {} on {}",
+            self.config.cluster, self.target
+        );
+        let interval = Duration::from_secs(self.config.interval);
+        let mut ticker = tokio::time::interval(interval);
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    if let Err(err) = self.announce().await {
+                        warn!("unable to announce on {}: {:?}", self.target, err);
+                    }
+                    self.expire(interval * 3);
+                }
+                received = self.socket.recv_from(&mut buffer) => {
+                    let (length, from) = received?;
+                    self.receive(&buffer[..length], from);
+                }
+            }
+        }
+    }
+
+    async fn announce(&mut self) -> io::Result<()> {
+        self.announcement.timestamp = Utc::now().timestamp();
+        let payload = serde_json::to_string(&self.announcement)?;
+        let packet = Packet {
+            signature: sign_announcement(&self.config.secret, payload.as_bytes()),
+            payload,
+        };
+        self.socket
+            .send_to(&serde_json::to_vec(&packet)?, self.target)
+            .await?;
+        Ok(())
+    }
+
+    fn receive(&mut self, packet: &[u8], from: SocketAddr) {
+        match self.parse(packet, from) {
+            Ok(Some((name, peer))) => {
+                let last = Instant::now();
+                let changed = self.seen.get(&name).map_or(true, |seen| seen.peer != peer);
+                if changed {
+                    info!("discovered {} at {}", name, peer.endpoint);
+                }
+                self.seen.insert(name, Sighting { peer, last });
+                if changed {
+                    self.publish();
+                }
+            }
+            // our own announcement, looped back
+            Ok(None) => (),
+            Err(reason) => debug!("ignoring announcement from {}: {}", from, reason),
+        }
+    }
+
+    // The announced peer, if it is one of ours (and not us)
+    fn parse(
+        &mut self,
+        packet: &[u8],
+        from: SocketAddr,
+    ) -> Result<Option<(String, NetworkMachineConfig)>, String> {
+        let packet: Packet = serde_json::from_slice(packet).map_err(|err| err.to_string())?;
+        if !verify_announcement(
+            &self.config.secret,
+            packet.payload.as_bytes(),
+            &packet.signature,
+        ) {
+            return Err("bad signature".to_string());
+        }
+        let announcement: Announcement =
+            serde_json::from_str(&packet.payload).map_err(|err| err.to_string())?;
+        if announcement.cluster != self.config.cluster {
+            return Err(format!("other cluster {}", announcement.cluster));
+        }
+        if (Utc::now().timestamp() - announcement.timestamp).abs() > MAX_CLOCK_SKEW {
+            return Err(format!("stale announcement of {}", announcement.machine));
+        }
+        if announcement.machine == self.announcement.machine {
+            return Ok(None);
+        }
+        if let Some((timestamp, signature)) = self.newest.get(&announcement.machine) {
+            if announcement.timestamp < *timestamp || packet.signature == *signature {
+                return Err(format!("replayed announcement of {}", announcement.machine));
+            }
+        }
+        self.newest.insert(
+            announcement.machine.clone(),
+            (announcement.timestamp, packet.signature.clone()),
+        );
+        if announcement.tls && self.config.ca.is_none() {
+            return Err(format!(
+                "{} serves TLS but discovery.ca is not set",
+                announcement.machine
+            ));
+        }
+
+        // a peer listening on 0.0.0.0 is reachable at wherever the announcement came from
+        let endpoint = match announcement.endpoint.parse::<SocketAddr>() {
+            Ok(mut endpoint) if endpoint.ip().is_unspecified() => {
+                endpoint.set_ip(from.ip());
+                endpoint.to_string()
+            }
+            // an address, or a host name the peer chose to be reached at
+            _ => announcement.endpoint.clone(),
+        };
+        let peer = NetworkMachineConfig {
+            name: Some(announcement.machine.clone()),
+            command: self.config.command.clone(),
+            endpoint,
+            storage: announcement.storage,
+            secret: Some(self.config.secret.clone()),
+            ca: self.config.ca.clone().filter(|_| announcement.tls),
+            fingerprint: None,
+        };
+        Ok(Some((announcement.machine, peer)))
+    }
+
+    // Forgets peers that stopped announcing themselves
+    fn expire(&mut self, after: Duration) {
+        let before = self.seen.len();
+        self.seen.retain(|name, seen| {
+            let alive = seen.last.elapsed() < after;
+            if !alive {
+                info!("{} stopped announcing itself", name);
+            }
+            alive
+        });
+        if self.seen.len() != before {
+            self.publish();
+        }
+    }
+
+    fn publish(&self) {
+        let peers = self
+            .seen
+            .iter()
+            .map(|(name, seen)| (name.clone(), seen.peer.clone()))
+            .collect();
+        self.peers.send_replace(peers);
+    }
+}
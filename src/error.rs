@@ -0,0 +1,75 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::fuse::INode;
+use crate::index::CanonicalProjectName;
+
+/// Everything the nimbus library API can fail with.
+/// Mapped to an errno at the FUSE boundary and to a status code at the HTTP boundary.
+#[derive(Debug)]
+pub enum NimbusError {
+    /// The backing store (or some other io) failed
+    Io(io::Error),
+    /// Another machine holds the lock on the project
+    LockHeld {
+        project: CanonicalProjectName,
+        holder: String,
+    },
+    /// A peer could not be reached (or answered with something unexpected)
+    PeerUnreachable { peer: String, reason: String },
+    /// The project could not be copied to or from a peer
+    SyncFailed {
+        project: CanonicalProjectName,
+        reason: String,
+    },
+    /// The configuration is unusable
+    ConfigInvalid(String),
+    /// The kernel asked about an inode we don't know (anymore)
+    InodeNotFound(INode),
+    /// The path is not inside any project (local_storage itself, or outside of it)
+    NotInProject(PathBuf),
+}
+
+pub type Result<T, E = NimbusError> = std::result::Result<T, E>;
+
+impl fmt::Display for NimbusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NimbusError::Io(error) => write!(f, "{}", error),
+            NimbusError::LockHeld { project, holder } => {
+                write!(f, "project {:?} is locked by {}", project, holder)
+            }
+            NimbusError::PeerUnreachable { peer, reason } => {
+                write!(f, "peer {} is unreachable: {}", peer, reason)
+            }
+            NimbusError::SyncFailed { project, reason } => {
+                write!(f, "syncing {:?} failed: {}", project, reason)
+            }
+            NimbusError::ConfigInvalid(reason) => write!(f, "invalid config: {}", reason),
+            NimbusError::InodeNotFound(ino) => write!(f, "inode {:?} not found", ino),
+            NimbusError::NotInProject(path) => write!(f, "{:?} is not inside a project", path),
+        }
+    }
+}
+
+impl std::error::Error for NimbusError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NimbusError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for NimbusError {
+    fn from(error: io::Error) -> NimbusError {
+        NimbusError::Io(error)
+    }
+}
+
+impl From<nix::errno::Errno> for NimbusError {
+    fn from(errno: nix::errno::Errno) -> NimbusError {
+        NimbusError::Io(errno.into())
+    }
+}
@@ -7,29 +7,50 @@ use std::io::{
 };
 use std::pin::Pin;
 
+use crate::index::CanonicalProjectName;
+
 pub struct FileHandler {
     file: Option<std::fs::File>,
     pub offset: i64, // todo: should always be positive (maybe change type)
     write: Option<BufWriter<std::fs::File>>,
+    pub project: Option<CanonicalProjectName>, // none for files outside of projects
 }
 
-// todo: expose buf write size to user later, tune buffer size better
+// todo: tune the default buffer size better
 impl FileHandler {
-    pub fn new(file: std::fs::File, offset: i64, use_write_buffer: bool) -> FileHandler {
-        if use_write_buffer {
+    // write_buffer is None for handles that write straight through
+    pub fn new(
+        file: std::fs::File,
+        offset: i64,
+        write_buffer: Option<usize>,
+        project: Option<CanonicalProjectName>,
+    ) -> FileHandler {
+        if let Some(capacity) = write_buffer {
             FileHandler {
                 file: None,
                 offset: offset,
-                write: Some(BufWriter::with_capacity(4096 * 32, file)),
+                write: Some(BufWriter::with_capacity(capacity, file)),
+                project,
             }
         } else {
             FileHandler {
                 file: Some(file),
                 offset: offset,
                 write: None,
+                project,
             }
         }
     }
+    // Whether writes may sit in memory until the next flush
+    pub fn buffered(&self) -> bool {
+        self.write.is_some()
+    }
+    // Bytes written but still sitting in the buffer
+    pub fn buffered_bytes(&self) -> usize {
+        self.write
+            .as_ref()
+            .map_or(0, |writer| writer.buffer().len())
+    }
     pub fn sync_all(&self) -> Result<()> {
         if self.file.is_some() {
             let file = self.file.as_ref().expect("sync_all unexpectedly failed!");
@@ -39,6 +60,15 @@ impl FileHandler {
             writer.get_ref().sync_all()
         }
     }
+    pub fn sync_data(&self) -> Result<()> {
+        if self.file.is_some() {
+            let file = self.file.as_ref().expect("sync_data unexpectedly failed!");
+            file.sync_data()
+        } else {
+            let writer = self.write.as_ref().expect("sync_data unexpectedly failed!");
+            writer.get_ref().sync_data()
+        }
+    }
     pub fn metadata(&mut self) -> Result<Metadata> {
         if self.file.is_some() {
             let file = self.file.as_ref().expect("sync_all unexpectedly failed!");
@@ -1,41 +1,52 @@
-use nix::fcntl::renameat2;
+use nix::fcntl::{renameat2, RenameFlags};
 use nix::unistd::chown;
 use procfs::process::Process;
-use procfs::ProcError;
-use procfs::ProcError::*;
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 use std::ffi::OsStr;
 use std::fs;
 use std::fs::{File, FileTimes, OpenOptions};
 use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::Path;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 
-use libc::{c_int, ENOSYS};
+use libc::{
+    c_int, EINVAL, EIO, ENOSYS, EOVERFLOW, EPERM, F_OK, O_ACCMODE, O_RDONLY, O_RDWR, O_TRUNC,
+    O_WRONLY, R_OK, W_OK, X_OK,
+};
 use std::path::PathBuf;
 
 use chrono::prelude::*;
 use std::time::{Duration, Instant, SystemTime};
 
+use fuser::consts::FUSE_DO_READDIRPLUS;
 use fuser::TimeOrNow::{Now, SpecificTime};
 use fuser::{
     FileAttr, Filesystem, KernelConfig, Reply, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
-    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, Request, TimeOrNow,
+    ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, Request,
+    TimeOrNow,
 };
 use log::{debug, error, info, trace, warn};
 
+use crate::config::TuningConfig;
 use crate::convert::{convert_file_type, convert_metadata, parse_flag_options};
+use crate::error::{NimbusError, Result};
 use crate::file_handler::FileHandler;
-use crate::fuse::{parse_error_cint, FileCreate, Fuse, IDirHandle, IFileHandle, INode};
-use crate::index::{CanonicalProjectName, Index};
+use crate::fuse::{
+    catch_panic, parse_error_cint, FileCreate, Fuse, IDirHandle, IFileHandle, INode,
+};
+use crate::index::{CanonicalProjectName, Index, LockStatus::*};
+use crate::inode_table::{INodeTable, ROOT_DIR};
+use crate::journal::{default_journal_path, Journal};
+use crate::status::{ProjectActivities, Status};
+use crate::tracker::{ProjectRefs, ProjectTracker};
+
+// const TIMEOUT: Duration = Duration::new(1, 0);
+// const SLEEP_INTERVAL: Duration = Duration::new(0, 10);
 
-const ROOT_DIR: INode = (1 as u64).into();
-const ATTR_TTL: Duration = Duration::new(1, 0);
-const PID_POLLING_INTERVAL: Duration = Duration::new(1, 0); // maybe too long?
-                                                            // const TIMEOUT: Duration = Duration::new(1, 0);
-                                                            // const SLEEP_INTERVAL: Duration = Duration::new(0, 10);
+/// Open file handlers, shared so they can be flushed on shutdown
+pub type FileHandlers = Arc<Mutex<FxHashMap<IFileHandle, Arc<Mutex<FileHandler>>>>>;
 
 pub struct NimbusFS {
     /// This where we store the nimbus files on disk
@@ -49,155 +60,186 @@ pub struct NimbusFS {
     last_updated_utc: DateTime<Utc>,
     last_updated_local: SystemTime,
 
-    /// Attribute cache duration
-    // pub attr_ttl: Duration,
+    /// Cache durations and write buffer capacity, changed when the config is reloaded
+    tuning: Arc<Mutex<TuningConfig>>,
     generation: u64,
 
-    /// Map containing inode-pathbuf mappings
-    ino_file_map: FxHashMap<INode, PathBuf>,
-    file_ino_map: FxHashMap<PathBuf, INode>,
+    /// Inode table mapping inodes to (parent, name) pairs
+    inodes: INodeTable,
+    /// How many times each inode has been handed to the kernel (balanced by forget)
+    lookup_counts: FxHashMap<INode, u64>,
+    /// Inodes the kernel forgot while they still had open handlers
+    forgotten: FxHashSet<INode>,
 
     /// Index locks
     index: Arc<Mutex<Index>>, // maybe use channels
     /// Reference counting for the project locks (do we need atomic?)
-    index_refs: FxHashMap<CanonicalProjectName, Arc<AtomicU64>>,
+    index_refs: ProjectRefs,
+    /// Open handles, last access and sync state per project (reported by the status api)
+    activity: ProjectActivities,
+    /// Watches processes working inside projects (single polling thread)
+    tracker: ProjectTracker,
 
     /// Keep track of file handlers
     ino_open_file_handlers: FxHashMap<INode, Vec<IFileHandle>>,
-    file_handlers_map: FxHashMap<IFileHandle, Arc<Mutex<FileHandler>>>,
+    file_handlers_map: FileHandlers,
     /// An incrementing counter so we can generate unique file handle ids
     last_file_handle: IFileHandle,
-    // Last inode allocated
-    last_ino_alloc: INode,
+    /// Set once shutdown begins, after which no new files are opened
+    draining: Arc<AtomicBool>,
+    /// Buffered writes that would be lost if nimbus crashed
+    journal: Journal,
+    /// Mounted without default_permissions, so the kernel leaves the mode bits to us
+    check_modes: bool,
 }
 
 impl NimbusFS {
-    pub fn default(local_storage: PathBuf, mount_directory: PathBuf) -> NimbusFS {
+    pub fn default(local_storage: PathBuf, mount_directory: PathBuf) -> Result<NimbusFS> {
+        NimbusFS::new(local_storage, mount_directory, &TuningConfig::default())
+    }
+
+    pub fn new(
+        local_storage: PathBuf,
+        mount_directory: PathBuf,
+        tuning: &TuningConfig,
+    ) -> Result<NimbusFS> {
         // todo: change last_updated to actually be last_updated
         let last_updated = Utc::now();
-        let mut nimbus = NimbusFS {
-            local_storage: fs::canonicalize(local_storage.clone())
-                .expect("Unable to canonicalize link"),
-            mount_directory: fs::canonicalize(mount_directory)
-                .expect("Unable to canonicalize link"),
+        let local_storage = fs::canonicalize(local_storage)?;
+        let mount_directory = fs::canonicalize(mount_directory)?;
+        let index = Arc::new(Mutex::new(Index::new()));
+        index.lock().expect("lock failed").lock_lease = tuning.lock_lease();
+        for entry in fs::read_dir(&local_storage)? {
+            let project = entry?.file_name();
+            index
+                .lock()
+                .expect("lock failed")
+                .register_project(project.into());
+        }
+        let index_refs: ProjectRefs = Arc::new(Mutex::new(FxHashMap::default()));
+        let journal = Journal::open(&default_journal_path(&local_storage), tuning.write_buffer)?;
+        Ok(NimbusFS {
+            local_storage: local_storage.clone(),
+            mount_directory: mount_directory.clone(),
             last_updated_utc: last_updated,
             last_updated_local: SystemTime::from(last_updated),
-            // attr_ttl: Duration::new(1, 0), // default to one sec
+            tuning: Arc::new(Mutex::new(tuning.clone())),
             generation: 0,
-            ino_file_map: FxHashMap::default(),
-            file_ino_map: FxHashMap::default(),
-            index: Arc::new(Mutex::new(Index::new())),
-            index_refs: FxHashMap::default(),
+            inodes: INodeTable::new(local_storage),
+            lookup_counts: FxHashMap::default(),
+            forgotten: FxHashSet::default(),
+            index: Arc::clone(&index),
+            index_refs: Arc::clone(&index_refs),
+            activity: Arc::new(Mutex::new(FxHashMap::default())),
+            tracker: ProjectTracker::spawn(
+                mount_directory.clone(),
+                index,
+                index_refs,
+                tuning.polling_interval(),
+            ),
             ino_open_file_handlers: FxHashMap::default(),
-            file_handlers_map: FxHashMap::default(),
+            file_handlers_map: Arc::new(Mutex::new(FxHashMap::default())),
             last_file_handle: 0.into(),
-            last_ino_alloc: ROOT_DIR,
-        };
-        nimbus.register_ino(
-            ROOT_DIR,
-            fs::canonicalize(local_storage).expect("Unable to canonicalize link"),
-        );
-        nimbus
+            draining: Arc::new(AtomicBool::new(false)),
+            journal,
+            check_modes: false,
+        })
+    }
+
+    pub fn tuning(&self) -> Arc<Mutex<TuningConfig>> {
+        Arc::clone(&self.tuning)
+    }
+
+    fn entry_ttl(&self) -> Duration {
+        self.tuning.lock().expect("lock failed").entry_ttl()
+    }
+
+    pub fn local_storage(&self) -> PathBuf {
+        self.local_storage.clone()
     }
 
     pub fn index(&self) -> Arc<Mutex<Index>> {
         Arc::clone(&self.index)
     }
 
-    pub fn canonicize_project_name(&self, path: &PathBuf) -> CanonicalProjectName {
-        path.clone()
-            .strip_prefix(self.local_storage.clone())
-            .expect("Unable to canonicize project name (strip_prefix)")
-            .components()
-            .find_map(|c| match c {
-                std::path::Component::Normal(project) => Some(project),
-                _ => None,
+    pub fn tracker(&self) -> ProjectTracker {
+        self.tracker.clone()
+    }
+
+    pub fn status(&self) -> Status {
+        Status::new(
+            self.index(),
+            Arc::clone(&self.index_refs),
+            Arc::clone(&self.activity),
+            self.tracker.pinned(),
+            Arc::clone(&self.file_handlers_map),
+        )
+    }
+
+    pub fn activity(&self) -> ProjectActivities {
+        Arc::clone(&self.activity)
+    }
+
+    pub fn file_handlers(&self) -> FileHandlers {
+        Arc::clone(&self.file_handlers_map)
+    }
+
+    pub fn draining(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.draining)
+    }
+
+    // Whether the kernel checks the mode bits (the default_permissions mount option)
+    pub fn set_default_permissions(&mut self, default_permissions: bool) {
+        self.check_modes = !default_permissions;
+    }
+
+    pub fn journal(&self) -> Journal {
+        self.journal.clone()
+    }
+
+    // Opening files is refused once shutdown has begun
+    fn check_draining(&self) -> Result<()> {
+        if self.draining.load(Ordering::SeqCst) {
+            return Err(Error::new(ErrorKind::ResourceBusy, "nimbus is shutting down").into());
+        }
+        Ok(())
+    }
+
+    pub fn touch_project(&self, project: &CanonicalProjectName) {
+        let mut activity = self.activity.lock().expect("lock failed");
+        activity.entry(project.clone()).or_default().last_access = Some(Utc::now());
+    }
+
+    // The first component below local_storage
+    pub fn canonicize_project_name(&self, path: &PathBuf) -> Result<CanonicalProjectName> {
+        path.strip_prefix(&self.local_storage)
+            .ok()
+            .and_then(|path| {
+                path.components().find_map(|c| match c {
+                    std::path::Component::Normal(project) => Some(project.into()),
+                    _ => None,
+                })
             })
-            .expect("Unable to canonicize project name (find_map)")
-            .into()
+            .ok_or_else(|| NimbusError::NotInProject(path.clone()))
     }
 
-    // Increment immediately, then decrement when/if cwd is outside of the project dir
+    // Increment immediately, the tracker decrements once pid stops referencing the project dir
     pub fn pid_cwd_project_ref(&mut self, project: CanonicalProjectName, pid: u32) {
+        if self.tracker.is_watching(pid, &project) {
+            return;
+        }
         let counter = self.inc_project_ref(project.clone());
-        let mut full_cwd = self.mount_directory.clone();
-        full_cwd.push(project);
-        let index = self.index();
-        std::thread::spawn(move || {
-            // check cwd of pid
-            // we sleep twice the polling interval to make sure the kernel has time to update procfs (hacky!)
-            std::thread::sleep(PID_POLLING_INTERVAL);
-            match Process::new(pid as i32) {
-                Ok(process) => loop {
-                    std::thread::sleep(PID_POLLING_INTERVAL);
-                    match process.cwd() {
-                        Ok(cwd) => {
-                            if !cwd.starts_with(&full_cwd) {
-                                info!("left directory {:?}", full_cwd);
-                                break;
-                            }
-                        }
-                        Err(PermissionDenied(path)) => {
-                            error!(
-                                "permission denied to snoop on process {} for project {:?} at path {:?}",
-                                pid, full_cwd, path
-                                );
-                            break;
-                        }
-                        Err(NotFound(path)) => {
-                            info!("process not found at {:?}", path);
-                            break;
-                        }
-                        Err(Incomplete(path)) => {
-                            error!("proc file at {:?} has incomplete contents", path)
-                            // retry, todo: add retry counter so this function terminates upon repeated Incompletes
-                        }
-                        Err(Io(err, path)) => {
-                            error!("io error {:?} at {:?}", err, path);
-                            break;
-                        }
-                        Err(InternalError(err)) => {
-                            error!("internal error {:?}", err);
-                            break;
-                        }
-                        Err(Other(err)) => {
-                            error!("other error {:?}", err);
-                            break;
-                        }
-                    }
-                },
-                Err(PermissionDenied(path)) => error!(
-                    "permission denied to snoop on process {} for project {:?} at path {:?}",
-                    pid, full_cwd, path
-                ),
-                Err(NotFound(path)) => info!("process not found at {:?}", path),
-                Err(Incomplete(path)) => error!("proc file at {:?} has incomplete contents", path),
-                Err(Io(err, path)) => error!("io error {:?} at {:?}", err, path),
-                Err(InternalError(err)) => error!("internal error {:?}", err),
-                Err(Other(err)) => error!("other error {:?}", err),
-            };
-
-            // decrement
-            let prev = counter.fetch_sub(1, Ordering::SeqCst);
-            info!(
-                "project counter for {:?} was at {}, now at {}",
-                full_cwd,
-                prev,
-                prev - 1
-            );
-            if prev == 0 {
-                panic!("reference counting decrement failed/overflowed!");
-            } else if prev == 1 {
-                // acquire index lock, then check again to be safe
-                info!("should release project lock for {:?} now", full_cwd);
-                let acquired_index = index.lock().unwrap();
-                todo!();
-            }
-        });
+        self.tracker.watch(pid, project, counter);
     }
 
     pub fn inc_project_ref(&mut self, project: CanonicalProjectName) -> Arc<AtomicU64> {
-        match self.index_refs.get_mut(&project) {
+        self.touch_project(&project);
+        self.index
+            .lock()
+            .expect("lock failed")
+            .register_project(project.clone());
+        let mut index_refs = self.index_refs.lock().expect("lock failed");
+        match index_refs.get_mut(&project) {
             Some(inc) => {
                 let prev = inc.fetch_add(1, Ordering::SeqCst);
                 info!(
@@ -206,10 +248,8 @@ impl NimbusFS {
                     prev,
                     prev + 1
                 );
-                if prev == 0 {
-                    info!("should obtain project lock now")
-                    // acquire index lock, then check again to be safe
-                }
+                // the first reference takes no lock: locks are taken from the control socket
+                // (or by a peer), using a project only keeps a lock we already hold
                 Arc::clone(inc)
                 // else if prev == MAX {
                 //     panic!("reference counting increment failed/overflowed!");
@@ -217,7 +257,7 @@ impl NimbusFS {
             }
             None => {
                 let inc = Arc::new(AtomicU64::new(1));
-                if self.index_refs.insert(project, Arc::clone(&inc)).is_some() {
+                if index_refs.insert(project, Arc::clone(&inc)).is_some() {
                     panic!("should not happen");
                 };
                 inc
@@ -226,7 +266,9 @@ impl NimbusFS {
     }
 
     pub fn dec_project_ref(&mut self, project: CanonicalProjectName) -> Arc<AtomicU64> {
-        match self.index_refs.get_mut(&project) {
+        self.touch_project(&project);
+        let index_refs = self.index_refs.lock().expect("lock failed");
+        match index_refs.get(&project) {
             Some(dec) => {
                 let prev = dec.fetch_sub(1, Ordering::SeqCst);
                 info!(
@@ -238,8 +280,8 @@ impl NimbusFS {
                 if prev == 0 {
                     panic!("reference counting decrement failed/overflowed!");
                 } else if prev == 1 {
-                    // acquire project lock first, then check again
-                    info!("should release project lock now");
+                    // the tracker releases the lock once the grace period is over
+                    info!("project {:?} is idle now", project);
                 }
                 Arc::clone(dec)
             }
@@ -249,93 +291,130 @@ impl NimbusFS {
 
     // pub fn get_path(&self, path)
 
-    pub fn register_ino(&mut self, ino: INode, path: PathBuf) {
-        self.ino_file_map.insert(ino, path.clone());
-        self.file_ino_map.insert(path, ino);
-    }
-
-    pub fn fresh_ino(&mut self) -> INode {
-        self.last_ino_alloc.inc();
-        self.last_ino_alloc
-    }
-
-    pub fn parent_name_lookup_result(
-        &self,
-        parent: INode,
-        name: &OsStr,
-    ) -> std::io::Result<PathBuf> {
-        let parent_file = self.lookup_ino_result(&parent)?;
-        let mut file = parent_file.clone();
+    pub fn parent_name_lookup_result(&self, parent: INode, name: &OsStr) -> Result<PathBuf> {
+        let mut file = self.lookup_ino_result(&parent)?;
         file.push(name);
         Ok(file)
     }
 
-    pub fn lookup_ino_result(&self, ino: &INode) -> std::io::Result<&PathBuf> {
-        match self.ino_file_map.get(ino) {
+    pub fn lookup_ino_result(&self, ino: &INode) -> Result<PathBuf> {
+        match self.inodes.path(*ino) {
             Some(path) => Ok(path),
-            None => Err(Error::new(
-                ErrorKind::NotFound,
-                "ino lookup failed: ino not found",
-            )),
+            None => Err(NimbusError::InodeNotFound(*ino)),
         }
     }
 
-    // todo: rename to lookup_path
-    pub fn lookup_file_result(&self, path: &PathBuf) -> std::io::Result<&INode> {
-        match self.file_ino_map.get(path) {
-            Some(ino) => Ok(ino),
-            None => Err(Error::new(
-                ErrorKind::NotFound,
-                "file lookup failed: file not found",
-            )),
+    pub fn lookup_or_create_ino(&mut self, parent: INode, name: &OsStr) -> INode {
+        self.inodes.lookup_or_create(parent, name)
+    }
+
+    // Every entry replied to the kernel (lookup, create, mkdir, symlink, readdirplus) must be
+    // counted, right where the reply is sent
+    pub fn inc_lookup_count(&mut self, ino: INode) {
+        self.forgotten.remove(&ino);
+        *self.lookup_counts.entry(ino).or_insert(0) += 1;
+    }
+
+    pub fn forget_ino(&mut self, ino: INode, nlookup: u64) {
+        match self.lookup_counts.get_mut(&ino) {
+            Some(count) if *count > nlookup => *count -= nlookup,
+            Some(_) => {
+                self.lookup_counts.remove(&ino);
+                let has_handlers = self
+                    .ino_open_file_handlers
+                    .get(&ino)
+                    .map_or(false, |handlers| !handlers.is_empty());
+                if has_handlers {
+                    // dropped once the last handler is released
+                    self.forgotten.insert(ino);
+                } else if ino != ROOT_DIR {
+                    self.inodes.forget(ino);
+                }
+            }
+            None => warn!("forget called on {:?}, which was never looked up", ino),
         }
     }
 
-    pub fn lookup_or_create_path(&mut self, path: &PathBuf) -> INode {
-        let result = self.file_ino_map.get(path);
-        match result {
-            Some(ino) => *ino,
-            None => {
-                let ino = self.fresh_ino();
-                self.register_ino(ino, path.clone());
-                ino
+    // Refuses to modify a project while another machine holds its lock
+    pub fn check_project_lock(&self, path: &PathBuf) -> Result<()> {
+        let project = self.canonicize_project_name(path)?;
+        let index = self.index.lock().expect("lock failed");
+        match index.project_lock.get(&project) {
+            Some(SomeoneHasLock(other)) => Err(NimbusError::LockHeld {
+                project: project.clone(),
+                holder: other.clone(),
             }
+            .into()),
+            _ => Ok(()),
         }
     }
 
-    pub fn rename_ino(&mut self, old_path: &PathBuf, new_path: &PathBuf) -> std::io::Result<()> {
-        let ino = *self.lookup_file_result(&old_path.clone())?;
-        self.remove_path(&old_path.clone())?;
-        self.register_ino(ino, new_path.clone());
+    // Adding or removing an entry of parent needs write and search permission on it
+    fn check_parent_permissions(&self, req: &Request<'_>, parent: INode) -> Result<()> {
+        if self.check_modes {
+            let metadata = fs::metadata(self.lookup_ino_result(&parent)?)?;
+            check_permissions(req, &metadata, W_OK | X_OK)?;
+        }
         Ok(())
     }
 
-    pub fn remove_path(&mut self, path: &PathBuf) -> std::io::Result<()> {
-        match self.file_ino_map.remove(path) {
-            Some(ino) => match self.ino_file_map.remove(&ino) {
-                Some(_) => Ok(()),
-                None => Err(Error::new(
-                    ErrorKind::NotFound,
-                    "path remove failed: ino not found",
-                )),
-            },
-            None => Err(Error::new(
-                ErrorKind::NotFound,
-                "path remove failed: file not found",
-            )),
+    // Runs a FUSE callback through catch_panic, recovering the locks a panic left poisoned
+    fn guard<T>(
+        &mut self,
+        operation: &str,
+        ino: u64,
+        callback: impl FnOnce(&mut NimbusFS) -> T,
+    ) -> Option<T> {
+        let result = catch_panic(operation, ino, || callback(self));
+        if result.is_none() {
+            self.recover_locks();
+        }
+        result
+    }
+
+    // What the locks guard may be half updated, but failing every later request with EIO
+    // (or panicking the server and the tracker) is worse
+    fn recover_locks(&self) {
+        self.index.clear_poison();
+        self.tuning.clear_poison();
+        self.index_refs.clear_poison();
+        self.activity.clear_poison();
+        self.file_handlers_map.clear_poison();
+        let handlers = self.file_handlers_map.lock().expect("lock failed");
+        for handler in handlers.values() {
+            handler.clear_poison();
         }
     }
 
+    // Where the journal finds ino, relative to local_storage
+    fn journal_path(&self, ino: &INode) -> Option<PathBuf> {
+        let path = self.inodes.path(*ino)?;
+        path.strip_prefix(&self.local_storage)
+            .ok()
+            .map(Path::to_path_buf)
+    }
+
     pub fn register_file_handler(
         &mut self,
         ino: INode,
         file: std::fs::File,
+        project: Option<CanonicalProjectName>,
         use_write_buffer: bool,
     ) -> IFileHandle {
         self.last_file_handle.inc();
-        self.file_handlers_map.insert(
+        if use_write_buffer {
+            if let Some(path) = self.journal_path(&ino) {
+                self.journal.record_open(self.last_file_handle.into(), path);
+            }
+        }
+        self.file_handlers_map.lock().expect("lock failed").insert(
             self.last_file_handle.clone(),
-            Arc::new(Mutex::new(FileHandler::new(file, 0, use_write_buffer))),
+            Arc::new(Mutex::new(FileHandler::new(
+                file,
+                0,
+                use_write_buffer.then_some(self.tuning.lock().expect("lock failed").write_buffer),
+                project,
+            ))),
         );
         match self.ino_open_file_handlers.get_mut(&ino) {
             Some(handlers) => handlers.push(self.last_file_handle.clone()),
@@ -355,13 +434,14 @@ impl NimbusFS {
     pub fn lookup_file_handler_result(
         &mut self,
         fh: IFileHandle,
-    ) -> std::io::Result<&Arc<Mutex<FileHandler>>> {
-        match self.file_handlers_map.get(&fh) {
-            Some(fh) => Ok(fh),
+    ) -> Result<Arc<Mutex<FileHandler>>> {
+        match self.file_handlers_map.lock().expect("lock failed").get(&fh) {
+            Some(fh) => Ok(Arc::clone(fh)),
             None => Err(Error::new(
                 ErrorKind::NotFound,
                 "file handler lookup failed: file handler not found",
-            )),
+            )
+            .into()),
         }
     }
 
@@ -369,34 +449,43 @@ impl NimbusFS {
         &mut self,
         ino: INode,
         fh: IFileHandle,
-    ) -> std::io::Result<Arc<Mutex<FileHandler>>> {
+    ) -> Result<Arc<Mutex<FileHandler>>> {
         match self.ino_open_file_handlers.get_mut(&ino) {
             Some(handlers) => handlers.retain(|x| x != &fh),
             None => {
                 panic!("could not close file handler!")
             }
         }
+        if self.ino_open_file_handlers[&ino].is_empty() {
+            self.ino_open_file_handlers.remove(&ino);
+            if self.forgotten.remove(&ino) && ino != ROOT_DIR {
+                self.inodes.forget(ino);
+            }
+        }
 
-        match self.file_handlers_map.remove(&fh) {
+        match self
+            .file_handlers_map
+            .lock()
+            .expect("lock failed")
+            .remove(&fh)
+        {
             Some(fh) => Ok(fh),
             None => Err(Error::new(
                 ErrorKind::NotFound,
                 "file handler deletion failed: file handler not found",
-            )),
+            )
+            .into()),
         }
     }
 
-    pub fn flush_associated_file_handlers(&mut self, ino: INode) -> std::io::Result<()> {
+    pub fn flush_associated_file_handlers(&mut self, ino: INode) -> Result<()> {
         match self.ino_open_file_handlers.get_mut(&ino) {
             Some(handlers) => {
                 for x in handlers.clone() {
-                    let fh = self
-                        .lookup_file_handler_result(x)
-                        .expect("failed to flush file handles");
-                    let arc_file_handler = Arc::clone(fh);
+                    let arc_file_handler = self.lookup_file_handler_result(x)?;
                     let mut file_handler = arc_file_handler.lock().unwrap();
                     file_handler.flush()?;
-                    file_handler.sync_all()?;
+                    self.journal.record_flush(x.into());
                 }
             }
             None => (),
@@ -405,23 +494,34 @@ impl NimbusFS {
     }
 
     pub fn count_file_handlers(&mut self) -> usize {
-        self.file_handlers_map.len()
+        self.file_handlers_map.lock().expect("lock failed").len()
     }
 
-    fn getattr_path(&self, path: &PathBuf) -> Result<FileAttr, std::io::Error> {
+    fn getattr_path(&self, path: &PathBuf) -> Result<FileAttr> {
         let metadata = fs::symlink_metadata(path)?; // todo: better error handling
-        Ok(convert_metadata(&metadata))
+        Ok(convert_metadata(&metadata)?)
     }
 }
 
 impl Fuse for NimbusFS {
     fn duration(&mut self) -> Duration {
-        ATTR_TTL
+        self.tuning.lock().expect("lock failed").attr_ttl()
+    }
+
+    fn init_fs(&mut self, _req: &Request<'_>, config: &mut KernelConfig) -> Result<()> {
+        if let Err(unsupported) = config.add_capabilities(FUSE_DO_READDIRPLUS) {
+            warn!(
+                "kernel does not support readdirplus ({:#x}), falling back to readdir",
+                unsupported
+            );
+        }
+        info!("Filesystem mounted");
+        Ok(())
     }
 
-    fn getattr_fs(&mut self, req: &Request<'_>, ino: INode) -> std::io::Result<FileAttr> {
+    fn getattr_fs(&mut self, req: &Request<'_>, ino: INode) -> Result<FileAttr> {
         self.flush_associated_file_handlers(ino)?;
-        let mut attr = self.getattr_path(self.lookup_ino_result(&ino)?)?;
+        let mut attr = self.getattr_path(&self.lookup_ino_result(&ino)?)?;
         attr.ino = ino.into();
         Ok(attr)
     }
@@ -433,19 +533,16 @@ impl Fuse for NimbusFS {
         fh: IDirHandle,
         offset: i64,
         reply: &'a mut ReplyDirectory,
-    ) -> std::io::Result<&'a ReplyDirectory> {
+    ) -> Result<&'a ReplyDirectory> {
         let entries = fs::read_dir(self.lookup_ino_result(&ino)?)?;
-        for (counter, entry) in entries
-            .skip(offset.try_into().expect("Overflow")) // convert to result
-            .enumerate()
-        {
+        for (counter, entry) in entries.skip(offset.try_into().map_err(einval)?).enumerate() {
             let good_entry = entry?;
             let file_type = good_entry.file_type()?;
-            let ino = self.lookup_or_create_path(&good_entry.path());
+            let entry_ino = self.lookup_or_create_ino(ino, &good_entry.file_name());
             let result = reply.add(
-                ino.into(),
+                entry_ino.into(),
                 offset + counter as i64 + 1,
-                convert_file_type(file_type),
+                convert_file_type(file_type)?,
                 good_entry.file_name(),
             );
             if result {
@@ -455,21 +552,49 @@ impl Fuse for NimbusFS {
         Ok(reply)
     }
 
-    fn lookup_fs(
+    fn readdirplus_fs<'a>(
         &mut self,
         req: &Request<'_>,
-        parent: INode,
-        name: &OsStr,
-    ) -> std::io::Result<FileAttr> {
+        ino: INode,
+        fh: IDirHandle,
+        offset: i64,
+        reply: &'a mut ReplyDirectoryPlus,
+    ) -> Result<&'a ReplyDirectoryPlus> {
+        let entries = fs::read_dir(self.lookup_ino_result(&ino)?)?;
+        for (counter, entry) in entries.skip(offset.try_into().map_err(einval)?).enumerate() {
+            let good_entry = entry?;
+            let entry_ino = self.lookup_or_create_ino(ino, &good_entry.file_name());
+            self.flush_associated_file_handlers(entry_ino)?;
+            let mut attr = convert_metadata(&good_entry.metadata()?)?;
+            attr.ino = entry_ino.into();
+            let result = reply.add(
+                entry_ino.into(),
+                offset + counter as i64 + 1,
+                good_entry.file_name(),
+                &self.entry_ttl(),
+                &attr,
+                self.generation,
+            );
+            if result {
+                break;
+            }
+            self.inc_lookup_count(entry_ino);
+        }
+        Ok(reply)
+    }
+
+    fn lookup_fs(&mut self, req: &Request<'_>, parent: INode, name: &OsStr) -> Result<FileAttr> {
         info!("lookup: lookup called");
         let filename = self.parent_name_lookup_result(parent, name)?;
         info!("lookup: filename {:?}", filename);
-        self.pid_cwd_project_ref(self.canonicize_project_name(&filename), req.pid()); // this only really needs to happen on true lookups
-        let ino = self.lookup_or_create_path(&filename);
+        self.pid_cwd_project_ref(self.canonicize_project_name(&filename)?, req.pid()); // this only really needs to happen on true lookups
 
-        self.flush_associated_file_handlers(ino)?;
+        // a name that isn't there gets no inode, or every failed lookup would leave one behind
+        if let Some(ino) = self.inodes.lookup(parent, name) {
+            self.flush_associated_file_handlers(ino)?;
+        }
         let mut attr = self.getattr_path(&filename)?;
-        attr.ino = ino.into();
+        attr.ino = self.lookup_or_create_ino(parent, name).into();
         info!("lookup: attr {:?}", attr);
         Ok(attr)
     }
@@ -483,16 +608,15 @@ impl Fuse for NimbusFS {
         size: u32,
         flags: i32,
         lock_owner: Option<u64>,
-    ) -> std::io::Result<Vec<u8>> {
-        let f = self.lookup_file_handler_result(fh)?;
-        let arc_file_handler = Arc::clone(f);
+    ) -> Result<Vec<u8>> {
+        let arc_file_handler = self.lookup_file_handler_result(fh)?;
         let mut file_handler = arc_file_handler.lock().unwrap();
 
         // Seek to position
         file_handler.offset = file_handler
-            .seek(SeekFrom::Start(offset.try_into().expect("Overflow")))?
+            .seek(SeekFrom::Start(offset.try_into().map_err(einval)?))?
             .try_into()
-            .expect("Overflow");
+            .map_err(eoverflow)?;
 
         // from fuser examples; this is not correct!
         // file_handler.offset = file_handler
@@ -511,9 +635,11 @@ impl Fuse for NimbusFS {
         // let mut data: Vec<u8> = vec![0; actual_size.try_into().expect("Overflow")];
         // file_handler.read_exact(&mut data)?;
 
-        // Read
-        let mut data: Vec<u8> = vec![0; size.try_into().expect("Overflow")];
-        file_handler.read(&mut data)?;
+        // Read, short only at the end of the file
+        let mut data: Vec<u8> = Vec::with_capacity(size.try_into().map_err(eoverflow)?);
+        (&mut *file_handler)
+            .take(size.into())
+            .read_to_end(&mut data)?;
         Ok(data)
     }
     fn write_fs(
@@ -526,9 +652,8 @@ impl Fuse for NimbusFS {
         write_flags: u32,
         flags: i32,
         lock_owner: Option<u64>,
-    ) -> std::io::Result<usize> {
-        let f = self.lookup_file_handler_result(fh)?;
-        let arc_file_handler = Arc::clone(f);
+    ) -> Result<usize> {
+        let arc_file_handler = self.lookup_file_handler_result(fh)?;
         let mut file_handler = arc_file_handler.lock().unwrap();
 
         // Seek to position
@@ -540,32 +665,68 @@ impl Fuse for NimbusFS {
         //     ))?
         //     .try_into()
         //     .expect("Overflow");
-        file_handler.offset =
-            file_handler // corrupt
-                .seek(SeekFrom::Start(offset.try_into().expect("Overflow")))?
-                .try_into()
-                .expect("Overflow");
+        // seeking flushes the write buffer, so leave sequential writes alone
+        let seeked = !file_handler.buffered() || file_handler.offset != offset;
+        if seeked {
+            file_handler.offset =
+                file_handler // corrupt
+                    .seek(SeekFrom::Start(offset.try_into().map_err(einval)?))?
+                    .try_into()
+                    .map_err(eoverflow)?;
+        }
 
         // Write
-        file_handler.write(data)
+        if !file_handler.buffered() {
+            return Ok(file_handler.write(data)?);
+        }
+        let before = file_handler.buffered_bytes();
+        let written = file_handler.write(data)?;
+        file_handler.offset += i64::try_from(written).map_err(eoverflow)?;
+        let after = file_handler.buffered_bytes();
+        // only what still sits in the buffer can be lost in a crash
+        if seeked || after != before + written {
+            self.journal.record_flush(fh.into());
+        }
+        if after < written {
+            return Ok(written);
+        }
+        self.journal.record_write(
+            fh.into(),
+            offset.try_into().map_err(einval)?,
+            &data[..written],
+        );
+        Ok(written)
     }
-    fn open_fs(
-        &mut self,
-        _req: &Request<'_>,
-        ino: INode,
-        flags: i32,
-    ) -> std::io::Result<IFileHandle> // might also want to return flags in the future
+    fn open_fs(&mut self, req: &Request<'_>, ino: INode, flags: i32) -> Result<IFileHandle> // might also want to return flags in the future
     {
-        let (options, use_write_buffer) = parse_flag_options(flags);
+        self.check_draining()?;
+        let (options, use_write_buffer) = parse_flag_options(flags)?;
+        if flags & O_ACCMODE != O_RDONLY && ino != ROOT_DIR {
+            self.check_project_lock(&self.lookup_ino_result(&ino)?)?;
+        }
+        if self.check_modes {
+            let mut mask = match flags & O_ACCMODE {
+                O_WRONLY => W_OK,
+                O_RDWR => R_OK | W_OK,
+                _ => R_OK,
+            };
+            if flags & O_TRUNC != 0 {
+                mask |= W_OK;
+            }
+            let metadata = fs::metadata(self.lookup_ino_result(&ino)?)?;
+            check_permissions(req, &metadata, mask)?;
+        }
         let fh = options.open(self.lookup_ino_result(&ino)?)?;
 
+        let mut project = None;
         if ino != ROOT_DIR {
             let path = self.lookup_ino_result(&ino)?;
-            let project_name = self.canonicize_project_name(path);
-            self.inc_project_ref(project_name);
+            let project_name = self.canonicize_project_name(&path)?;
+            self.inc_project_ref(project_name.clone());
+            project = Some(project_name);
         }
 
-        Ok(self.register_file_handler(ino, fh, use_write_buffer))
+        Ok(self.register_file_handler(ino, fh, project, use_write_buffer))
     }
     fn create_fs(
         &mut self,
@@ -575,23 +736,28 @@ impl Fuse for NimbusFS {
         mode: u32,
         umask: u32,
         flags: i32,
-    ) -> std::io::Result<FileCreate> {
+    ) -> Result<FileCreate> {
+        self.check_draining()?;
         let filename = self.parent_name_lookup_result(parent, name)?;
+        self.check_project_lock(&filename)?;
+        let (_, use_write_buffer) = parse_flag_options(flags)?;
+        self.check_parent_permissions(req, parent)?;
         let fh = File::create_new(filename.clone())?;
         let mut attr = self.getattr_path(&filename)?;
-        let ino = self.lookup_or_create_path(&filename);
-        let (_, use_write_buffer) = parse_flag_options(flags);
+        let ino = self.lookup_or_create_ino(parent, name);
         attr.ino = ino.into();
 
+        let mut project = None;
         if ino != ROOT_DIR {
             let path = self.lookup_ino_result(&ino)?;
-            let project_name = self.canonicize_project_name(path);
-            self.inc_project_ref(project_name);
+            let project_name = self.canonicize_project_name(&path)?;
+            self.inc_project_ref(project_name.clone());
+            project = Some(project_name);
         }
 
         Ok(FileCreate::new(
             attr,
-            self.register_file_handler(ino, fh, use_write_buffer),
+            self.register_file_handler(ino, fh, project, use_write_buffer),
         ))
     }
 
@@ -611,23 +777,40 @@ impl Fuse for NimbusFS {
         chgtime: Option<SystemTime>,
         bkuptime: Option<SystemTime>,
         flags: Option<u32>,
-    ) -> std::io::Result<FileAttr> {
+    ) -> Result<FileAttr> {
         let times = construct_file_time(atime, mtime, ctime);
 
         // Currently, the file handler option is ignored
         let filename = self.lookup_ino_result(&ino)?;
-        let file = File::options().write(true).open(filename)?;
+        if ino != ROOT_DIR {
+            self.check_project_lock(&filename)?;
+        }
+        if self.check_modes {
+            let metadata = fs::metadata(&filename)?;
+            if mode.is_some() || uid.is_some() || gid.is_some() {
+                check_owner(req, &metadata)?;
+            }
+            if size.is_some() {
+                check_permissions(req, &metadata, W_OK)?;
+            }
+            // anyone who may write the file may touch it, only the owner may backdate it
+            if (atime.is_some() || mtime.is_some()) && check_owner(req, &metadata).is_err() {
+                check_permissions(req, &metadata, W_OK)?;
+            }
+        }
+        let file = File::options().write(true).open(&filename)?;
 
         file.set_times(times)?;
         if let Some(mode_st) = mode {
             let mut perms = file.metadata()?.permissions();
             perms.set_mode(mode_st);
+            file.set_permissions(perms)?;
         }
         if let Some(len) = size {
             file.set_len(len)?;
         }
         if uid.is_some() || gid.is_some() {
-            chown(filename, uid.map(|x| x.into()), gid.map(|x| x.into()))?;
+            chown(&filename, uid.map(|x| x.into()), gid.map(|x| x.into()))?;
         }
 
         self.getattr_fs(req, ino)
@@ -639,12 +822,32 @@ impl Fuse for NimbusFS {
         ino: INode,
         fh: IFileHandle,
         lock_owner: u64,
-    ) -> std::io::Result<()> {
-        let f = self.lookup_file_handler_result(fh)?;
-        let arc_file_handler = Arc::clone(f);
+    ) -> Result<()> {
+        let arc_file_handler = self.lookup_file_handler_result(fh)?;
         let mut file_handler = arc_file_handler.lock().unwrap();
+        // durability is only promised on fsync
         file_handler.flush()?;
-        file_handler.sync_all()
+        self.journal.record_flush(fh.into());
+        Ok(())
+    }
+
+    fn fsync_fs(
+        &mut self,
+        req: &Request<'_>,
+        ino: INode,
+        fh: IFileHandle,
+        datasync: bool,
+    ) -> Result<()> {
+        let arc_file_handler = self.lookup_file_handler_result(fh)?;
+        let mut file_handler = arc_file_handler.lock().unwrap();
+        file_handler.flush()?;
+        self.journal.record_flush(fh.into());
+        if datasync {
+            file_handler.sync_data()?
+        } else {
+            file_handler.sync_all()?
+        }
+        Ok(())
     }
 
     fn release_fs(
@@ -655,32 +858,39 @@ impl Fuse for NimbusFS {
         flags: i32,
         lock_owner: Option<u64>,
         flush: bool,
-    ) -> std::io::Result<()> {
+    ) -> Result<()> {
         let f = self.delete_file_handler_result(ino, fh)?;
         let mut file_handler = f.lock().unwrap();
-        file_handler.flush()?; // maybe check bool flag?
-        file_handler.sync_all()?;
-
+        // the inode may be gone (renamed over), the project it was opened in is not
+        if let Some(project) = file_handler.project.take() {
+            self.dec_project_ref(project);
+        }
+        let flushed = file_handler.flush(); // maybe check bool flag?
+        self.journal.record_close(fh.into());
+        Ok(flushed?)
+    }
+    fn opendir_fs(&mut self, req: &Request<'_>, ino: INode, _flags: i32) -> Result<IDirHandle> {
         if ino != ROOT_DIR {
             let path = self.lookup_ino_result(&ino)?;
-            let project_name = self.canonicize_project_name(path);
-            self.dec_project_ref(project_name);
+            let project_name = self.canonicize_project_name(&path)?;
+            self.inc_project_ref(project_name);
         }
-
-        Ok(())
+        Ok(0.into())
     }
-    fn opendir_fs(
+    fn fsyncdir_fs(
         &mut self,
         req: &Request<'_>,
         ino: INode,
-        _flags: i32,
-    ) -> std::io::Result<IDirHandle> {
-        if ino != ROOT_DIR {
-            let path = self.lookup_ino_result(&ino)?;
-            let project_name = self.canonicize_project_name(path);
-            self.inc_project_ref(project_name);
+        fh: IDirHandle,
+        datasync: bool,
+    ) -> Result<()> {
+        let dir = File::open(self.lookup_ino_result(&ino)?)?;
+        if datasync {
+            dir.sync_data()?
+        } else {
+            dir.sync_all()?
         }
-        Ok(0.into())
+        Ok(())
     }
     fn releasedir_fs(
         &mut self,
@@ -688,10 +898,10 @@ impl Fuse for NimbusFS {
         ino: INode,
         fh: IDirHandle,
         flags: i32,
-    ) -> std::io::Result<()> {
+    ) -> Result<()> {
         if ino != ROOT_DIR {
             let path = self.lookup_ino_result(&ino)?;
-            let project_name = self.canonicize_project_name(path);
+            let project_name = self.canonicize_project_name(&path)?;
             self.dec_project_ref(project_name);
         }
         Ok(())
@@ -703,25 +913,33 @@ impl Fuse for NimbusFS {
         name: &OsStr,
         mode: u32,
         umask: u32,
-    ) -> std::io::Result<FileAttr> {
+    ) -> Result<FileAttr> {
         let dir_path = self.parent_name_lookup_result(parent, name)?;
+        self.check_project_lock(&dir_path)?;
+        self.check_parent_permissions(req, parent)?;
         fs::create_dir(dir_path.clone())?;
         fs::symlink_metadata(dir_path)?.permissions().set_mode(mode);
         self.lookup_fs(req, parent, name)
     }
 
-    fn rmdir_fs(&mut self, req: &Request<'_>, parent: INode, name: &OsStr) -> std::io::Result<()> {
+    fn rmdir_fs(&mut self, req: &Request<'_>, parent: INode, name: &OsStr) -> Result<()> {
         let dir_path = self.parent_name_lookup_result(parent, name)?;
+        self.check_project_lock(&dir_path)?;
+        self.check_parent_permissions(req, parent)?;
         info!(
             "rmdir: there are {:?} files in the dir",
             fs::read_dir(dir_path.clone())?.count()
         );
         fs::remove_dir(dir_path.clone())?;
+        if let Ok(path) = dir_path.strip_prefix(&self.local_storage) {
+            self.journal.record_remove(path.to_path_buf());
+        }
         info!(
             "removed! parent: {:?}, name: {:?}, path: {:?}",
             parent, name, dir_path
         );
-        // self.remove_path(&dir_path)?;
+        // a directory made under the same name later is a new inode
+        self.inodes.remove(parent, name);
         Ok(())
     }
     fn rename_fs(
@@ -732,23 +950,37 @@ impl Fuse for NimbusFS {
         new_parent: INode,
         new_name: &OsStr,
         flags: u32,
-    ) -> std::io::Result<()> {
-        // todo: check flags for RENAME_EXCHANGE and RENAME_NOREPLACE
+    ) -> Result<()> {
         let dir_path = self.parent_name_lookup_result(parent, name)?;
-        // let ino = *self.lookup_file_result(&dir_path)?;
         let new_dir_path = self.parent_name_lookup_result(new_parent, new_name)?;
-        renameat2(
-            None,
-            &dir_path,
-            None,
-            &new_dir_path,
-            nix::fcntl::RenameFlags::from_bits_truncate(flags),
-        )?;
-        // fs::rename(dir_path.clone(), new_dir_path)?;
-        self.rename_ino(&dir_path, &new_dir_path)?;
-        // self.remove_path(&dir_path)?;
-        // let new_ino = self.fresh_ino();
-        // self.register_ino(new_ino, dir_path);
+        let flags = RenameFlags::from_bits_truncate(flags);
+
+        // a cross-project rename modifies both projects
+        self.check_project_lock(&dir_path)?;
+        self.check_project_lock(&new_dir_path)?;
+        self.check_parent_permissions(req, parent)?;
+        self.check_parent_permissions(req, new_parent)?;
+
+        // RENAME_NOREPLACE is enforced by renameat2 itself (EEXIST)
+        renameat2(None, &dir_path, None, &new_dir_path, flags)?;
+        // pending writes follow the files they were meant for
+        if let (Ok(from), Ok(to)) = (
+            dir_path.strip_prefix(&self.local_storage),
+            new_dir_path.strip_prefix(&self.local_storage),
+        ) {
+            if flags.contains(RenameFlags::RENAME_EXCHANGE) {
+                self.journal
+                    .record_exchange(from.to_path_buf(), to.to_path_buf());
+            } else {
+                self.journal
+                    .record_rename(from.to_path_buf(), to.to_path_buf());
+            }
+        }
+        if flags.contains(RenameFlags::RENAME_EXCHANGE) {
+            self.inodes.exchange(parent, name, new_parent, new_name);
+        } else {
+            self.inodes.rename(parent, name, new_parent, new_name);
+        }
         Ok(())
     }
     fn symlink_fs(
@@ -757,46 +989,74 @@ impl Fuse for NimbusFS {
         parent: INode,
         name: &OsStr,
         link: &Path,
-    ) -> std::io::Result<FileAttr> {
+    ) -> Result<FileAttr> {
         let sym_path = self.parent_name_lookup_result(parent, name)?;
+        self.check_project_lock(&sym_path)?;
+        self.check_parent_permissions(req, parent)?;
         std::os::unix::fs::symlink(link, sym_path)?;
         self.lookup_fs(req, parent, name)
     }
-    fn unlink_fs(&mut self, req: &Request<'_>, parent: INode, name: &OsStr) -> std::io::Result<()> {
+    fn unlink_fs(&mut self, req: &Request<'_>, parent: INode, name: &OsStr) -> Result<()> {
         info!("unlink called");
         let file_path = self.parent_name_lookup_result(parent, name)?;
+        self.check_project_lock(&file_path)?;
+        self.check_parent_permissions(req, parent)?;
         fs::remove_file(file_path.clone())?;
-        // self.remove_path(&file_path)?;
+        if let Ok(path) = file_path.strip_prefix(&self.local_storage) {
+            self.journal.record_remove(path.to_path_buf());
+        }
+        // a file created under the same name later is a new inode
+        self.inodes.remove(parent, name);
         Ok(())
     }
-    fn readlink_fs(
-        &mut self,
-        req: &Request<'_>,
-        ino: INode,
-    ) -> std::io::Result<std::path::PathBuf> {
+    fn readlink_fs(&mut self, req: &Request<'_>, ino: INode) -> Result<std::path::PathBuf> {
         let file = self.lookup_ino_result(&ino)?;
-        fs::read_link(file)
+        Ok(fs::read_link(file)?)
+    }
+    fn access_fs(&mut self, req: &Request<'_>, ino: INode, mask: i32) -> Result<()> {
+        let path = self.lookup_ino_result(&ino)?;
+        let metadata = fs::symlink_metadata(&path)?;
+        if mask == F_OK {
+            return Ok(());
+        }
+        // nimbus policy: no writes to projects another machine is working on (access()
+        // answers EACCES, where the write itself gets EROFS)
+        if mask & W_OK != 0 && ino != ROOT_DIR {
+            if let Err(NimbusError::LockHeld { project, holder }) = self.check_project_lock(&path) {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("project {:?} is locked by {}", project, holder),
+                )
+                .into());
+            }
+        }
+        check_permissions(req, &metadata, mask)
     }
 }
 
-// This mostly does error handling
+// This mostly does error handling (and keeps a panic in one request from taking the mount down)
 impl Filesystem for NimbusFS {
     fn init(
         &mut self,
         req: &Request<'_>,
         config: &mut KernelConfig,
     ) -> std::result::Result<(), c_int> {
-        match self.init_fs(req, config) {
-            Ok(()) => Ok(()),
-            Err(error) => Err(parse_error_cint(error)),
-        }
+        self.guard("init", ROOT_DIR.into(), |nimbus| {
+            match nimbus.init_fs(req, config) {
+                Ok(()) => Ok(()),
+                Err(error) => Err(parse_error_cint(error)),
+            }
+        })
+        .unwrap_or(Err(EIO))
     }
 
     fn getattr(&mut self, req: &Request<'_>, ino: u64, reply: ReplyAttr) {
-        match self.getattr_fs(req, ino.into()) {
-            Ok(attr) => reply.attr(&self.duration(), &attr),
-            Err(error) => reply.error(parse_error_cint(error)),
-        };
+        self.guard("getattr", ino, |nimbus| {
+            match nimbus.getattr_fs(req, ino.into()) {
+                Ok(attr) => reply.attr(&nimbus.duration(), &attr),
+                Err(error) => reply.error(parse_error_cint(error)),
+            };
+        });
     }
 
     fn readdir(
@@ -807,20 +1067,41 @@ impl Filesystem for NimbusFS {
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        match self.readdir_fs(req, ino.into(), fh.into(), offset, &mut reply) {
-            Ok(_) => reply.ok(),
-            Err(error) => reply.error(parse_error_cint(error)),
-        }
+        self.guard("readdir", ino, |nimbus| {
+            match nimbus.readdir_fs(req, ino.into(), fh.into(), offset, &mut reply) {
+                Ok(_) => reply.ok(),
+                Err(error) => reply.error(parse_error_cint(error)),
+            }
+        });
+    }
+
+    fn readdirplus(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        self.guard("readdirplus", ino, |nimbus| {
+            match nimbus.readdirplus_fs(req, ino.into(), fh.into(), offset, &mut reply) {
+                Ok(_) => reply.ok(),
+                Err(error) => reply.error(parse_error_cint(error)),
+            }
+        });
     }
 
     fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        match self.lookup_fs(req, parent.into(), name) {
-            Ok(attr) => {
-                reply.entry(&ATTR_TTL, &attr, self.generation);
-                info!("reply: {:?}", attr);
+        self.guard("lookup", parent, |nimbus| {
+            match nimbus.lookup_fs(req, parent.into(), name) {
+                Ok(attr) => {
+                    nimbus.inc_lookup_count(attr.ino.into());
+                    reply.entry(&nimbus.entry_ttl(), &attr, nimbus.generation);
+                    info!("reply: {:?}", attr);
+                }
+                Err(error) => reply.error(parse_error_cint(error)),
             }
-            Err(error) => reply.error(parse_error_cint(error)),
-        }
+        });
     }
 
     fn read(
@@ -834,10 +1115,12 @@ impl Filesystem for NimbusFS {
         lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
-        match self.read_fs(req, ino.into(), fh.into(), offset, size, flags, lock_owner) {
-            Ok(data) => reply.data(&data.into_boxed_slice()),
-            Err(error) => reply.error(parse_error_cint(error)),
-        }
+        self.guard("read", ino, |nimbus| {
+            match nimbus.read_fs(req, ino.into(), fh.into(), offset, size, flags, lock_owner) {
+                Ok(data) => reply.data(&data.into_boxed_slice()),
+                Err(error) => reply.error(parse_error_cint(error)),
+            }
+        });
     }
 
     fn write(
@@ -852,26 +1135,30 @@ impl Filesystem for NimbusFS {
         lock_owner: Option<u64>,
         reply: ReplyWrite,
     ) {
-        match self.write_fs(
-            req,
-            ino.into(),
-            fh.into(),
-            offset,
-            data,
-            write_flags,
-            flags,
-            lock_owner,
-        ) {
-            Ok(write_size) => reply.written(write_size.try_into().expect("Overflow")),
-            Err(error) => reply.error(parse_error_cint(error)),
-        }
+        self.guard("write", ino, |nimbus| {
+            match nimbus.write_fs(
+                req,
+                ino.into(),
+                fh.into(),
+                offset,
+                data,
+                write_flags,
+                flags,
+                lock_owner,
+            ) {
+                Ok(write_size) => reply.written(write_size.try_into().expect("Overflow")),
+                Err(error) => reply.error(parse_error_cint(error)),
+            }
+        });
     }
 
     fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
-        match self.open_fs(req, ino.into(), flags) {
-            Ok(fh) => reply.opened(fh.into(), 0), // todo: check if 0 is the right flag to return here
-            Err(error) => reply.error(parse_error_cint(error)),
-        };
+        self.guard("open", ino, |nimbus| {
+            match nimbus.open_fs(req, ino.into(), flags) {
+                Ok(fh) => reply.opened(fh.into(), 0), // todo: check if 0 is the right flag to return here
+                Err(error) => reply.error(parse_error_cint(error)),
+            };
+        });
     }
 
     fn create(
@@ -884,10 +1171,21 @@ impl Filesystem for NimbusFS {
         flags: i32,
         reply: ReplyCreate,
     ) {
-        match self.create_fs(req, parent.into(), name, mode, umask, flags) {
-            Ok(file) => reply.created(&ATTR_TTL, &file.attr, self.generation, file.fh.into(), 0), // flags?
-            Err(error) => reply.error(parse_error_cint(error)),
-        }
+        self.guard("create", parent, |nimbus| {
+            match nimbus.create_fs(req, parent.into(), name, mode, umask, flags) {
+                Ok(file) => {
+                    nimbus.inc_lookup_count(file.attr.ino.into());
+                    reply.created(
+                        &nimbus.entry_ttl(),
+                        &file.attr,
+                        nimbus.generation,
+                        file.fh.into(),
+                        0,
+                    ) // flags?
+                }
+                Err(error) => reply.error(parse_error_cint(error)),
+            }
+        });
     }
 
     fn setattr(
@@ -908,32 +1206,45 @@ impl Filesystem for NimbusFS {
         flags: Option<u32>,
         reply: ReplyAttr,
     ) {
-        match self.setattr_fs(
-            req,
-            ino.into(),
-            mode,
-            uid,
-            gid,
-            size,
-            atime,
-            mtime,
-            ctime,
-            fh.map(|x| x.into()),
-            crtime,
-            chgtime,
-            bkuptime,
-            flags,
-        ) {
-            Ok(attr) => reply.attr(&self.duration(), &attr),
-            Err(error) => reply.error(parse_error_cint(error)),
-        }
+        self.guard("setattr", ino, |nimbus| {
+            match nimbus.setattr_fs(
+                req,
+                ino.into(),
+                mode,
+                uid,
+                gid,
+                size,
+                atime,
+                mtime,
+                ctime,
+                fh.map(|x| x.into()),
+                crtime,
+                chgtime,
+                bkuptime,
+                flags,
+            ) {
+                Ok(attr) => reply.attr(&nimbus.duration(), &attr),
+                Err(error) => reply.error(parse_error_cint(error)),
+            }
+        });
     }
 
     fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
-        match self.flush_fs(req, ino.into(), fh.into(), lock_owner) {
-            Ok(_) => reply.ok(),
-            Err(error) => reply.error(parse_error_cint(error)),
-        }
+        self.guard("flush", ino, |nimbus| {
+            match nimbus.flush_fs(req, ino.into(), fh.into(), lock_owner) {
+                Ok(_) => reply.ok(),
+                Err(error) => reply.error(parse_error_cint(error)),
+            }
+        });
+    }
+
+    fn fsync(&mut self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        self.guard("fsync", ino, |nimbus| {
+            match nimbus.fsync_fs(req, ino.into(), fh.into(), datasync) {
+                Ok(_) => reply.ok(),
+                Err(error) => reply.error(parse_error_cint(error)),
+            }
+        });
     }
 
     fn release(
@@ -946,24 +1257,46 @@ impl Filesystem for NimbusFS {
         flush: bool,
         reply: ReplyEmpty,
     ) {
-        match self.release_fs(req, ino.into(), fh.into(), flags, lock_owner, flush) {
-            Ok(_) => reply.ok(),
-            Err(error) => reply.error(parse_error_cint(error)),
-        }
+        self.guard("release", ino, |nimbus| {
+            match nimbus.release_fs(req, ino.into(), fh.into(), flags, lock_owner, flush) {
+                Ok(_) => reply.ok(),
+                Err(error) => reply.error(parse_error_cint(error)),
+            }
+        });
     }
 
     fn opendir(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
-        match self.opendir_fs(req, ino.into(), flags) {
-            Ok(fh) => reply.opened(fh.into(), 0),
-            Err(error) => reply.error(parse_error_cint(error)),
-        }
+        self.guard("opendir", ino, |nimbus| {
+            match nimbus.opendir_fs(req, ino.into(), flags) {
+                Ok(fh) => reply.opened(fh.into(), 0),
+                Err(error) => reply.error(parse_error_cint(error)),
+            }
+        });
     }
 
     fn releasedir(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
-        match self.releasedir_fs(req, ino.into(), fh.into(), flags) {
-            Ok(_) => reply.ok(),
-            Err(error) => reply.error(parse_error_cint(error)),
-        }
+        self.guard("releasedir", ino, |nimbus| {
+            match nimbus.releasedir_fs(req, ino.into(), fh.into(), flags) {
+                Ok(_) => reply.ok(),
+                Err(error) => reply.error(parse_error_cint(error)),
+            }
+        });
+    }
+
+    fn fsyncdir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.guard("fsyncdir", ino, |nimbus| {
+            match nimbus.fsyncdir_fs(req, ino.into(), fh.into(), datasync) {
+                Ok(_) => reply.ok(),
+                Err(error) => reply.error(parse_error_cint(error)),
+            }
+        });
     }
 
     fn mkdir(
@@ -975,17 +1308,24 @@ impl Filesystem for NimbusFS {
         umask: u32,
         reply: ReplyEntry,
     ) {
-        match self.mkdir_fs(req, parent.into(), name, mode, umask) {
-            Ok(attr) => reply.entry(&ATTR_TTL, &attr, 0),
-            Err(error) => reply.error(parse_error_cint(error)),
-        }
+        self.guard("mkdir", parent, |nimbus| {
+            match nimbus.mkdir_fs(req, parent.into(), name, mode, umask) {
+                Ok(attr) => {
+                    nimbus.inc_lookup_count(attr.ino.into());
+                    reply.entry(&nimbus.entry_ttl(), &attr, 0)
+                }
+                Err(error) => reply.error(parse_error_cint(error)),
+            }
+        });
     }
 
     fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        match self.rmdir_fs(req, parent.into(), name) {
-            Ok(_) => reply.ok(),
-            Err(error) => reply.error(parse_error_cint(error)),
-        }
+        self.guard("rmdir", parent, |nimbus| {
+            match nimbus.rmdir_fs(req, parent.into(), name) {
+                Ok(_) => reply.ok(),
+                Err(error) => reply.error(parse_error_cint(error)),
+            }
+        });
     }
 
     fn rename(
@@ -998,10 +1338,12 @@ impl Filesystem for NimbusFS {
         flags: u32,
         reply: ReplyEmpty,
     ) {
-        match self.rename_fs(req, parent.into(), name, new_parent.into(), new_name, flags) {
-            Ok(_) => reply.ok(),
-            Err(error) => reply.error(parse_error_cint(error)),
-        }
+        self.guard("rename", parent, |nimbus| {
+            match nimbus.rename_fs(req, parent.into(), name, new_parent.into(), new_name, flags) {
+                Ok(_) => reply.ok(),
+                Err(error) => reply.error(parse_error_cint(error)),
+            }
+        });
     }
     fn symlink(
         &mut self,
@@ -1011,29 +1353,112 @@ impl Filesystem for NimbusFS {
         link: &Path,
         reply: ReplyEntry,
     ) {
-        match self.symlink_fs(req, parent.into(), name, link) {
-            Ok(attr) => reply.entry(&ATTR_TTL, &attr, 0),
-            Err(error) => reply.error(parse_error_cint(error)),
-        }
+        self.guard("symlink", parent, |nimbus| {
+            match nimbus.symlink_fs(req, parent.into(), name, link) {
+                Ok(attr) => {
+                    nimbus.inc_lookup_count(attr.ino.into());
+                    reply.entry(&nimbus.entry_ttl(), &attr, 0)
+                }
+                Err(error) => reply.error(parse_error_cint(error)),
+            }
+        });
     }
     fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        match self.unlink_fs(req, parent.into(), name) {
-            Ok(_) => reply.ok(),
-            Err(error) => reply.error(parse_error_cint(error)),
-        }
+        self.guard("unlink", parent, |nimbus| {
+            match nimbus.unlink_fs(req, parent.into(), name) {
+                Ok(_) => reply.ok(),
+                Err(error) => reply.error(parse_error_cint(error)),
+            }
+        });
     }
     fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData) {
-        match self.readlink_fs(req, ino.into()) {
-            Ok(loc) => reply.data(
-                loc.to_str()
-                    .expect("Unable to convert PathBuf to str")
-                    .as_bytes(),
-            ),
-            Err(error) => reply.error(parse_error_cint(error)),
-        }
+        self.guard("readlink", ino, |nimbus| {
+            match nimbus.readlink_fs(req, ino.into()) {
+                Ok(loc) => reply.data(
+                    loc.to_str()
+                        .expect("Unable to convert PathBuf to str")
+                        .as_bytes(),
+                ),
+                Err(error) => reply.error(parse_error_cint(error)),
+            }
+        });
+    }
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        self.guard("access", ino, |nimbus| {
+            match nimbus.access_fs(req, ino.into(), mask) {
+                Ok(_) => reply.ok(),
+                Err(error) => reply.error(parse_error_cint(error)),
+            }
+        });
+    }
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.guard("forget", ino, |nimbus| {
+            trace!("forget called on {} ({} lookups)", ino, nlookup);
+            nimbus.forget_ino(ino.into(), nlookup);
+        });
+    }
+}
+
+// For offsets from the kernel that don't fit (negative ones)
+fn einval<E>(_: E) -> Error {
+    Error::from_raw_os_error(EINVAL)
+}
+
+// For positions and sizes the kernel can't be told about
+fn eoverflow<E>(_: E) -> Error {
+    Error::from_raw_os_error(EOVERFLOW)
+}
+
+// Only the owner (or root) may change the mode, owner or times of a file
+fn check_owner(req: &Request<'_>, metadata: &fs::Metadata) -> Result<()> {
+    if req.uid() == 0 || req.uid() == metadata.uid() {
+        Ok(())
+    } else {
+        Err(Error::from_raw_os_error(EPERM).into())
     }
-    fn forget(&mut self, _req: &Request<'_>, _ino: u64, _nlookup: u64) {
-        info!("forget called!");
+}
+
+// Evaluates the mode bits of a file against the requesting user, owner and groups
+fn check_permissions(req: &Request<'_>, metadata: &fs::Metadata, mask: i32) -> Result<()> {
+    let mode = metadata.mode();
+    let granted = if req.uid() == 0 {
+        // root may read and write anything, but only execute if some execute bit is set
+        let exec = if metadata.is_dir() || mode & 0o111 != 0 {
+            X_OK
+        } else {
+            0
+        };
+        R_OK | W_OK | exec
+    } else {
+        let bits = if req.uid() == metadata.uid() {
+            mode >> 6
+        } else if req.gid() == metadata.gid() || request_groups(req).contains(&metadata.gid()) {
+            mode >> 3
+        } else {
+            mode
+        };
+        (bits & 0o7) as i32
+    };
+
+    if mask & !granted & (R_OK | W_OK | X_OK) == 0 {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("access denied (mask {:o}, mode {:o})", mask, mode),
+        )
+        .into())
+    }
+}
+
+// Supplementary groups of the requesting process (fuse only hands us the primary gid)
+fn request_groups(req: &Request<'_>) -> Vec<u32> {
+    match Process::new(req.pid() as i32).and_then(|process| process.status()) {
+        Ok(status) => status.groups.into_iter().map(|gid| gid as u32).collect(),
+        Err(err) => {
+            warn!("unable to read groups of process {}: {:?}", req.pid(), err);
+            Vec::new()
+        }
     }
 }
 
@@ -1,22 +1,28 @@
 use libc::{
-    c_int, EISDIR, ENAMETOOLONG, ENOENT, ENOSYS, ENOTEMPTY, EPERM, O_ACCMODE, O_APPEND, O_RDONLY,
-    O_RDWR, O_WRONLY, PATH_MAX,
+    c_int, E2BIG, EACCES, EADDRINUSE, EADDRNOTAVAIL, EAGAIN, EBUSY, ECONNABORTED, ECONNREFUSED,
+    ECONNRESET, EDEADLK, EEXIST, EFBIG, EHOSTUNREACH, EINTR, EINVAL, EIO, EISDIR, EMLINK,
+    ENAMETOOLONG, ENETDOWN, ENETUNREACH, ENOENT, ENOMEM, ENOSPC, ENOSYS, ENOTCONN, ENOTDIR,
+    ENOTEMPTY, EPERM, EPIPE, EROFS, ESPIPE, ESTALE, ETIMEDOUT, ETXTBSY, EXDEV, O_ACCMODE, O_APPEND,
+    O_RDONLY, O_RDWR, O_WRONLY, PATH_MAX,
 };
 
 use log::{debug, error, info, trace, warn};
 use std::ffi::OsStr;
 use std::fs::FileType;
-use std::io::{ErrorKind, Result}; // O_EXEC, O_SEARCH,
+use std::io::ErrorKind; // O_EXEC, O_SEARCH,
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
 use std::time::{Duration, Instant, SystemTime};
 
 use serde::{Deserialize, Serialize};
 
+use crate::error::{NimbusError, Result};
 use crate::macros;
 
 use fuser::{
     FileAttr, Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
-    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, Request, TimeOrNow,
+    ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, Request,
+    TimeOrNow,
 };
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, Hash, PartialEq, Eq)]
@@ -95,7 +101,7 @@ pub trait Fuse {
         Ok(())
     }
 
-    fn getattr_fs(&mut self, req: &Request<'_>, ino: INode) -> std::io::Result<FileAttr>;
+    fn getattr_fs(&mut self, req: &Request<'_>, ino: INode) -> Result<FileAttr>;
     fn readdir_fs<'a>(
         &mut self,
         req: &Request<'_>,
@@ -103,13 +109,16 @@ pub trait Fuse {
         fh: IDirHandle,
         offset: i64,
         reply: &'a mut ReplyDirectory,
-    ) -> std::io::Result<&'a ReplyDirectory>;
-    fn lookup_fs(
+    ) -> Result<&'a ReplyDirectory>;
+    fn readdirplus_fs<'a>(
         &mut self,
         req: &Request<'_>,
-        parent: INode,
-        name: &OsStr,
-    ) -> std::io::Result<FileAttr>;
+        ino: INode,
+        fh: IDirHandle,
+        offset: i64,
+        reply: &'a mut ReplyDirectoryPlus,
+    ) -> Result<&'a ReplyDirectoryPlus>;
+    fn lookup_fs(&mut self, req: &Request<'_>, parent: INode, name: &OsStr) -> Result<FileAttr>;
     fn read_fs(
         &mut self,
         req: &Request<'_>,
@@ -119,7 +128,7 @@ pub trait Fuse {
         size: u32,
         flags: i32,
         lock_owner: Option<u64>,
-    ) -> std::io::Result<Vec<u8>>;
+    ) -> Result<Vec<u8>>;
     fn write_fs(
         &mut self,
         _req: &Request<'_>,
@@ -130,14 +139,9 @@ pub trait Fuse {
         write_flags: u32,
         flags: i32,
         lock_owner: Option<u64>,
-    ) -> std::io::Result<usize>;
+    ) -> Result<usize>;
 
-    fn open_fs(
-        &mut self,
-        req: &Request<'_>,
-        ino: INode,
-        flags: i32,
-    ) -> std::io::Result<IFileHandle>; // might also want to return flags in the future
+    fn open_fs(&mut self, req: &Request<'_>, ino: INode, flags: i32) -> Result<IFileHandle>; // might also want to return flags in the future
 
     fn create_fs(
         &mut self,
@@ -147,7 +151,7 @@ pub trait Fuse {
         mode: u32,
         umask: u32,
         flags: i32,
-    ) -> std::io::Result<FileCreate>;
+    ) -> Result<FileCreate>;
 
     fn setattr_fs(
         &mut self,
@@ -165,7 +169,7 @@ pub trait Fuse {
         chgtime: Option<SystemTime>,
         bkuptime: Option<SystemTime>,
         flags: Option<u32>,
-    ) -> std::io::Result<FileAttr>;
+    ) -> Result<FileAttr>;
 
     fn flush_fs(
         &mut self,
@@ -173,7 +177,14 @@ pub trait Fuse {
         ino: INode,
         fh: IFileHandle,
         lock_owner: u64,
-    ) -> std::io::Result<()>;
+    ) -> Result<()>;
+    fn fsync_fs(
+        &mut self,
+        req: &Request<'_>,
+        ino: INode,
+        fh: IFileHandle,
+        datasync: bool,
+    ) -> Result<()>;
     fn release_fs(
         &mut self,
         req: &Request<'_>,
@@ -182,21 +193,23 @@ pub trait Fuse {
         flags: i32,
         lock_owner: Option<u64>,
         flush: bool,
-    ) -> std::io::Result<()>;
+    ) -> Result<()>;
 
-    fn opendir_fs(
+    fn opendir_fs(&mut self, req: &Request<'_>, ino: INode, _flags: i32) -> Result<IDirHandle>;
+    fn fsyncdir_fs(
         &mut self,
         req: &Request<'_>,
         ino: INode,
-        _flags: i32,
-    ) -> std::io::Result<IDirHandle>;
+        fh: IDirHandle,
+        datasync: bool,
+    ) -> Result<()>;
     fn releasedir_fs(
         &mut self,
         req: &Request<'_>,
         ino: INode,
         fh: IDirHandle,
         flags: i32,
-    ) -> std::io::Result<()>;
+    ) -> Result<()>;
 
     fn mkdir_fs(
         &mut self,
@@ -205,8 +218,8 @@ pub trait Fuse {
         name: &OsStr,
         mode: u32,
         umask: u32,
-    ) -> std::io::Result<FileAttr>;
-    fn rmdir_fs(&mut self, req: &Request<'_>, parent: INode, name: &OsStr) -> std::io::Result<()>;
+    ) -> Result<FileAttr>;
+    fn rmdir_fs(&mut self, req: &Request<'_>, parent: INode, name: &OsStr) -> Result<()>;
     fn rename_fs(
         &mut self,
         req: &Request<'_>,
@@ -215,17 +228,17 @@ pub trait Fuse {
         new_parent: INode,
         new_name: &OsStr,
         flags: u32,
-    ) -> std::io::Result<()>;
+    ) -> Result<()>;
     fn symlink_fs(
         &mut self,
         req: &Request<'_>,
         parent: INode,
         name: &OsStr,
         link: &Path,
-    ) -> std::io::Result<FileAttr>;
-    fn unlink_fs(&mut self, req: &Request<'_>, parent: INode, name: &OsStr) -> std::io::Result<()>;
-    fn readlink_fs(&mut self, req: &Request<'_>, ino: INode)
-        -> std::io::Result<std::path::PathBuf>;
+    ) -> Result<FileAttr>;
+    fn unlink_fs(&mut self, req: &Request<'_>, parent: INode, name: &OsStr) -> Result<()>;
+    fn readlink_fs(&mut self, req: &Request<'_>, ino: INode) -> Result<std::path::PathBuf>;
+    fn access_fs(&mut self, req: &Request<'_>, ino: INode, mask: i32) -> Result<()>;
 }
 
 // todo: create type alias for file handler
@@ -248,14 +261,85 @@ impl FileCreate {
 //     name: OsStr,
 // }
 
-pub fn parse_error_cint(error: std::io::Error) -> c_int {
+// Runs a FUSE callback, turning a panic into a log line instead of a dead mount.
+// The reply, dropped while unwinding, answers the kernel with EIO.
+pub fn catch_panic<T>(operation: &str, ino: u64, callback: impl FnOnce() -> T) -> Option<T> {
+    match panic::catch_unwind(AssertUnwindSafe(callback)) {
+        Ok(result) => Some(result),
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            error!(
+                "{} on inode {} panicked, replying EIO: {}",
+                operation, ino, message
+            );
+            None
+        }
+    }
+}
+
+pub fn parse_error_cint(error: NimbusError) -> c_int {
     info!("parse error: {:?}", error);
     // info!("{}", std::backtrace::Backtrace::capture());
     // panic!();
+    match error {
+        NimbusError::Io(error) => io_error_cint(&error),
+        // the project is read-only here until the holder lets go
+        NimbusError::LockHeld { .. } => EROFS,
+        NimbusError::PeerUnreachable { .. } => EHOSTUNREACH,
+        NimbusError::SyncFailed { .. } => EIO,
+        NimbusError::ConfigInvalid(_) => EINVAL,
+        NimbusError::InodeNotFound(_) => ENOENT,
+        NimbusError::NotInProject(_) => EINVAL,
+    }
+}
+
+fn io_error_cint(error: &std::io::Error) -> c_int {
+    // straight from the backing store, which already told us what went wrong
+    if let Some(errno) = error.raw_os_error() {
+        return errno;
+    }
     match error.kind() {
         ErrorKind::NotFound => ENOENT,
-        ErrorKind::InvalidFilename => ENAMETOOLONG, // is this right?
+        ErrorKind::PermissionDenied => EACCES,
+        ErrorKind::ConnectionRefused => ECONNREFUSED,
+        ErrorKind::ConnectionReset => ECONNRESET,
+        ErrorKind::HostUnreachable => EHOSTUNREACH,
+        ErrorKind::NetworkUnreachable => ENETUNREACH,
+        ErrorKind::ConnectionAborted => ECONNABORTED,
+        ErrorKind::NotConnected => ENOTCONN,
+        ErrorKind::AddrInUse => EADDRINUSE,
+        ErrorKind::AddrNotAvailable => EADDRNOTAVAIL,
+        ErrorKind::NetworkDown => ENETDOWN,
+        ErrorKind::BrokenPipe => EPIPE,
+        ErrorKind::AlreadyExists => EEXIST,
+        ErrorKind::WouldBlock => EAGAIN,
+        ErrorKind::NotADirectory => ENOTDIR,
+        ErrorKind::IsADirectory => EISDIR,
         ErrorKind::DirectoryNotEmpty => ENOTEMPTY,
-        _ => todo!(),
+        ErrorKind::ReadOnlyFilesystem => EROFS,
+        ErrorKind::StaleNetworkFileHandle => ESTALE,
+        ErrorKind::InvalidInput => EINVAL,
+        ErrorKind::InvalidData => EINVAL,
+        ErrorKind::TimedOut => ETIMEDOUT,
+        ErrorKind::WriteZero => EIO,
+        ErrorKind::StorageFull => ENOSPC,
+        ErrorKind::NotSeekable => ESPIPE,
+        ErrorKind::FileTooLarge => EFBIG,
+        ErrorKind::ResourceBusy => EBUSY,
+        ErrorKind::ExecutableFileBusy => ETXTBSY,
+        ErrorKind::Deadlock => EDEADLK,
+        ErrorKind::CrossesDevices => EXDEV,
+        ErrorKind::TooManyLinks => EMLINK,
+        ErrorKind::InvalidFilename => ENAMETOOLONG, // is this right?
+        ErrorKind::ArgumentListTooLong => E2BIG,
+        ErrorKind::Interrupted => EINTR,
+        ErrorKind::Unsupported => ENOSYS,
+        ErrorKind::UnexpectedEof => EIO,
+        ErrorKind::OutOfMemory => ENOMEM,
+        _ => EIO,
     }
 }
@@ -0,0 +1,180 @@
+use chrono::prelude::*;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::client::PeerClient;
+use crate::config::TuningConfig;
+use crate::sync::Syncer;
+
+/// Whether a peer answered its last ping
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reachability {
+    /// Not pinged yet (or it can't be, without a secret)
+    Unknown,
+    Up,
+    Down,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PeerHealth {
+    pub name: String,
+    pub reachability: Reachability,
+    /// Round trip of the last answered ping
+    pub latency_ms: Option<u64>,
+    /// When the peer last answered
+    pub last_seen: Option<DateTime<Utc>>,
+    /// Why the last ping failed
+    pub error: Option<String>,
+}
+
+impl PeerHealth {
+    fn unknown(name: &str) -> PeerHealth {
+        PeerHealth {
+            name: name.to_string(),
+            reachability: Reachability::Unknown,
+            latency_ms: None,
+            last_seen: None,
+            error: None,
+        }
+    }
+}
+
+/// Pings every peer in the background and remembers who answered
+#[derive(Clone)]
+pub struct Prober {
+    /// Knows the peers (as of the last reload or discovery)
+    syncer: Syncer,
+    tuning: Arc<Mutex<TuningConfig>>,
+    health: Arc<Mutex<HashMap<String, PeerHealth>>>,
+}
+
+impl Prober {
+    pub fn new(syncer: Syncer, tuning: Arc<Mutex<TuningConfig>>) -> Prober {
+        Prober {
+            syncer,
+            tuning,
+            health: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Every peer, by name
+    pub fn peers(&self) -> Vec<PeerHealth> {
+        let mut names = self.syncer.peers();
+        names.sort();
+        let health = self.health.lock().expect("lock failed");
+        names
+            .iter()
+            .map(|name| {
+                health
+                    .get(name)
+                    .cloned()
+                    .unwrap_or_else(|| PeerHealth::unknown(name))
+            })
+            .collect()
+    }
+
+    pub fn reachability(&self, peer: &str) -> Reachability {
+        let health = self.health.lock().expect("lock failed");
+        health
+            .get(peer)
+            .map_or(Reachability::Unknown, |health| health.reachability)
+    }
+
+    // Pings every peer once, all at the same time
+    pub async fn probe(&self) {
+        let timeout = self.tuning.lock().expect("lock failed").probe_timeout();
+        let pings: Vec<_> = self
+            .syncer
+            .clients()
+            .into_iter()
+            .map(|client| tokio::spawn(ping(client, timeout)))
+            .collect();
+        let mut results = Vec::new();
+        for ping in pings {
+            if let Ok(result) = ping.await {
+                results.push(result);
+            }
+        }
+        self.record(results);
+    }
+
+    // Pings peer right away instead of waiting for the next round; a peer that can't be
+    // pinged (without a secret) stays Unknown
+    pub async fn probe_peer(&self, peer: &str) -> Reachability {
+        let timeout = self.tuning.lock().expect("lock failed").probe_timeout();
+        let client = self
+            .syncer
+            .clients()
+            .into_iter()
+            .find(|client| client.peer() == peer);
+        if let Some(client) = client {
+            self.record(vec![ping(client, timeout).await]);
+        }
+        self.reachability(peer)
+    }
+
+    fn record(&self, results: Vec<PeerHealth>) {
+        let peers = self.syncer.peers();
+        let mut health = self.health.lock().expect("lock failed");
+        // forget peers that were removed from the config
+        health.retain(|name, _| peers.contains(name));
+        for result in results {
+            let previous = health
+                .get(&result.name)
+                .map_or(Reachability::Unknown, |health| health.reachability);
+            if previous != result.reachability {
+                match result.reachability {
+                    Reachability::Down => warn!(
+                        "{} is down: {}",
+                        result.name,
+                        result.error.as_deref().unwrap_or_default()
+                    ),
+                    _ => info!("{} is {:?}", result.name, result.reachability),
+                }
+            }
+            let last_seen = match result.last_seen {
+                Some(last_seen) => Some(last_seen),
+                None => health.get(&result.name).and_then(|health| health.last_seen),
+            };
+            health.insert(
+                result.name.clone(),
+                PeerHealth {
+                    last_seen,
+                    ..result
+                },
+            );
+        }
+    }
+
+    pub async fn run(self) {
+        loop {
+            self.probe().await;
+            let interval = self.tuning.lock().expect("lock failed").probe_interval();
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+async fn ping(client: PeerClient, timeout: std::time::Duration) -> PeerHealth {
+    let mut health = PeerHealth::unknown(client.peer());
+    let start = Instant::now();
+    match tokio::time::timeout(timeout, client.ping()).await {
+        Ok(Ok(_)) => {
+            health.reachability = Reachability::Up;
+            health.latency_ms = Some(start.elapsed().as_millis() as u64);
+            health.last_seen = Some(Utc::now());
+        }
+        Ok(Err(err)) => {
+            health.reachability = Reachability::Down;
+            health.error = Some(format!("{:?}", err));
+        }
+        Err(_) => {
+            health.reachability = Reachability::Down;
+            health.error = Some(format!("no answer within {:?}", timeout));
+        }
+    }
+    health
+}
@@ -1,12 +1,17 @@
+use crate::error::Result;
 use crate::fuse::INode;
 use crate::index::LockStatus::*;
+use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum LockStatus {
     WeHaveLock(String),     // we have the lock
     SomeoneHasLock(String), // somebody has the lock
@@ -15,113 +20,460 @@ pub enum LockStatus {
 
 pub type CanonicalProjectName = PathBuf; // for now
 
+/// How long a lock lent to a peer stays valid without being renewed (unless tuned)
+pub const LOCK_LEASE: Duration = Duration::from_secs(300);
+
+/// Bookkeeping that goes along with a lock
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Lease {
+    /// Bumped every time the lock changes hands
+    pub epoch: u64,
+    /// When a lock lent to a peer lapses (None if nobody borrowed it)
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl Lease {
+    pub fn expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires.map_or(false, |expires| expires <= now)
+    }
+}
+
+/// What peers get back from the lock endpoints
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LockResponse {
+    pub holder: Option<String>,
+    pub epoch: u64,
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl LockResponse {
+    fn new(lock: &LockStatus, lease: &Lease) -> LockResponse {
+        LockResponse {
+            holder: match lock {
+                WeHaveLock(holder) | SomeoneHasLock(holder) => Some(holder.clone()),
+                NobodyHasLock => None,
+            },
+            epoch: lease.epoch,
+            expires: lease.expires,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LockError {
+    UnknownProject,
+    /// Somebody else holds the lock
+    Held(LockResponse),
+    /// The caller does not hold the lock (or holds an outdated epoch of it)
+    NotHeld(LockResponse),
+    /// Too few machines agreed to lend us the lock
+    NoQuorum { agreed: usize, needed: usize },
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct Index {
     // counter: u64,
     pub project_lock: HashMap<CanonicalProjectName, LockStatus>, // ProjectID != INode because we may want to rename projects
+    pub project_lease: HashMap<CanonicalProjectName, Lease>,
     pub index_lock: LockStatus,
+    pub index_lease: Lease,
+    /// How long the locks we lend out stay valid (from [tuning], not persisted)
+    #[serde(skip, default = "default_lock_lease")]
+    pub lock_lease: Duration,
+}
+
+fn default_lock_lease() -> Duration {
+    LOCK_LEASE
 }
 
 impl Index {
     pub fn new() -> Index {
         Index {
             project_lock: HashMap::new(),
+            project_lease: HashMap::new(),
             index_lock: LockStatus::NobodyHasLock,
+            index_lease: Lease::default(),
+            lock_lease: LOCK_LEASE,
         }
     }
 
-    pub fn acquire_project_lock(
+    // Written through a temporary file so a crash never leaves half an index behind
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut temporary = path.as_os_str().to_os_string();
+        temporary.push(".tmp");
+        fs::write(
+            &temporary,
+            serde_json::to_vec_pretty(self).map_err(io::Error::from)?,
+        )?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    // None if nothing was persisted yet
+    pub fn load(path: &Path) -> Result<Option<Index>> {
+        match fs::read(path) {
+            Ok(contents) => Ok(Some(
+                serde_json::from_slice(&contents).map_err(io::Error::from)?,
+            )),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Projects start out unlocked the first time we see them
+    pub fn register_project(&mut self, project: CanonicalProjectName) {
+        self.project_lock.entry(project).or_insert(NobodyHasLock);
+    }
+
+    pub fn project_lock_response(
+        &self,
+        project: &CanonicalProjectName,
+    ) -> Result<LockResponse, LockError> {
+        let lock = self
+            .project_lock
+            .get(project)
+            .ok_or(LockError::UnknownProject)?;
+        let lease = self.project_lease.get(project).cloned().unwrap_or_default();
+        Ok(LockResponse::new(lock, &lease))
+    }
+
+    // A peer asks us for the lock on project; with an epoch it is voting in a quorum,
+    // and steal ignores another peer's unexpired lease
+    pub fn lend_project_lock(
         &mut self,
-        project: CanonicalProjectName,
+        project: &CanonicalProjectName,
         machine_name: String,
-    ) -> bool {
-        // returns true on success
-        match self.project_lock.get(&project) {
-            Some(WeHaveLock(_)) => true, // fast path
-            Some(SomeoneHasLock(other)) => {
-                // obtain lock
-                if other == &machine_name {
-                    true
-                } else {
-                    todo!();
-                    // try to request for lock
-                    false
+        epoch: Option<u64>,
+        steal: bool,
+    ) -> Result<LockResponse, LockError> {
+        let lock = self
+            .project_lock
+            .get_mut(project)
+            .ok_or(LockError::UnknownProject)?;
+        let lease = self.project_lease.entry(project.clone()).or_default();
+        lend_lock(lock, lease, machine_name, self.lock_lease, epoch, steal)
+    }
+
+    // A peer hands the lock on project back to us
+    pub fn return_project_lock(
+        &mut self,
+        project: &CanonicalProjectName,
+        machine_name: String,
+        epoch: Option<u64>,
+    ) -> Result<LockResponse, LockError> {
+        let lock = self
+            .project_lock
+            .get_mut(project)
+            .ok_or(LockError::UnknownProject)?;
+        let lease = self.project_lease.entry(project.clone()).or_default();
+        return_lock(lock, lease, machine_name, epoch)
+    }
+
+    // Takes back every lock lent to machine (it left [network]); returns the projects
+    pub fn revoke_locks(&mut self, machine_name: &str) -> Vec<CanonicalProjectName> {
+        let holder = SomeoneHasLock(machine_name.to_string());
+        if self.index_lock == holder {
+            self.index_lock = NobodyHasLock;
+            self.index_lease.expires = None;
+        }
+        let mut revoked = Vec::new();
+        for (project, lock) in self.project_lock.iter_mut() {
+            if *lock == holder {
+                *lock = NobodyHasLock;
+                if let Some(lease) = self.project_lease.get_mut(project) {
+                    lease.expires = None;
                 }
+                revoked.push(project.clone());
             }
-            Some(NobodyHasLock) => {
-                // obtain lock after inactivity
-                // tell everyone that we want the lock
-                todo!();
-                self.project_lock
-                    .insert(project, SomeoneHasLock(machine_name))
-                    .expect("this should never happen");
-                true
-            }
-            None => panic!(
-                "attempted to acquire lock for {} in {:?}, but does not exist in the index! panic!",
-                machine_name, project
-            ),
         }
+        revoked
     }
-    pub fn release_project_lock(
+
+    // We take the lock on project ourselves; steal ignores a peer's unexpired lease, and
+    // epoch is the one a quorum agreed on
+    pub fn take_project_lock(
         &mut self,
-        project: CanonicalProjectName,
+        project: &CanonicalProjectName,
         machine_name: String,
+        steal: bool,
+        epoch: Option<u64>,
+    ) -> Result<LockResponse, LockError> {
+        let lock = self
+            .project_lock
+            .get_mut(project)
+            .ok_or(LockError::UnknownProject)?;
+        let lease = self.project_lease.entry(project.clone()).or_default();
+        take_lock(lock, lease, machine_name, steal, epoch)
+    }
+
+    // We lost the lock on project at epoch, to whoever holds it now (if anyone);
+    // false if we didn't hold that epoch anymore
+    pub fn lose_project_lock(
+        &mut self,
+        project: &CanonicalProjectName,
+        epoch: u64,
+        now: Option<&LockResponse>,
     ) -> bool {
-        // returns true on success
-        match self.project_lock.get(&project) {
-            Some(WeHaveLock(_)) => {
-                todo!();
-                true
-                // notify others
+        let lease = self.project_lease.entry(project.clone()).or_default();
+        let lock = match self.project_lock.get_mut(project) {
+            Some(lock) if matches!(lock, WeHaveLock(_)) && lease.epoch == epoch => lock,
+            _ => return false,
+        };
+        match now {
+            Some(LockResponse {
+                holder: Some(holder),
+                epoch,
+                expires,
+            }) => {
+                *lock = SomeoneHasLock(holder.clone());
+                lease.epoch = lease.epoch.max(*epoch);
+                lease.expires = *expires;
             }
-            Some(SomeoneHasLock(other)) => {
-                // obtain lock
-                if other == &machine_name {
-                    todo!();
-                    true // notify others
-                } else {
-                    false
-                }
+            _ => {
+                *lock = NobodyHasLock;
+                lease.expires = None;
             }
-            Some(NobodyHasLock) => false,
-            None => panic!(
-                "attempted to acquire lock for {} in {:?}, but does not exist in the index! panic!",
-                machine_name, project
-            ),
         }
+        true
+    }
+
+    pub fn lend_index_lock(&mut self, machine_name: String) -> Result<LockResponse, LockError> {
+        lend_lock(
+            &mut self.index_lock,
+            &mut self.index_lease,
+            machine_name,
+            self.lock_lease,
+            None,
+            false,
+        )
+    }
+
+    pub fn return_index_lock(
+        &mut self,
+        machine_name: String,
+        epoch: Option<u64>,
+    ) -> Result<LockResponse, LockError> {
+        return_lock(
+            &mut self.index_lock,
+            &mut self.index_lease,
+            machine_name,
+            epoch,
+        )
     }
 }
 
-pub fn acquire_project_lock(
-    index: Arc<Mutex<Index>>,
-    project: CanonicalProjectName,
+fn lend_lock(
+    lock: &mut LockStatus,
+    lease: &mut Lease,
     machine_name: String,
-) -> bool {
+    duration: Duration,
+    epoch: Option<u64>,
+    steal: bool,
+) -> Result<LockResponse, LockError> {
+    let now = Utc::now();
+    let renewing = lock == &SomeoneHasLock(machine_name.clone());
+    let available = match lock {
+        WeHaveLock(_) => false,                           // mine, not yours
+        SomeoneHasLock(_) if renewing => true,            // you already have it, renew
+        SomeoneHasLock(_) => steal || lease.expired(now), // only once their lease lapsed
+        NobodyHasLock => true,
+    };
+    // a proposal for an epoch we already moved past comes from an outdated holder
+    let current = match epoch {
+        Some(epoch) if renewing => epoch >= lease.epoch,
+        Some(epoch) => epoch > lease.epoch,
+        None => true,
+    };
+    if !available || !current {
+        return Err(LockError::Held(LockResponse::new(lock, lease)));
+    }
+    match epoch {
+        Some(epoch) => lease.epoch = epoch,
+        None if !renewing => lease.epoch += 1,
+        None => (),
+    }
+    *lock = SomeoneHasLock(machine_name);
+    lease.expires = Some(now + chrono::Duration::from_std(duration).expect("Overflow"));
+    Ok(LockResponse::new(lock, lease))
+}
+
+fn take_lock(
+    lock: &mut LockStatus,
+    lease: &mut Lease,
+    machine_name: String,
+    steal: bool,
+    epoch: Option<u64>,
+) -> Result<LockResponse, LockError> {
+    let held = matches!(lock, WeHaveLock(_));
+    let available = match lock {
+        WeHaveLock(_) => true,
+        SomeoneHasLock(_) => steal || lease.expired(Utc::now()),
+        NobodyHasLock => true,
+    };
+    let current = match epoch {
+        Some(epoch) if held => epoch >= lease.epoch,
+        Some(epoch) => epoch > lease.epoch,
+        None => true,
+    };
+    if !available || !current {
+        return Err(LockError::Held(LockResponse::new(lock, lease)));
+    }
+    match epoch {
+        Some(epoch) => lease.epoch = epoch,
+        // whoever held it before now holds an outdated epoch
+        None if !held => lease.epoch += 1,
+        None => (),
+    }
+    *lock = WeHaveLock(machine_name);
+    lease.expires = None;
+    Ok(LockResponse::new(lock, lease))
+}
+
+fn return_lock(
+    lock: &mut LockStatus,
+    lease: &mut Lease,
+    machine_name: String,
+    epoch: Option<u64>,
+) -> Result<LockResponse, LockError> {
+    let holds = lock == &SomeoneHasLock(machine_name);
+    if !holds || epoch.map_or(false, |epoch| epoch != lease.epoch) {
+        return Err(LockError::NotHeld(LockResponse::new(lock, lease)));
+    }
+    *lock = NobodyHasLock;
+    lease.expires = None;
+    Ok(LockResponse::new(lock, lease))
+}
+
+// Sits next to local_storage rather than inside it, where only projects live
+pub fn default_state_path(local_storage: &Path) -> PathBuf {
+    let mut name = local_storage.file_name().unwrap_or_default().to_os_string();
+    name.push(".index.json");
+    local_storage.with_file_name(name)
+}
+
+// Only clears our side, the peers are told through Quorum::release
+pub fn release_project_lock(index: Arc<Mutex<Index>>, project: CanonicalProjectName) -> bool {
     let mut index = index.lock().expect("lock failed");
     match index.project_lock.get(&project) {
-        Some(WeHaveLock(_)) => true, // fast path
-        Some(SomeoneHasLock(other)) => {
-            // obtain lock
-            if other == &machine_name {
-                true
-            } else {
-                // try to request for lock
-                false
-            }
-        }
-        Some(NobodyHasLock) => {
-            // obtain lock after inactivity
-            // tell everyone that we want the lock
-            index
-                .project_lock
-                .insert(project, SomeoneHasLock(machine_name))
-                .expect("this should never happen");
+        Some(WeHaveLock(_)) => {
+            index.project_lock.insert(project, NobodyHasLock);
             true
         }
-        None => panic!(
-            "attempted to acquire lock for {} in {:?}, but does not exist in the index! panic!",
-            machine_name, project
-        ),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project() -> CanonicalProjectName {
+        PathBuf::from("project")
+    }
+
+    fn index() -> Index {
+        let mut index = Index::new();
+        index.register_project(project());
+        index
+    }
+
+    fn lend(index: &mut Index, machine: &str, epoch: Option<u64>, steal: bool) -> Option<u64> {
+        index
+            .lend_project_lock(&project(), machine.to_string(), epoch, steal)
+            .ok()
+            .map(|response| response.epoch)
+    }
+
+    fn lock(index: &Index) -> LockStatus {
+        index.project_lock[&project()].clone()
+    }
+
+    #[test]
+    fn proposals_below_the_current_epoch_are_refused() {
+        let mut index = index();
+        assert_eq!(lend(&mut index, "b", Some(2), false), Some(2));
+
+        // even stealing takes a higher epoch
+        assert_eq!(lend(&mut index, "c", Some(2), true), None);
+        assert_eq!(lend(&mut index, "c", Some(3), true), Some(3));
+        assert_eq!(lock(&index), SomeoneHasLock("c".to_string()));
+
+        // b was cut off: it can neither renew nor hand back what it held
+        assert_eq!(lend(&mut index, "b", Some(2), false), None);
+        assert!(matches!(
+            index.return_project_lock(&project(), "b".to_string(), Some(2)),
+            Err(LockError::NotHeld(_))
+        ));
+        assert!(matches!(
+            index.return_project_lock(&project(), "c".to_string(), Some(2)),
+            Err(LockError::NotHeld(_))
+        ));
+        let returned = index
+            .return_project_lock(&project(), "c".to_string(), Some(3))
+            .unwrap();
+        assert_eq!((returned.holder, returned.epoch), (None, 3));
+
+        // the epoch stays where it was once the lock is free again
+        assert_eq!(lend(&mut index, "b", Some(3), false), None);
+        assert_eq!(lend(&mut index, "b", Some(4), false), Some(4));
+    }
+
+    #[test]
+    fn holders_renew_at_their_epoch() {
+        let mut index = index();
+        assert_eq!(lend(&mut index, "b", None, false), Some(1));
+        assert_eq!(lend(&mut index, "b", None, false), Some(1));
+        assert_eq!(lend(&mut index, "b", Some(1), false), Some(1));
+        assert_eq!(lend(&mut index, "b", Some(0), false), None);
+        assert_eq!(lend(&mut index, "c", None, false), None);
+        assert_eq!(lock(&index), SomeoneHasLock("b".to_string()));
+    }
+
+    #[test]
+    fn lapsed_leases_are_granted_again() {
+        let mut index = index();
+        index.lock_lease = Duration::ZERO;
+        assert_eq!(lend(&mut index, "b", None, false), Some(1));
+        assert_eq!(lend(&mut index, "c", None, false), Some(2));
+        assert_eq!(lend(&mut index, "b", Some(3), false), Some(3));
+        assert!(index
+            .take_project_lock(&project(), "a".to_string(), false, Some(4))
+            .is_ok());
+        assert_eq!(lock(&index), WeHaveLock("a".to_string()));
+    }
+
+    #[test]
+    fn taking_the_lock_fences_the_previous_holder() {
+        let mut index = index();
+        assert_eq!(lend(&mut index, "b", None, false), Some(1));
+        assert!(matches!(
+            index.take_project_lock(&project(), "a".to_string(), false, None),
+            Err(LockError::Held(_))
+        ));
+        let taken = index
+            .take_project_lock(&project(), "a".to_string(), true, None)
+            .unwrap();
+        assert_eq!((taken.epoch, taken.expires), (2, None));
+        assert_eq!(lend(&mut index, "b", Some(1), false), None);
+
+        // renewing at the epoch we hold, but never below it
+        assert!(index
+            .take_project_lock(&project(), "a".to_string(), false, Some(2))
+            .is_ok());
+        assert!(index
+            .take_project_lock(&project(), "a".to_string(), false, Some(1))
+            .is_err());
+
+        // losing an epoch we already moved past changes nothing
+        assert!(!index.lose_project_lock(&project(), 1, None));
+        assert_eq!(lock(&index), WeHaveLock("a".to_string()));
+        let now = LockResponse {
+            holder: Some("c".to_string()),
+            epoch: 5,
+            expires: None,
+        };
+        assert!(index.lose_project_lock(&project(), 2, Some(&now)));
+        assert_eq!(lock(&index), SomeoneHasLock("c".to_string()));
+        assert_eq!(index.project_lease[&project()].epoch, 5);
     }
 }
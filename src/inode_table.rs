@@ -0,0 +1,286 @@
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::ffi::{OsStr, OsString};
+use std::path::PathBuf;
+
+use crate::fuse::INode;
+
+pub const ROOT_DIR: INode = (1 as u64).into();
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct INodeEntry {
+    pub parent: INode,
+    pub name: OsString,
+}
+
+/// Inode table keyed by (parent inode, name) instead of full paths, so that
+/// renaming a directory is O(1) and everything below it stays valid
+pub struct INodeTable {
+    /// Backing path of the root inode
+    root: PathBuf,
+
+    /// Where each inode lives
+    entries: FxHashMap<INode, INodeEntry>,
+    /// Reverse mapping from (parent, name) to inode
+    children: FxHashMap<(INode, OsString), INode>,
+    /// Names known in every directory, so the subtree of a removed one can be dropped
+    listing: FxHashMap<INode, FxHashSet<OsString>>,
+
+    /// Last inode allocated
+    last_ino_alloc: INode,
+}
+
+impl INodeTable {
+    pub fn new(root: PathBuf) -> INodeTable {
+        INodeTable {
+            root,
+            entries: FxHashMap::default(),
+            children: FxHashMap::default(),
+            listing: FxHashMap::default(),
+            last_ino_alloc: ROOT_DIR,
+        }
+    }
+
+    pub fn fresh_ino(&mut self) -> INode {
+        self.last_ino_alloc.inc();
+        self.last_ino_alloc
+    }
+
+    // Walks up the parents to reconstruct the backing path of ino
+    pub fn path(&self, ino: INode) -> Option<PathBuf> {
+        let mut names = Vec::new();
+        let mut current = ino;
+        while current != ROOT_DIR {
+            let entry = self.entries.get(&current)?;
+            names.push(&entry.name);
+            current = entry.parent;
+        }
+        let mut path = self.root.clone();
+        path.extend(names.into_iter().rev());
+        Some(path)
+    }
+
+    pub fn entry(&self, ino: INode) -> Option<&INodeEntry> {
+        self.entries.get(&ino)
+    }
+
+    pub fn lookup(&self, parent: INode, name: &OsStr) -> Option<INode> {
+        self.children.get(&(parent, name.to_os_string())).copied()
+    }
+
+    pub fn lookup_or_create(&mut self, parent: INode, name: &OsStr) -> INode {
+        match self.lookup(parent, name) {
+            Some(ino) => ino,
+            None => {
+                let ino = self.fresh_ino();
+                self.insert(ino, parent, name.to_os_string());
+                ino
+            }
+        }
+    }
+
+    fn insert(&mut self, ino: INode, parent: INode, name: OsString) {
+        self.children.insert((parent, name.clone()), ino);
+        self.listing.entry(parent).or_default().insert(name.clone());
+        self.entries.insert(ino, INodeEntry { parent, name });
+    }
+
+    // Takes (parent, name) out of the table, leaving what is below it in place
+    fn detach(&mut self, parent: INode, name: &OsStr) -> Option<INode> {
+        let ino = self.children.remove(&(parent, name.to_os_string()))?;
+        if let Some(names) = self.listing.get_mut(&parent) {
+            names.remove(name);
+            if names.is_empty() {
+                self.listing.remove(&parent);
+            }
+        }
+        self.entries.remove(&ino);
+        Some(ino)
+    }
+
+    // Drops everything below ino
+    fn prune(&mut self, ino: INode) {
+        let mut pending = vec![ino];
+        while let Some(parent) = pending.pop() {
+            for name in self.listing.remove(&parent).unwrap_or_default() {
+                if let Some(child) = self.children.remove(&(parent, name)) {
+                    self.entries.remove(&child);
+                    pending.push(child);
+                }
+            }
+        }
+    }
+
+    // Removes (parent, name) from the table, along with anything below it
+    pub fn remove(&mut self, parent: INode, name: &OsStr) -> Option<INode> {
+        let ino = self.detach(parent, name)?;
+        self.prune(ino);
+        Some(ino)
+    }
+
+    // Drops an inode the kernel no longer references (it holds on to the parents of
+    // everything it references, so nothing below ino is in use either)
+    pub fn forget(&mut self, ino: INode) {
+        if let Some(entry) = self.entries.get(&ino) {
+            if self.children.get(&(entry.parent, entry.name.clone())) == Some(&ino) {
+                let (parent, name) = (entry.parent, entry.name.clone());
+                self.remove(parent, &name);
+            } else {
+                self.entries.remove(&ino);
+                self.prune(ino);
+            }
+        }
+    }
+
+    // Moves (parent, name) to (new_parent, new_name), dropping whatever it replaced
+    pub fn rename(
+        &mut self,
+        parent: INode,
+        name: &OsStr,
+        new_parent: INode,
+        new_name: &OsStr,
+    ) -> Option<INode> {
+        if parent == new_parent && name == new_name {
+            return self.lookup(parent, name);
+        }
+        let ino = self.lookup(parent, name)?;
+        self.remove(new_parent, new_name);
+        self.detach(parent, name);
+        self.insert(ino, new_parent, new_name.to_os_string());
+        Some(ino)
+    }
+
+    // Swaps the inodes living at (parent, name) and (new_parent, new_name)
+    pub fn exchange(&mut self, parent: INode, name: &OsStr, new_parent: INode, new_name: &OsStr) {
+        let ino = self.detach(parent, name);
+        let other_ino = self.detach(new_parent, new_name);
+        if let Some(ino) = ino {
+            self.insert(ino, new_parent, new_name.to_os_string());
+        }
+        if let Some(other_ino) = other_ino {
+            self.insert(other_ino, parent, name.to_os_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table() -> INodeTable {
+        INodeTable::new(PathBuf::from("/storage"))
+    }
+
+    // Creates every component of path below the root, returning the last one
+    fn create(table: &mut INodeTable, path: &str) -> INode {
+        path.split('/').fold(ROOT_DIR, |parent, name| {
+            table.lookup_or_create(parent, OsStr::new(name))
+        })
+    }
+
+    fn path(table: &INodeTable, ino: INode) -> Option<String> {
+        table
+            .path(ino)
+            .map(|path| path.to_string_lossy().into_owned())
+    }
+
+    #[test]
+    fn builds_paths_from_parents() {
+        let mut table = table();
+        let file = create(&mut table, "project/src/main.rs");
+        assert_eq!(path(&table, ROOT_DIR).as_deref(), Some("/storage"));
+        assert_eq!(
+            path(&table, file).as_deref(),
+            Some("/storage/project/src/main.rs")
+        );
+        assert_eq!(create(&mut table, "project/src/main.rs"), file);
+        let unknown = table.fresh_ino();
+        assert_eq!(path(&table, unknown), None);
+    }
+
+    #[test]
+    fn renamed_directories_keep_their_subtree() {
+        let mut table = table();
+        let file = create(&mut table, "project/src/main.rs");
+        let project = create(&mut table, "project");
+        let src = create(&mut table, "project/src");
+        let other = create(&mut table, "other");
+
+        assert_eq!(
+            table.rename(project, OsStr::new("src"), other, OsStr::new("lib")),
+            Some(src)
+        );
+        assert_eq!(
+            path(&table, file).as_deref(),
+            Some("/storage/other/lib/main.rs")
+        );
+        assert_eq!(table.lookup(project, OsStr::new("src")), None);
+        assert_eq!(table.lookup(other, OsStr::new("lib")), Some(src));
+    }
+
+    #[test]
+    fn replaced_directories_are_pruned() {
+        let mut table = table();
+        let replaced = create(&mut table, "project/old");
+        let below = create(&mut table, "project/old/deep/file");
+        let new = create(&mut table, "project/new");
+        let project = create(&mut table, "project");
+
+        table.rename(project, OsStr::new("new"), project, OsStr::new("old"));
+        assert_eq!(path(&table, new).as_deref(), Some("/storage/project/old"));
+        assert_eq!(path(&table, replaced), None);
+        assert_eq!(path(&table, below), None);
+        // the project, old (now new) and nothing else
+        assert_eq!(table.entries.len(), 2);
+        assert_eq!(table.children.len(), 2);
+        assert_eq!(table.listing.len(), 2);
+    }
+
+    #[test]
+    fn removed_and_forgotten_directories_are_pruned() {
+        let mut table = table();
+        create(&mut table, "project/a/b/c");
+        let d = create(&mut table, "project/d/e");
+        let project = create(&mut table, "project");
+
+        table.remove(project, OsStr::new("a"));
+        assert_eq!(table.entries.len(), 3);
+        let d_dir = table.lookup(project, OsStr::new("d")).unwrap();
+        table.forget(d_dir);
+        assert_eq!(path(&table, d), None);
+        assert_eq!(table.entries.len(), 1);
+        assert_eq!(table.children.len(), 1);
+        assert_eq!(table.listing.len(), 1);
+    }
+
+    #[test]
+    fn removed_names_come_back_as_new_inodes() {
+        let mut table = table();
+        let file = create(&mut table, "project/file");
+        let project = create(&mut table, "project");
+
+        assert_eq!(table.remove(project, OsStr::new("file")), Some(file));
+        assert_eq!(path(&table, file), None);
+        assert_eq!(table.lookup(project, OsStr::new("file")), None);
+        assert_ne!(create(&mut table, "project/file"), file);
+        assert_eq!(table.remove(project, OsStr::new("missing")), None);
+    }
+
+    #[test]
+    fn exchange_swaps_places() {
+        let mut table = table();
+        let a = create(&mut table, "project/a/file");
+        let b = create(&mut table, "other/b");
+        let project = create(&mut table, "project");
+        let other = create(&mut table, "other");
+
+        table.exchange(project, OsStr::new("a"), other, OsStr::new("b"));
+        assert_eq!(path(&table, a).as_deref(), Some("/storage/other/b/file"));
+        assert_eq!(path(&table, b).as_deref(), Some("/storage/project/a"));
+
+        // with nothing on the other side, it is a plain move
+        table.exchange(other, OsStr::new("b"), project, OsStr::new("c"));
+        assert_eq!(path(&table, a).as_deref(), Some("/storage/project/c/file"));
+        assert_eq!(table.lookup(other, OsStr::new("b")), None);
+        assert_eq!(path(&table, b).as_deref(), Some("/storage/project/a"));
+    }
+}
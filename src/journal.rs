@@ -0,0 +1,489 @@
+use log::{error, info, warn};
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// One line of JSON per record in the journal, a write's data follows its line as is
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(tag = "record", rename_all = "lowercase")]
+enum Record {
+    /// Data sitting in a write buffer, path is relative to local_storage
+    Write {
+        handle: u64,
+        path: PathBuf,
+        offset: u64,
+        /// Bytes of data following the line (and a newline)
+        length: usize,
+    },
+    /// Everything written through handle so far reached local_storage
+    Flushed { handle: u64 },
+    /// Pending writes to from (or anything under it) now belong to to, those to whatever
+    /// to replaced are dropped
+    Renamed { from: PathBuf, to: PathBuf },
+    /// Pending writes to either path (or anything under it) now belong to the other
+    Exchanged { first: PathBuf, second: PathBuf },
+    /// Pending writes to path (or anything under it) are dropped
+    Removed { path: PathBuf },
+}
+
+/// A write found in the journal that still has to be applied
+#[derive(Debug)]
+struct PendingWrite {
+    handle: u64,
+    path: PathBuf,
+    offset: u64,
+    data: Vec<u8>,
+}
+
+struct JournalFile {
+    file: File,
+    /// Where every open buffered handle writes to, relative to local_storage (None once the
+    /// file was removed or replaced)
+    paths: FxHashMap<u64, Option<PathBuf>>,
+    /// Handles with writes that have not been flushed yet
+    pending: FxHashSet<u64>,
+    /// Bytes journaled since the last fsync
+    unsynced: usize,
+    /// Fsync once this many bytes were journaled (a write buffer's worth)
+    sync_every: usize,
+}
+
+/// Buffered writes that have not reached local_storage yet, replayed if nimbus crashes.
+/// Fsynced every write buffer's worth, so a power loss loses at most that much on top of
+/// what was never fsynced by the application.
+#[derive(Clone)]
+pub struct Journal {
+    journal: Arc<Mutex<JournalFile>>,
+}
+
+// Where path ends up once from was renamed to to (None if it is unaffected)
+fn moved(path: &Path, from: &Path, to: &Path) -> Option<PathBuf> {
+    path.strip_prefix(from).ok().map(|rest| {
+        if rest.as_os_str().is_empty() {
+            to.to_path_buf()
+        } else {
+            to.join(rest)
+        }
+    })
+}
+
+impl Record {
+    // Where a file at path ends up after this rename or removal (None if it is gone)
+    fn apply(&self, path: &Path) -> Option<PathBuf> {
+        match self {
+            Record::Renamed { from, to } => match moved(path, from, to) {
+                Some(path) => Some(path),
+                None if path.starts_with(to) => None,
+                None => Some(path.to_path_buf()),
+            },
+            Record::Exchanged { first, second } => Some(
+                moved(path, first, second)
+                    .or_else(|| moved(path, second, first))
+                    .unwrap_or_else(|| path.to_path_buf()),
+            ),
+            Record::Removed { path: removed } if path.starts_with(removed) => None,
+            _ => Some(path.to_path_buf()),
+        }
+    }
+}
+
+// Sits next to local_storage rather than inside it, where only projects live
+pub fn default_journal_path(local_storage: &Path) -> PathBuf {
+    let mut name = local_storage.file_name().unwrap_or_default().to_os_string();
+    name.push(".journal");
+    local_storage.with_file_name(name)
+}
+
+impl Journal {
+    // Starts out empty, so replay whatever a previous run left behind first
+    pub fn open(path: &Path, sync_every: usize) -> io::Result<Journal> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.set_len(0)?;
+        Ok(Journal {
+            journal: Arc::new(Mutex::new(JournalFile {
+                file,
+                paths: FxHashMap::default(),
+                pending: FxHashSet::default(),
+                unsynced: 0,
+                sync_every,
+            })),
+        })
+    }
+
+    fn append(journal: &mut JournalFile, record: &Record, data: Option<&[u8]>) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        if let Some(data) = data {
+            line.extend_from_slice(data);
+            line.push(b'\n');
+        }
+        journal.file.write_all(&line)?;
+        journal.unsynced += line.len();
+        if journal.unsynced >= journal.sync_every {
+            journal.file.sync_data()?;
+            journal.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    // A buffered handle on path (relative to local_storage) was opened
+    pub fn record_open(&self, handle: u64, path: PathBuf) {
+        let mut journal = self.journal.lock().expect("lock failed");
+        journal.paths.insert(handle, Some(path));
+    }
+
+    // Call once the handle was flushed for the last time
+    pub fn record_close(&self, handle: u64) {
+        self.record_flush(handle);
+        let mut journal = self.journal.lock().expect("lock failed");
+        journal.paths.remove(&handle);
+    }
+
+    // Writes to a file that was removed since the handle was opened are lost anyway
+    pub fn record_write(&self, handle: u64, offset: u64, data: &[u8]) {
+        let mut journal = self.journal.lock().expect("lock failed");
+        let path = match journal.paths.get(&handle) {
+            Some(Some(path)) => path.clone(),
+            _ => return,
+        };
+        let record = Record::Write {
+            handle,
+            path,
+            offset,
+            length: data.len(),
+        };
+        match Journal::append(&mut journal, &record, Some(data)) {
+            Ok(()) => {
+                journal.pending.insert(handle);
+            }
+            Err(err) => error!("unable to journal write to handle {}: {:?}", handle, err),
+        }
+    }
+
+    pub fn record_flush(&self, handle: u64) {
+        let mut journal = self.journal.lock().expect("lock failed");
+        if !journal.pending.remove(&handle) {
+            return;
+        }
+        // nothing left to replay, start over instead of growing forever
+        let result = if journal.pending.is_empty() {
+            journal.unsynced = 0;
+            journal.file.set_len(0)
+        } else {
+            Journal::append(&mut journal, &Record::Flushed { handle }, None)
+        };
+        if let Err(err) = result {
+            error!("unable to journal flush of handle {}: {:?}", handle, err);
+        }
+    }
+
+    // Renames and removals decide where pending writes land, so they are fsynced right away
+    fn record_move(&self, record: Record) {
+        let mut journal = self.journal.lock().expect("lock failed");
+        for path in journal.paths.values_mut() {
+            if let Some(current) = path {
+                *path = record.apply(current);
+            }
+        }
+        if journal.pending.is_empty() {
+            return;
+        }
+        let result = Journal::append(&mut journal, &record, None).and_then(|_| {
+            journal.unsynced = 0;
+            journal.file.sync_data()
+        });
+        if let Err(err) = result {
+            error!("unable to journal {:?}: {:?}", record, err);
+        }
+    }
+
+    // Paths are relative to local_storage
+    pub fn record_rename(&self, from: PathBuf, to: PathBuf) {
+        self.record_move(Record::Renamed { from, to });
+    }
+
+    pub fn record_exchange(&self, first: PathBuf, second: PathBuf) {
+        self.record_move(Record::Exchanged { first, second });
+    }
+
+    pub fn record_remove(&self, path: PathBuf) {
+        self.record_move(Record::Removed { path });
+    }
+
+    // Every handle was flushed (on shutdown)
+    pub fn clear(&self) -> io::Result<()> {
+        let mut journal = self.journal.lock().expect("lock failed");
+        journal.pending.clear();
+        journal.unsynced = 0;
+        journal.file.set_len(0)
+    }
+}
+
+// The writes still pending at the end of the journal, wherever they belong by then
+fn pending_writes(mut journal: impl BufRead) -> io::Result<Vec<PendingWrite>> {
+    let mut writes: Vec<PendingWrite> = Vec::new();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if journal.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        let record = match serde_json::from_slice(&line) {
+            Ok(record) => record,
+            // nimbus died halfway through the last record
+            Err(err) => {
+                warn!("skipping corrupt journal record: {}", err);
+                break;
+            }
+        };
+        match record {
+            Record::Write {
+                handle,
+                path,
+                offset,
+                length,
+            } => {
+                let mut data = vec![0; length + 1];
+                match journal.read_exact(&mut data) {
+                    Ok(()) => {
+                        data.truncate(length);
+                        writes.push(PendingWrite {
+                            handle,
+                            path,
+                            offset,
+                            data,
+                        });
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                        warn!("skipping truncated journal write to {:?}", path);
+                        break;
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            Record::Flushed { handle } => writes.retain(|write| write.handle != handle),
+            record => {
+                writes = writes
+                    .into_iter()
+                    .filter_map(|write| {
+                        record
+                            .apply(&write.path)
+                            .map(|path| PendingWrite { path, ..write })
+                    })
+                    .collect()
+            }
+        }
+    }
+    Ok(writes)
+}
+
+// Applies writes a crashed run never flushed, then removes the journal; returns how many
+pub fn replay(path: &Path, local_storage: &Path) -> io::Result<usize> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+
+    let mut replayed = 0;
+    for write in pending_writes(BufReader::new(file))? {
+        let target = local_storage.join(&write.path);
+        let result = OpenOptions::new()
+            .write(true)
+            .open(&target)
+            .and_then(|file| {
+                file.write_all_at(&write.data, write.offset)
+                    .and_then(|_| file.sync_all())
+            });
+        match result {
+            Ok(()) => replayed += 1,
+            Err(err) => error!("unable to replay write to {:?}: {:?}", target, err),
+        }
+    }
+    if replayed > 0 {
+        info!("replayed {} unflushed writes from {:?}", replayed, path);
+    }
+    fs::remove_file(path)?;
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// local_storage with a journal next to it, as left behind by a crash
+    struct Crashed {
+        dir: tempfile::TempDir,
+        journal: Journal,
+    }
+
+    impl Crashed {
+        fn new(files: &[&str]) -> Crashed {
+            let dir = tempfile::tempdir().unwrap();
+            fs::create_dir_all(dir.path().join("storage/project")).unwrap();
+            for file in files {
+                fs::write(dir.path().join("storage/project").join(file), "........").unwrap();
+            }
+            let journal = Journal::open(&dir.path().join("storage.journal"), 1024).unwrap();
+            Crashed { dir, journal }
+        }
+
+        fn open(&self, handle: u64, file: &str) {
+            self.journal
+                .record_open(handle, Path::new("project").join(file));
+        }
+
+        fn replay(&self) -> usize {
+            replay(
+                &self.dir.path().join("storage.journal"),
+                &self.dir.path().join("storage"),
+            )
+            .unwrap()
+        }
+
+        fn read(&self, file: &str) -> String {
+            fs::read_to_string(self.dir.path().join("storage/project").join(file)).unwrap()
+        }
+    }
+
+    #[test]
+    fn replays_unflushed_writes() {
+        let crashed = Crashed::new(&["a"]);
+        crashed.open(1, "a");
+        crashed.journal.record_write(1, 2, b"xy");
+        crashed.journal.record_write(1, 4, b"\nz");
+
+        assert_eq!(crashed.replay(), 2);
+        assert_eq!(crashed.read("a"), "..xy\nz..");
+        assert!(!crashed.dir.path().join("storage.journal").exists());
+    }
+
+    #[test]
+    fn skips_flushed_writes() {
+        let crashed = Crashed::new(&["a", "b"]);
+        crashed.open(1, "a");
+        crashed.open(2, "b");
+        crashed.journal.record_write(1, 0, b"aa");
+        crashed.journal.record_write(2, 0, b"bb");
+        crashed.journal.record_flush(1);
+        crashed.journal.record_write(1, 4, b"aa");
+
+        assert_eq!(crashed.replay(), 2);
+        assert_eq!(crashed.read("a"), "....aa..");
+        assert_eq!(crashed.read("b"), "bb......");
+    }
+
+    #[test]
+    fn follows_renamed_files() {
+        let crashed = Crashed::new(&["a", "b"]);
+        crashed.open(1, "a");
+        crashed.open(2, "b");
+        crashed.journal.record_write(1, 0, b"aa");
+        crashed.journal.record_write(2, 0, b"bb");
+        fs::rename(
+            crashed.dir.path().join("storage/project/a"),
+            crashed.dir.path().join("storage/project/c"),
+        )
+        .unwrap();
+        crashed
+            .journal
+            .record_rename("project/a".into(), "project/c".into());
+        // still open, so later writes land there too
+        crashed.journal.record_write(1, 2, b"cc");
+
+        assert_eq!(crashed.replay(), 3);
+        assert_eq!(crashed.read("b"), "bb......");
+        assert_eq!(crashed.read("c"), "aacc....");
+        assert!(!crashed.dir.path().join("storage/project/a").exists());
+    }
+
+    #[test]
+    fn follows_renamed_directories_and_exchanges() {
+        let crashed = Crashed::new(&["a", "b"]);
+        crashed.open(1, "a");
+        crashed.open(2, "b");
+        crashed.journal.record_write(1, 0, b"aa");
+        crashed.journal.record_write(2, 0, b"bb");
+        crashed
+            .journal
+            .record_exchange("project/a".into(), "project/b".into());
+        let storage = crashed.dir.path().join("storage");
+        fs::rename(storage.join("project"), storage.join("moved")).unwrap();
+        crashed
+            .journal
+            .record_rename("project".into(), "moved".into());
+        fs::create_dir(storage.join("project")).unwrap();
+        fs::write(storage.join("project/a"), "........").unwrap();
+
+        assert_eq!(crashed.replay(), 2);
+        assert_eq!(
+            fs::read_to_string(storage.join("moved/a")).unwrap(),
+            "bb......"
+        );
+        assert_eq!(
+            fs::read_to_string(storage.join("moved/b")).unwrap(),
+            "aa......"
+        );
+        assert_eq!(crashed.read("a"), "........");
+    }
+
+    #[test]
+    fn drops_writes_to_removed_or_replaced_files() {
+        let crashed = Crashed::new(&["a", "b"]);
+        crashed.open(1, "a");
+        crashed.open(2, "b");
+        crashed.journal.record_write(1, 0, b"aa");
+        crashed.journal.record_write(2, 0, b"bb");
+        crashed.journal.record_remove("project/a".into());
+        crashed
+            .journal
+            .record_rename("project/c".into(), "project/b".into());
+        // the handles now point at files that are gone
+        crashed.journal.record_write(1, 2, b"aa");
+        crashed.journal.record_write(2, 2, b"bb");
+
+        // what took their place must not be touched
+        assert_eq!(crashed.replay(), 0);
+        assert_eq!(crashed.read("a"), "........");
+        assert_eq!(crashed.read("b"), "........");
+    }
+
+    #[test]
+    fn stops_at_a_truncated_record() {
+        let crashed = Crashed::new(&["a"]);
+        crashed.open(1, "a");
+        crashed.journal.record_write(1, 0, b"aa");
+        crashed.journal.record_write(1, 2, b"bbbb");
+        let path = crashed.dir.path().join("storage.journal");
+        let length = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(length - 3)
+            .unwrap();
+
+        assert_eq!(crashed.replay(), 1);
+        assert_eq!(crashed.read("a"), "aa......");
+    }
+
+    #[test]
+    fn starts_over_once_everything_was_flushed() {
+        let crashed = Crashed::new(&["a"]);
+        crashed.open(1, "a");
+        crashed.journal.record_write(1, 0, b"aa");
+        crashed.journal.record_close(1);
+        let path = crashed.dir.path().join("storage.journal");
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+
+        // nothing is pending, so renames don't need journaling either
+        crashed
+            .journal
+            .record_rename("project/a".into(), "project/b".into());
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+    }
+}
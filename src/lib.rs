@@ -8,11 +8,27 @@
 #![feature(const_trait_impl)]
 #![feature(const_convert)]
 
+pub mod auth;
+pub mod client;
 pub mod config;
+pub mod control;
 pub mod convert;
+pub mod discovery;
+pub mod error;
 pub mod file_handler;
 pub mod files;
 pub mod fuse;
+pub mod health;
 pub mod index;
+pub mod inode_table;
+pub mod journal;
 pub mod macros;
+pub mod quorum;
+pub mod recovery;
+pub mod reload;
 pub mod server;
+pub mod shutdown;
+pub mod status;
+pub mod sync;
+pub mod tls;
+pub mod tracker;
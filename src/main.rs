@@ -1,26 +1,141 @@
-use log::{info, trace};
-use std::path::PathBuf;
-use std::str::FromStr;
-use std::sync::{Arc, Barrier};
+use log::{error, info, trace, warn};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::Arc;
 use structopt::StructOpt;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{oneshot, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
 
-use fuser::{BackgroundSession, MountOption, Session};
+use fuser::{BackgroundSession, Session};
 
-use nimbus::config::read_config;
+use nimbus::auth::peer_secrets;
+use nimbus::config::{effective_config, read_config, Config, DiscoveryConfig};
+use nimbus::control::{self, default_socket_path, Control, ControlRequest, ControlResponse};
+use nimbus::convert::parse_mount_option;
+use nimbus::discovery::Discovery;
 use nimbus::files::NimbusFS;
+use nimbus::health::Prober;
+use nimbus::index::default_state_path;
+use nimbus::journal::{self, default_journal_path};
+use nimbus::quorum::Quorum;
+use nimbus::recovery::{is_stale_mount, lazy_unmount, recover_index};
+use nimbus::reload::Reloader;
 use nimbus::server;
+use nimbus::shutdown::{Shutdown, SHUTDOWN_TIMEOUT, UNMOUNT_TIMEOUT};
+use nimbus::sync::Syncer;
+use nimbus::tracker::GracePeriods;
 
 #[derive(StructOpt, Debug)]
-#[structopt(name = "nimbus")]
-struct Opt {
+struct MountOpt {
+    /// Where to mount nimbus (overrides mount.directory)
     #[structopt(short, long)]
-    mount_directory: PathBuf,
+    mount_directory: Option<PathBuf>,
 
+    /// Where the projects are stored (overrides storage.path)
     #[structopt(short, long)]
-    local_storage: PathBuf,
+    local_storage: Option<PathBuf>,
 
     #[structopt(short, long)]
     config: PathBuf,
+
+    /// Mount option as passed to `mount -o`, repeatable (replaces mount.options)
+    #[structopt(short = "o", long = "option")]
+    options: Vec<String>,
+
+    /// Mount without DefaultPermissions, leaving permission checks (access, open, create) to nimbus
+    #[structopt(long)]
+    no_default_permissions: bool,
+
+    /// Lazily unmount a stale mount left behind by a crashed nimbus without asking
+    #[structopt(long)]
+    unmount_stale: bool,
+}
+
+// How to reach the running daemon (a doc comment here would replace the subcommands' help)
+#[derive(StructOpt, Debug)]
+struct ControlOpt {
+    /// Control socket of the daemon
+    #[structopt(short, long)]
+    socket: Option<PathBuf>,
+
+    /// Config of the daemon (used to find machine.control_socket)
+    #[structopt(short, long)]
+    config: Option<PathBuf>,
+
+    /// Local storage of the daemon (the socket defaults to living next to it)
+    #[structopt(short, long)]
+    local_storage: Option<PathBuf>,
+}
+
+#[derive(StructOpt, Debug)]
+enum ConfigOpt {
+    /// Validate a config and print it with every default filled in
+    Check {
+        #[structopt(short, long)]
+        config: PathBuf,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "nimbus")]
+enum Opt {
+    /// Mount nimbus and run the daemon
+    Mount(MountOpt),
+    /// Show locks, references and sync state of every project
+    Status(ControlOpt),
+    /// Take the lock on a project
+    Lock {
+        project: String,
+        #[structopt(flatten)]
+        control: ControlOpt,
+    },
+    /// Take the lock on a project from a peer that is down
+    Steal {
+        project: String,
+        /// Don't ask before stealing from an unreachable peer
+        #[structopt(short, long)]
+        yes: bool,
+        #[structopt(flatten)]
+        control: ControlOpt,
+    },
+    /// Release the lock on a project without waiting for its grace period
+    Release {
+        project: String,
+        #[structopt(flatten)]
+        control: ControlOpt,
+    },
+    /// Keep a project (and its lock) around even when nobody uses it
+    Pin {
+        project: String,
+        #[structopt(flatten)]
+        control: ControlOpt,
+    },
+    /// Let a pinned project go idle again
+    Unpin {
+        project: String,
+        #[structopt(flatten)]
+        control: ControlOpt,
+    },
+    /// Push a project to every peer, or pull it from one
+    Sync {
+        project: String,
+        /// Peer to pull the project from instead
+        #[structopt(long)]
+        from: Option<String>,
+        #[structopt(flatten)]
+        control: ControlOpt,
+    },
+    /// Show whether every peer is up, and how long it takes to answer
+    Peers(ControlOpt),
+    /// Re-read the config of the daemon without unmounting
+    Reload(ControlOpt),
+    /// Unmount and stop the daemon
+    Unmount(ControlOpt),
+    /// Work with config files
+    Config(ConfigOpt),
 }
 
 #[tokio::main]
@@ -29,45 +144,465 @@ async fn main() {
     let args = Opt::from_args();
     info!("Args parsed");
 
-    let config = read_config(args.config);
+    let (control, request) = match args {
+        Opt::Mount(args) => return mount(args).await,
+        Opt::Config(ConfigOpt::Check { config }) => exit(check_config(config)),
+        Opt::Status(control) => (control, ControlRequest::Status),
+        Opt::Lock { project, control } => (control, ControlRequest::Lock { project }),
+        Opt::Steal {
+            project,
+            yes,
+            control,
+        } => (
+            control,
+            ControlRequest::Steal {
+                project,
+                confirmed: yes,
+            },
+        ),
+        Opt::Peers(control) => (control, ControlRequest::Peers),
+        Opt::Release { project, control } => (control, ControlRequest::Release { project }),
+        Opt::Pin { project, control } => (control, ControlRequest::Pin { project }),
+        Opt::Unpin { project, control } => (control, ControlRequest::Unpin { project }),
+        Opt::Sync {
+            project,
+            from,
+            control,
+        } => (control, ControlRequest::Sync { project, from }),
+        Opt::Reload(control) => (control, ControlRequest::Reload),
+        Opt::Unmount(control) => (control, ControlRequest::Unmount),
+    };
+    exit(send(control, request));
+}
+
+// Returns the exit code
+fn check_config(path: PathBuf) -> i32 {
+    match read_config(path).and_then(|config| effective_config(&config)) {
+        Ok(config) => {
+            print!("{}", config);
+            0
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
+
+fn control_socket(opt: ControlOpt) -> Option<PathBuf> {
+    if opt.socket.is_some() {
+        return opt.socket;
+    }
+    let mut local_storage = opt.local_storage;
+    if let Some(config) = opt.config {
+        match read_config(config) {
+            Ok(config) if config.machine.control_socket.is_some() => {
+                return config.machine.control_socket
+            }
+            Ok(config) => local_storage = local_storage.or(config.storage.path),
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+    let local_storage = std::fs::canonicalize(local_storage?).ok()?;
+    Some(default_socket_path(&local_storage))
+}
+
+// Returns the exit code
+fn send(opt: ControlOpt, request: ControlRequest) -> i32 {
+    let socket = match control_socket(opt) {
+        Some(socket) => socket,
+        None => {
+            eprintln!(
+                "unable to find the control socket, pass --socket, --config or --local-storage"
+            );
+            return 2;
+        }
+    };
+    send_to(&socket, request)
+}
+
+fn send_to(socket: &Path, request: ControlRequest) -> i32 {
+    match control::request(socket, &request) {
+        Ok(ControlResponse::Status(projects)) => {
+            println!(
+                "{:<24} {:<24} {:>5} {:>6} {:>7}  sync",
+                "project", "lock", "refs", "pinned", "handles"
+            );
+            for project in projects {
+                println!(
+                    "{:<24} {:<24} {:>5} {:>6} {:>7}  {:?}",
+                    project.name,
+                    format!("{:?}", project.lock),
+                    project.refs,
+                    project.pinned,
+                    project.open_handles,
+                    project.sync
+                );
+            }
+            0
+        }
+        Ok(ControlResponse::Lock(lock)) => {
+            println!(
+                "locked by {} (epoch {})",
+                lock.holder.unwrap_or_default(),
+                lock.epoch
+            );
+            0
+        }
+        Ok(ControlResponse::Peers(peers)) => {
+            println!(
+                "{:<24} {:<8} {:>8}  {:<26} error",
+                "peer", "state", "latency", "last seen"
+            );
+            for peer in peers {
+                println!(
+                    "{:<24} {:<8} {:>8}  {:<26} {}",
+                    peer.name,
+                    format!("{:?}", peer.reachability),
+                    peer.latency_ms
+                        .map(|latency| format!("{}ms", latency))
+                        .unwrap_or_default(),
+                    peer.last_seen
+                        .map(|last_seen| last_seen.to_rfc3339())
+                        .unwrap_or_default(),
+                    peer.error.unwrap_or_default()
+                );
+            }
+            0
+        }
+        Ok(ControlResponse::Confirm(question)) => match request {
+            ControlRequest::Steal { project, .. }
+                if nix::unistd::isatty(0).unwrap_or(false) && confirm(&question) =>
+            {
+                let confirmed = ControlRequest::Steal {
+                    project,
+                    confirmed: true,
+                };
+                send_to(socket, confirmed)
+            }
+            _ => {
+                eprintln!("{} (pass --yes to go ahead)", question);
+                1
+            }
+        },
+        Ok(ControlResponse::Done(message)) => {
+            println!("{}", message);
+            0
+        }
+        Ok(ControlResponse::Error(message)) => {
+            eprintln!("{}", message);
+            1
+        }
+        Err(err) => {
+            eprintln!("unable to reach nimbus at {:?}: {}", socket, err);
+            2
+        }
+    }
+}
+
+// A crashed nimbus leaves a dead mount behind, which has to go before mounting again
+fn clear_stale_mount(mount_directory: &Path, unmount_stale: bool) -> bool {
+    if !is_stale_mount(mount_directory) {
+        return true;
+    }
+    eprintln!(
+        "{:?} is a stale mount left behind by a nimbus that is no longer running",
+        mount_directory
+    );
+    let confirmed =
+        unmount_stale || (nix::unistd::isatty(0).unwrap_or(false) && confirm("unmount it lazily?"));
+    if !confirmed {
+        eprintln!("pass --unmount-stale to unmount it");
+        return false;
+    }
+    match lazy_unmount(mount_directory) {
+        Ok(()) => {
+            info!("lazily unmounted stale mount at {:?}", mount_directory);
+            true
+        }
+        Err(err) => {
+            eprintln!("unable to unmount {:?}: {}", mount_directory, err);
+            false
+        }
+    }
+}
+
+fn confirm(question: &str) -> bool {
+    eprint!("{} [y/N] ", question);
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer).is_ok()
+        && matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+// Flags given on the command line win over the config
+fn apply_overrides(config: &mut Config, args: &MountOpt) {
+    if let Some(local_storage) = &args.local_storage {
+        config.storage.path = Some(local_storage.clone());
+    }
+    if let Some(mount_directory) = &args.mount_directory {
+        config.mount.directory = Some(mount_directory.clone());
+    }
+    if !args.options.is_empty() {
+        config.mount.options = args.options.clone();
+    }
+    if args.no_default_permissions {
+        config
+            .mount
+            .options
+            .retain(|option| option != "default_permissions");
+    }
+}
+
+async fn mount(args: MountOpt) {
+    let mut config = match read_config(args.config.clone()) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(1);
+        }
+    };
+    apply_overrides(&mut config, &args);
     info!("{:?}", config);
+    let (local_storage, mount_directory) =
+        match (config.storage.path.clone(), config.mount.directory.clone()) {
+            (Some(local_storage), Some(mount_directory)) => (local_storage, mount_directory),
+            (None, _) => {
+                eprintln!("no local storage, pass --local-storage or set storage.path");
+                exit(2);
+            }
+            (_, None) => {
+                eprintln!("no mount directory, pass --mount-directory or set mount.directory");
+                exit(2);
+            }
+        };
 
-    let nimbus = NimbusFS::default(args.local_storage, args.mount_directory.clone());
+    // Recover from a crashed run before mounting
+    if !clear_stale_mount(&mount_directory, args.unmount_stale) {
+        exit(1);
+    }
+    let local_storage = std::fs::canonicalize(&local_storage).expect("Unable to canonicalize link");
+    if let Err(err) = journal::replay(&default_journal_path(&local_storage), &local_storage) {
+        eprintln!("unable to replay the journal of the previous run: {}", err);
+        exit(1);
+    }
 
-    // Listen for interrupt
-    let interrupt = Arc::new(Barrier::new(2));
-    let c = Arc::clone(&interrupt);
-    ctrlc::set_handler(move || {
-        c.wait();
-        trace!("Ctrl-C recieved, forwarding to main thread!");
-    })
-    .expect("Error setting Ctrl-C handler");
+    let mut nimbus = match NimbusFS::new(local_storage, mount_directory.clone(), &config.tuning) {
+        Ok(nimbus) => nimbus,
+        Err(err) => {
+            eprintln!("unable to start nimbus: {}", err);
+            exit(1);
+        }
+    };
+    nimbus.set_default_permissions(
+        config
+            .mount
+            .options
+            .iter()
+            .any(|option| option == "default_permissions"),
+    );
+    nimbus
+        .tracker()
+        .set_grace_periods(GracePeriods::from_config(&config));
+    recover_index(
+        &nimbus.index(),
+        &default_state_path(&nimbus.local_storage()),
+        &config,
+    )
+    .await;
+
+    // Listen for interrupt and termination (or `nimbus unmount`)
+    let shutdown = Arc::new(Notify::new());
+    let c = Arc::clone(&shutdown);
+    tokio::spawn(async move {
+        let mut terminate = signal(SignalKind::terminate()).expect("Error setting SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => trace!("Ctrl-C recieved, forwarding to main thread!"),
+            _ = terminate.recv() => trace!("SIGTERM recieved, forwarding to main thread!"),
+        }
+        c.notify_one();
+    });
 
     // Setup server
-    let server = server::build(nimbus.index(), config.machine.endpoint.clone());
+    let (stop_server, server_stopped) = oneshot::channel();
+    let syncer = Syncer::new(&config, nimbus.local_storage(), nimbus.activity());
+    let secrets = peer_secrets(&config);
+    let prober = Prober::new(syncer.clone(), nimbus.tuning());
+    let quorum = Quorum::new(
+        config.machine.name.clone(),
+        nimbus.index(),
+        syncer.clone(),
+        nimbus.tuning(),
+    );
+    nimbus.tracker().set_quorum(quorum.clone());
+    let routes = server::routes(
+        nimbus.index(),
+        nimbus.status(),
+        prober.clone(),
+        secrets.clone(),
+        syncer.clone(),
+    );
+    let server = server::build(
+        routes,
+        config.machine.endpoint.clone(),
+        config.machine.tls.clone(),
+        async {
+            let _ = server_stopped.await;
+        },
+    );
+
+    // Reload the config on hangup (or `nimbus reload`)
+    let reloader = Reloader::new(
+        args.config.clone(),
+        config.clone(),
+        nimbus.index(),
+        nimbus.tracker(),
+        nimbus.tuning(),
+        secrets,
+        syncer.clone(),
+        quorum.clone(),
+    );
+    if let Some(discovery) = &config.discovery {
+        spawn_discovery(discovery, &config, nimbus.local_storage(), reloader.clone());
+    }
+    let r = reloader.clone();
+    tokio::spawn(async move {
+        let mut hangup = signal(SignalKind::hangup()).expect("Error setting SIGHUP handler");
+        while hangup.recv().await.is_some() {
+            trace!("SIGHUP recieved, reloading config!");
+            if let Err(err) = r.reload() {
+                error!("keeping the current config: {}", err);
+            }
+        }
+    });
+
+    // Setup control socket
+    let control_socket = config
+        .machine
+        .control_socket
+        .clone()
+        .unwrap_or_else(|| default_socket_path(&nimbus.local_storage()));
+    let control = Control::new(
+        quorum.clone(),
+        nimbus.index(),
+        nimbus.tracker(),
+        nimbus.status(),
+        syncer.clone(),
+        reloader,
+        prober.clone(),
+        Arc::clone(&shutdown),
+    );
+
+    // Setup what gets wound down before unmounting
+    let wind_down = Shutdown::new(
+        syncer,
+        nimbus.draining(),
+        nimbus.file_handlers(),
+        nimbus.journal(),
+        nimbus.index(),
+        default_state_path(&nimbus.local_storage()),
+        stop_server,
+    );
 
     // Setup fuse session
-    let session = Session::new(
-        nimbus,
-        &args.mount_directory,
-        &[
-            MountOption::DefaultPermissions,
-            MountOption::DirSync,
-            MountOption::Sync,
-            MountOption::NoAtime,
-        ], // MountOption::AutoUnmount,
-    )
-    .expect("Could not create session");
+    let options: Vec<_> = config
+        .mount
+        .options
+        .iter()
+        .map(|option| parse_mount_option(option))
+        .collect();
+    let session = match Session::new(nimbus, &mount_directory, &options) {
+        Ok(session) => session,
+        Err(err) => {
+            eprintln!("unable to mount at {:?}: {}", mount_directory, err);
+            exit(1);
+        }
+    };
 
     // Spawn stuff
-    tokio::spawn(async move { server.await });
+    let server = tokio::spawn(server);
+    let socket = control_socket.clone();
+    let control = tokio::spawn(async move {
+        if let Err(err) = control::serve(control, socket).await {
+            error!("control socket failed: {:?}", err);
+        }
+    });
+    tokio::spawn(prober.run());
+    tokio::spawn(quorum.run());
     let bg = session.spawn().expect("Session failed to spawn");
-    cleanup_mount(interrupt, bg).await;
+    cleanup_mount(shutdown, wind_down, server, control, control_socket, bg).await;
 }
 
-async fn cleanup_mount(interrupt: Arc<Barrier>, bg: BackgroundSession) {
-    interrupt.wait();
-    info!("Ctrl-C recieved, gracefully exiting!");
-    bg.join();
-    info!("Cleanup successful, exit complete!");
+// Discovered peers are merged into the config in effect, like a reload would
+fn spawn_discovery(
+    discovery: &DiscoveryConfig,
+    config: &Config,
+    local_storage: PathBuf,
+    reloader: Reloader,
+) {
+    let discovery = match Discovery::new(discovery, &config.machine, local_storage) {
+        Ok(discovery) => discovery,
+        Err(err) => {
+            error!("unable to start discovery, only using [network]: {:?}", err);
+            return;
+        }
+    };
+    let mut discovered = discovery.subscribe();
+    tokio::spawn(async move {
+        if let Err(err) = discovery.run().await {
+            error!("discovery failed: {:?}", err);
+        }
+    });
+    tokio::spawn(async move {
+        while discovered.changed().await.is_ok() {
+            let peers = discovered.borrow().clone();
+            reloader.set_discovered(peers);
+        }
+    });
+}
+
+async fn cleanup_mount(
+    shutdown: Arc<Notify>,
+    mut wind_down: Shutdown,
+    server: JoinHandle<()>,
+    control: JoinHandle<()>,
+    control_socket: PathBuf,
+    bg: BackgroundSession,
+) {
+    shutdown.notified().await;
+    info!("Shutdown requested, gracefully exiting!");
+    let graceful = async {
+        wind_down.run().await;
+        let _ = server.await;
+    };
+    if timeout(SHUTDOWN_TIMEOUT, graceful).await.is_err() {
+        warn!(
+            "shutdown took longer than {:?}, unmounting anyway",
+            SHUTDOWN_TIMEOUT
+        );
+    }
+    // also stops the server, if winding down timed out before it did
+    drop(wind_down);
+
+    control.abort();
+    if let Err(err) = std::fs::remove_file(&control_socket) {
+        warn!(
+            "unable to remove control socket {:?}: {:?}",
+            control_socket, err
+        );
+    }
+
+    let unmount = tokio::task::spawn_blocking(move || bg.join());
+    match timeout(UNMOUNT_TIMEOUT, unmount).await {
+        Ok(Ok(())) => info!("Cleanup successful, exit complete!"),
+        Ok(Err(err)) => error!("unmounting failed: {:?}", err),
+        Err(_) => {
+            error!(
+                "unmounting took longer than {:?}, is the mount still busy?",
+                UNMOUNT_TIMEOUT
+            );
+            // the runtime would otherwise wait on the blocked unmount forever
+            exit(1);
+        }
+    }
 }
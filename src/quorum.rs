@@ -0,0 +1,477 @@
+use chrono::prelude::*;
+use log::{debug, info, warn};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::client::{LockReply, PeerClient};
+use crate::config::TuningConfig;
+use crate::index::{CanonicalProjectName, Index, LockError, LockResponse, LockStatus::*};
+use crate::sync::Syncer;
+
+/// How often a proposal is retried above the highest epoch seen after losing a race
+const ATTEMPTS: usize = 3;
+
+// How many of the machines (us included) have to agree on who holds a lock
+pub fn majority(machines: usize) -> usize {
+    machines / 2 + 1
+}
+
+// A few renewals fit into every lease
+fn renewal_interval(lease: Duration) -> Duration {
+    lease / 3
+}
+
+/// The answers to one proposal
+#[derive(Default)]
+struct Votes {
+    /// Peers that lent us the lock at the proposed epoch
+    granted: Vec<PeerClient>,
+    /// What the peers that refused think of the lock
+    refused: Vec<LockResponse>,
+}
+
+/// A lock we took through the quorum
+struct Held {
+    epoch: u64,
+    /// When the last renewal a majority agreed to was proposed
+    renewed: Instant,
+}
+
+/// Takes project locks by asking every configured peer, so that with three or more
+/// machines a majority has to agree (and the others may be offline). Every lock comes
+/// with a higher epoch than the last, so a holder that was cut off is refused once it is back.
+#[derive(Clone)]
+pub struct Quorum {
+    machine: String,
+    index: Arc<Mutex<Index>>,
+    /// Knows the peers (as of the last reload or discovery)
+    syncer: Syncer,
+    tuning: Arc<Mutex<TuningConfig>>,
+    /// Who votes besides us: peers join once configured or discovered, but only leave on an
+    /// explicit reload, so a partition (or lost announcements) can't shrink the majority
+    members: Arc<Mutex<BTreeSet<String>>>,
+    held: Arc<Mutex<HashMap<CanonicalProjectName, Held>>>,
+}
+
+impl Quorum {
+    pub fn new(
+        machine: String,
+        index: Arc<Mutex<Index>>,
+        syncer: Syncer,
+        tuning: Arc<Mutex<TuningConfig>>,
+    ) -> Quorum {
+        Quorum {
+            machine,
+            index,
+            members: Arc::new(Mutex::new(syncer.peers().into_iter().collect())),
+            syncer,
+            tuning,
+            held: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Peers seen since the last reload join the vote
+    pub fn join(&self, peers: impl IntoIterator<Item = String>) {
+        let mut members = self.members.lock().expect("lock failed");
+        for peer in peers {
+            if peer != self.machine && members.insert(peer.clone()) {
+                info!("{} votes on locks from now on", peer);
+            }
+        }
+    }
+
+    // Only an explicit reload decides who left the cluster
+    pub fn set_members(&self, peers: impl IntoIterator<Item = String>) {
+        let peers: BTreeSet<String> = peers
+            .into_iter()
+            .filter(|peer| peer != &self.machine)
+            .collect();
+        let mut members = self.members.lock().expect("lock failed");
+        if *members != peers {
+            info!("{:?} vote on locks from now on", peers);
+            *members = peers;
+        }
+    }
+
+    pub fn members(&self) -> Vec<String> {
+        self.members
+            .lock()
+            .expect("lock failed")
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    // Us and every member, reachable or not
+    fn machines(&self) -> usize {
+        self.members.lock().expect("lock failed").len() + 1
+    }
+
+    // With fewer than three machines there is no majority to fall back on, so (as with
+    // asking the other machine) only a peer that answers and refuses stops us
+    fn agreed(&self, votes: &Votes) -> bool {
+        let members = self.members.lock().expect("lock failed");
+        let machines = members.len() + 1;
+        if machines < 3 {
+            votes.refused.is_empty()
+        } else {
+            let granted = votes
+                .granted
+                .iter()
+                .filter(|peer| members.contains(peer.peer()))
+                .count();
+            granted + 1 >= majority(machines)
+        }
+    }
+
+    // A refusal because another machine holds the lock, rather than over the epoch
+    fn held_elsewhere<'a>(&self, votes: &'a Votes) -> Option<&'a LockResponse> {
+        votes.refused.iter().find(|response| {
+            response
+                .holder
+                .as_ref()
+                .map_or(false, |holder| holder != &self.machine)
+        })
+    }
+
+    // Asks every peer at once; those that don't answer in time don't vote
+    async fn propose(&self, peers: &[PeerClient], project: &str, epoch: u64, steal: bool) -> Votes {
+        let timeout = self.tuning.lock().expect("lock failed").probe_timeout();
+        let proposals: Vec<_> = peers
+            .iter()
+            .cloned()
+            .map(|peer| {
+                let project = project.to_string();
+                tokio::spawn(async move {
+                    let reply = tokio::time::timeout(
+                        timeout,
+                        peer.propose_project_lock(&project, epoch, steal),
+                    )
+                    .await;
+                    (peer, reply)
+                })
+            })
+            .collect();
+
+        let mut votes = Votes::default();
+        for proposal in proposals {
+            let (peer, reply) = match proposal.await {
+                Ok(vote) => vote,
+                Err(_) => continue,
+            };
+            match reply {
+                Ok(Ok(LockReply::Granted(_))) => votes.granted.push(peer),
+                Ok(Ok(LockReply::Conflict(response))) => votes.refused.push(response),
+                Ok(Ok(LockReply::UnknownProject)) => {
+                    debug!("{} does not know {}, not voting", peer.peer(), project)
+                }
+                Ok(Err(err)) => debug!("{} did not vote on {}: {:?}", peer.peer(), project, err),
+                Err(_) => debug!("{} did not vote on {} in time", peer.peer(), project),
+            }
+        }
+        votes
+    }
+
+    // Hands the lock back to the peers that lent it to us at epoch, all at once; those
+    // that don't answer in time let it run out with the lease
+    async fn withdraw(&self, peers: &[PeerClient], project: &str, epoch: u64) {
+        let timeout = self.tuning.lock().expect("lock failed").probe_timeout();
+        let returns: Vec<_> = peers
+            .iter()
+            .cloned()
+            .map(|peer| {
+                let project = project.to_string();
+                tokio::spawn(async move {
+                    let reply =
+                        tokio::time::timeout(timeout, peer.release_project_lock(&project, epoch))
+                            .await;
+                    (peer, reply)
+                })
+            })
+            .collect();
+
+        for handed_back in returns {
+            let (peer, reply) = match handed_back.await {
+                Ok(reply) => reply,
+                Err(_) => continue,
+            };
+            match reply {
+                Ok(Ok(_)) => (),
+                Ok(Err(err)) => warn!(
+                    "unable to hand {} back to {}: {:?}",
+                    project,
+                    peer.peer(),
+                    err
+                ),
+                Err(_) => warn!("{} did not take {} back in time", peer.peer(), project),
+            }
+        }
+    }
+
+    // Takes the lock on project once enough machines agree; steal ignores the lease of a
+    // holder that is gone, but not a majority that knows better
+    pub async fn acquire(
+        &self,
+        project: &CanonicalProjectName,
+        steal: bool,
+    ) -> Result<LockResponse, LockError> {
+        let mut epoch = {
+            let mut index = self.index.lock().expect("lock failed");
+            let lock = index
+                .project_lock
+                .get(project)
+                .cloned()
+                .ok_or(LockError::UnknownProject)?;
+            let lease = index.project_lease.entry(project.clone()).or_default();
+            match lock {
+                // renewing, at the epoch we already hold
+                WeHaveLock(_) => lease.epoch,
+                // no point asking around for a lock we know is lent out
+                SomeoneHasLock(_) if !steal && !lease.expired(Utc::now()) => {
+                    return Err(LockError::Held(index.project_lock_response(project)?))
+                }
+                _ => lease.epoch + 1,
+            }
+        };
+
+        let name = project.to_string_lossy();
+        let peers = self.syncer.clients();
+        let mut votes = Votes::default();
+        for _ in 0..ATTEMPTS {
+            votes = self.propose(&peers, &name, epoch, steal).await;
+            if self.agreed(&votes) {
+                let taken = self.index.lock().expect("lock failed").take_project_lock(
+                    project,
+                    self.machine.clone(),
+                    steal,
+                    Some(epoch),
+                );
+                match &taken {
+                    Ok(_) => {
+                        info!(
+                            "took the lock on {:?} at epoch {} ({} peers agreed)",
+                            project,
+                            epoch,
+                            votes.granted.len()
+                        );
+                        self.held.lock().expect("lock failed").insert(
+                            project.clone(),
+                            Held {
+                                epoch,
+                                renewed: Instant::now(),
+                            },
+                        );
+                    }
+                    // somebody got ahead of us while the peers were voting
+                    Err(_) => self.withdraw(&votes.granted, &name, epoch).await,
+                }
+                return taken;
+            }
+            self.withdraw(&votes.granted, &name, epoch).await;
+
+            // only worth another try if we lost on the epoch rather than to a holder
+            let highest = votes.refused.iter().map(|response| response.epoch).max();
+            match highest {
+                Some(highest) if highest >= epoch => epoch = highest + 1,
+                _ => break,
+            }
+        }
+
+        match self.held_elsewhere(&votes) {
+            Some(response) => Err(LockError::Held(response.clone())),
+            None => Err(LockError::NoQuorum {
+                agreed: votes.granted.len() + 1,
+                needed: majority(self.machines()),
+            }),
+        }
+    }
+
+    // Hands the lock on project back to every peer right away, rather than at the next renewal
+    pub async fn release(&self, project: &CanonicalProjectName) {
+        let epoch = match self.held.lock().expect("lock failed").remove(project) {
+            Some(held) => held.epoch,
+            None => return,
+        };
+        self.withdraw(&self.syncer.clients(), &project.to_string_lossy(), epoch)
+            .await;
+        info!("handed {:?} (epoch {}) back to every peer", project, epoch);
+    }
+
+    // Renews every lock we hold with the peers, hands back the ones we let go of, and drops
+    // the ones a majority moved on from (or that can't be renewed before the lease runs out)
+    pub async fn renew(&self) {
+        let held: Vec<(CanonicalProjectName, u64, Instant)> = self
+            .held
+            .lock()
+            .expect("lock failed")
+            .iter()
+            .map(|(project, held)| (project.clone(), held.epoch, held.renewed))
+            .collect();
+        let peers = self.syncer.clients();
+        let lease = self.index.lock().expect("lock failed").lock_lease;
+        for (project, epoch, renewed) in held {
+            let ours = {
+                let index = self.index.lock().expect("lock failed");
+                matches!(index.project_lock.get(&project), Some(WeHaveLock(_)))
+                    && index
+                        .project_lease
+                        .get(&project)
+                        .map_or(false, |lease| lease.epoch == epoch)
+            };
+            if !ours {
+                self.release(&project).await;
+                continue;
+            }
+
+            let started = Instant::now();
+            let votes = self
+                .propose(&peers, &project.to_string_lossy(), epoch, false)
+                .await;
+            if let Some(response) = self.held_elsewhere(&votes) {
+                warn!(
+                    "lost the lock on {:?} to {} (epoch {})",
+                    project,
+                    response.holder.clone().unwrap_or_default(),
+                    response.epoch
+                );
+                self.lose(&project, epoch, Some(response));
+            } else if self.agreed(&votes) {
+                if let Some(held) = self.held.lock().expect("lock failed").get_mut(&project) {
+                    held.renewed = started;
+                }
+            } else if renewed.elapsed() + renewal_interval(lease) >= lease {
+                // the peers' leases run out before we could try again
+                warn!(
+                    "lost the lock on {:?}: no majority renewed it within the lease",
+                    project
+                );
+                self.lose(&project, epoch, None);
+            } else {
+                warn!(
+                    "unable to renew the lock on {:?}: {} of the {} machines needed agreed",
+                    project,
+                    votes.granted.len() + 1,
+                    majority(self.machines())
+                );
+            }
+        }
+    }
+
+    fn lose(&self, project: &CanonicalProjectName, epoch: u64, now: Option<&LockResponse>) {
+        self.held.lock().expect("lock failed").remove(project);
+        self.index
+            .lock()
+            .expect("lock failed")
+            .lose_project_lock(project, epoch, now);
+    }
+
+    pub async fn run(self) {
+        loop {
+            self.renew().await;
+            let lease = self.index.lock().expect("lock failed").lock_lease;
+            tokio::time::sleep(renewal_interval(lease)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::read_config;
+    use rustc_hash::FxHashMap;
+
+    // A quorum for machine a that knows peers (without talking to any of them)
+    fn quorum(peers: &[&str]) -> Quorum {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.toml");
+        let network: String = peers
+            .iter()
+            .map(|peer| {
+                format!(
+                    "[network.{}]\ncommand = \"cp -r {{HERE}} {{THERE}}\"\nendpoint = \"127.0.0.1:1\"\nsecret = \"secret\"\n\n",
+                    peer
+                )
+            })
+            .collect();
+        std::fs::write(
+            &path,
+            format!(
+                "[machine]\nname = \"a\"\nmode = \"DevelopmentMode\"\nendpoint = \"127.0.0.1:0\"\n\n{}",
+                network
+            ),
+        )
+        .unwrap();
+        let config = read_config(path).unwrap();
+        Quorum::new(
+            "a".to_string(),
+            Arc::new(Mutex::new(Index::new())),
+            Syncer::new(
+                &config,
+                dir.path().to_path_buf(),
+                Arc::new(Mutex::new(FxHashMap::default())),
+            ),
+            Arc::new(Mutex::new(TuningConfig::default())),
+        )
+    }
+
+    fn votes(quorum: &Quorum, granted: &[&str], refused: &[&str]) -> Votes {
+        Votes {
+            granted: quorum
+                .syncer
+                .clients()
+                .into_iter()
+                .filter(|peer| granted.contains(&peer.peer()))
+                .collect(),
+            refused: refused
+                .iter()
+                .map(|holder| LockResponse {
+                    holder: Some(holder.to_string()),
+                    epoch: 1,
+                    expires: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn majorities() {
+        let majorities: Vec<usize> = (1..=6).map(majority).collect();
+        assert_eq!(majorities, [1, 2, 2, 3, 3, 4]);
+    }
+
+    #[test]
+    fn two_machines_agree_unless_the_other_one_refuses() {
+        let quorum = quorum(&["b"]);
+        assert!(quorum.agreed(&votes(&quorum, &[], &[])));
+        assert!(quorum.agreed(&votes(&quorum, &["b"], &[])));
+        assert!(!quorum.agreed(&votes(&quorum, &[], &["b"])));
+
+        // and on its own, a machine always agrees with itself
+        let alone = self::quorum(&[]);
+        assert!(alone.agreed(&votes(&alone, &[], &[])));
+    }
+
+    #[test]
+    fn three_or_more_machines_need_a_majority() {
+        let quorum = quorum(&["b", "c"]);
+        assert!(!quorum.agreed(&votes(&quorum, &[], &[])));
+        assert!(quorum.agreed(&votes(&quorum, &["b"], &["c"])));
+
+        let quorum = self::quorum(&["b", "c", "d", "e"]);
+        assert!(!quorum.agreed(&votes(&quorum, &["b"], &[])));
+        assert!(quorum.agreed(&votes(&quorum, &["b", "c"], &["d", "e"])));
+    }
+
+    #[test]
+    fn only_members_vote() {
+        let quorum = quorum(&["b", "c", "d", "e"]);
+        quorum.set_members(["b".to_string(), "c".to_string()]);
+        assert!(!quorum.agreed(&votes(&quorum, &["d", "e"], &[])));
+        assert!(quorum.agreed(&votes(&quorum, &["c"], &[])));
+
+        // back down to two machines, silence is enough again
+        quorum.set_members(["b".to_string()]);
+        assert!(quorum.agreed(&votes(&quorum, &[], &[])));
+    }
+}
@@ -0,0 +1,136 @@
+use chrono::prelude::*;
+use libc::ENOTCONN;
+use log::{info, warn};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+use crate::client::{LockReply, PeerClient};
+use crate::config::Config;
+use crate::index::{CanonicalProjectName, Index, LockStatus::*};
+
+// A FUSE mount whose daemon died answers everything with ENOTCONN
+pub fn is_stale_mount(mount_directory: &Path) -> bool {
+    matches!(fs::metadata(mount_directory), Err(err) if err.raw_os_error() == Some(ENOTCONN))
+}
+
+// Lazily, so it works even while processes are still sitting inside the dead mount
+pub fn lazy_unmount(mount_directory: &Path) -> io::Result<()> {
+    for fusermount in ["fusermount3", "fusermount"] {
+        match Command::new(fusermount)
+            .arg("-u")
+            .arg("-z")
+            .arg(mount_directory)
+            .status()
+        {
+            Ok(status) if status.success() => return Ok(()),
+            Ok(status) => {
+                return Err(io::Error::new(
+                    ErrorKind::Other,
+                    format!("{} failed with {}", fusermount, status),
+                ))
+            }
+            // not installed, try the next one
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Err(io::Error::new(
+        ErrorKind::NotFound,
+        "neither fusermount3 nor fusermount is installed",
+    ))
+}
+
+// Carries epochs (and who borrowed what) over from the index the previous run persisted
+fn restore_index(index: &Arc<Mutex<Index>>, persisted: Index) {
+    let mut index = index.lock().expect("lock failed");
+    for (project, lock) in persisted.project_lock {
+        // the project is gone from local_storage
+        if !index.project_lock.contains_key(&project) {
+            continue;
+        }
+        // nothing is open right after starting, so whatever we held is free again
+        let lock = match lock {
+            WeHaveLock(_) => NobodyHasLock,
+            lock => lock,
+        };
+        index.project_lock.insert(project.clone(), lock);
+        if let Some(lease) = persisted.project_lease.get(&project) {
+            index.project_lease.insert(project, lease.clone());
+        }
+    }
+    index.index_lease.epoch = index.index_lease.epoch.max(persisted.index_lease.epoch);
+}
+
+// Locks may have changed hands while we were down, so ask every peer about every project
+async fn reconcile(index: &Arc<Mutex<Index>>, machine: &str, peers: &[PeerClient]) {
+    let projects: Vec<CanonicalProjectName> = {
+        let index = index.lock().expect("lock failed");
+        index.project_lock.keys().cloned().collect()
+    };
+    for project in projects {
+        let name = project.to_string_lossy();
+        for peer in peers {
+            let response = match peer.project_lock(&name).await {
+                Ok(LockReply::Granted(response)) => response,
+                // the peer does not know the project
+                Ok(_) => continue,
+                Err(err) => {
+                    warn!(
+                        "unable to reconcile {:?} with {}: {:?}",
+                        project,
+                        peer.peer(),
+                        err
+                    );
+                    continue;
+                }
+            };
+            {
+                let mut index = index.lock().expect("lock failed");
+                let lease = index.project_lease.entry(project.clone()).or_default();
+                lease.epoch = lease.epoch.max(response.epoch);
+            }
+            if response.holder.as_deref() == Some(machine) {
+                // lent to us before we went down, and nothing is using it now
+                match peer.release_project_lock(&name, response.epoch).await {
+                    Ok(LockReply::Granted(_)) => {
+                        info!("handed {:?} back to {}", project, peer.peer())
+                    }
+                    Ok(_) => (),
+                    Err(err) => warn!(
+                        "unable to hand {:?} back to {}: {:?}",
+                        project,
+                        peer.peer(),
+                        err
+                    ),
+                }
+            } else if response.holder.as_deref() == Some(peer.peer()) {
+                // the peer holds it itself; treat that as a lease so it can't wedge the project
+                info!("{:?} is locked by {}", project, peer.peer());
+                let mut index = index.lock().expect("lock failed");
+                let duration = index.lock_lease;
+                let lease = index.project_lease.entry(project.clone()).or_default();
+                lease.expires =
+                    Some(Utc::now() + chrono::Duration::from_std(duration).expect("Overflow"));
+                index
+                    .project_lock
+                    .insert(project.clone(), SomeoneHasLock(peer.peer().to_string()));
+            }
+        }
+    }
+}
+
+// Picks up the index persisted at state (if any) and brings it in line with the peers
+pub async fn recover_index(index: &Arc<Mutex<Index>>, state: &Path, config: &Config) {
+    match Index::load(state) {
+        Ok(Some(persisted)) => {
+            info!("restoring index persisted at {:?}", state);
+            restore_index(index, persisted);
+        }
+        Ok(None) => (),
+        Err(err) => warn!("ignoring unreadable index at {:?}: {:?}", state, err),
+    }
+    reconcile(index, &config.machine.name, &PeerClient::all(config)).await;
+}
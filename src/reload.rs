@@ -0,0 +1,455 @@
+use log::{info, warn};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::auth::{set_peer_secrets, PeerSecrets};
+use crate::config::{read_config, Config, MachineConfig, TuningConfig};
+use crate::discovery::DiscoveredPeers;
+use crate::error::Result;
+use crate::index::{CanonicalProjectName, Index};
+use crate::quorum::Quorum;
+use crate::sync::Syncer;
+use crate::tracker::{GracePeriods, ProjectTracker};
+
+/// What a reload changed
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Reload {
+    pub added: Vec<String>,
+    /// Peers that left [network] (or stopped announcing themselves); they are refused from now on
+    pub removed: Vec<String>,
+    /// Peers whose endpoint, secret or TLS settings changed
+    pub changed: Vec<String>,
+    /// Projects whose lock was lent to a peer removed from the config file and is free again
+    /// (the locks of peers that stopped announcing themselves run out with their lease)
+    pub revoked: Vec<CanonicalProjectName>,
+    /// Settings that were changed but only take effect after a restart
+    pub ignored: Vec<&'static str>,
+}
+
+impl fmt::Display for Reload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "reloaded config: added {:?}, removed {:?}, changed {:?}, revoked {:?}",
+            self.added, self.removed, self.changed, self.revoked
+        )?;
+        if !self.ignored.is_empty() {
+            write!(f, ", restart to apply {}", self.ignored.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the config in effect comes from
+struct Sources {
+    /// As read from the file, with the command line overrides applied
+    file: Config,
+    discovered: DiscoveredPeers,
+    /// The two merged
+    config: Config,
+}
+
+/// Re-reads the config (on SIGHUP or `nimbus reload`) and applies it without unmounting,
+/// along with the peers discovery finds
+#[derive(Clone)]
+pub struct Reloader {
+    path: PathBuf,
+    sources: Arc<Mutex<Sources>>,
+    index: Arc<Mutex<Index>>,
+    tracker: ProjectTracker,
+    tuning: Arc<Mutex<TuningConfig>>,
+    secrets: PeerSecrets,
+    syncer: Syncer,
+    quorum: Quorum,
+}
+
+// Parts of the config the running daemon is built around
+fn restart_needed(current: &Config, new: &Config) -> Vec<&'static str> {
+    let mut ignored = Vec::new();
+    if current.machine.name != new.machine.name {
+        ignored.push("machine.name");
+    }
+    if current.machine.mode != new.machine.mode {
+        ignored.push("machine.mode");
+    }
+    if current.machine.endpoint != new.machine.endpoint {
+        ignored.push("machine.endpoint");
+    }
+    if current.machine.control_socket != new.machine.control_socket {
+        ignored.push("machine.control_socket");
+    }
+    if current.machine.tls != new.machine.tls {
+        ignored.push("machine.tls");
+    }
+    if current.discovery != new.discovery {
+        ignored.push("discovery");
+    }
+    ignored
+}
+
+// Statically configured peers win over discovered ones of the same name
+fn merge(file: &Config, discovered: &DiscoveredPeers) -> Config {
+    let mut config = file.clone();
+    for (name, peer) in discovered {
+        if name != &config.machine.name {
+            config
+                .network
+                .entry(name.clone())
+                .or_insert_with(|| peer.clone());
+        }
+    }
+    config
+}
+
+impl Reloader {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        path: PathBuf,
+        config: Config,
+        index: Arc<Mutex<Index>>,
+        tracker: ProjectTracker,
+        tuning: Arc<Mutex<TuningConfig>>,
+        secrets: PeerSecrets,
+        syncer: Syncer,
+        quorum: Quorum,
+    ) -> Reloader {
+        Reloader {
+            path,
+            sources: Arc::new(Mutex::new(Sources {
+                file: config.clone(),
+                discovered: DiscoveredPeers::new(),
+                config,
+            })),
+            index,
+            tracker,
+            tuning,
+            secrets,
+            syncer,
+            quorum,
+        }
+    }
+
+    // A config that fails to read or validate leaves the current one in effect
+    pub fn reload(&self) -> Result<Reload> {
+        let mut file = read_config(self.path.clone())?;
+        let mut sources = self.sources.lock().expect("lock failed");
+
+        // storage and mount only change with the next mount
+        file.storage = sources.file.storage.clone();
+        file.mount = sources.file.mount.clone();
+        let ignored = restart_needed(&sources.file, &file);
+        file.machine = MachineConfig {
+            grace_period: file.machine.grace_period,
+            ..sources.file.machine.clone()
+        };
+        file.discovery = sources.file.discovery.clone();
+        if !ignored.is_empty() {
+            warn!(
+                "{} changed, which only takes effect after a restart",
+                ignored.join(", ")
+            );
+        }
+
+        sources.file = file;
+        let mut reload = self.apply(&mut sources, true);
+        reload.ignored = ignored;
+        self.quorum
+            .set_members(sources.config.network.keys().cloned());
+        info!("{}", reload);
+        Ok(reload)
+    }
+
+    // Replaces the peers found on the local network
+    pub fn set_discovered(&self, discovered: DiscoveredPeers) -> Reload {
+        let mut sources = self.sources.lock().expect("lock failed");
+        sources.discovered = discovered;
+        // a peer may just have missed a few announcements, so its locks run out with their lease
+        let reload = self.apply(&mut sources, false);
+        // peers that stopped announcing themselves still vote, until a reload says otherwise
+        self.quorum.join(sources.config.network.keys().cloned());
+        info!("{}", reload);
+        reload
+    }
+
+    // revoke frees the locks lent to removed peers right away
+    fn apply(&self, sources: &mut Sources, revoke: bool) -> Reload {
+        let config = merge(&sources.file, &sources.discovered);
+        let current = &sources.config;
+        let mut reload = Reload::default();
+        for (name, peer) in &config.network {
+            match current.network.get(name) {
+                None => reload.added.push(name.clone()),
+                Some(previous) if previous != peer => reload.changed.push(name.clone()),
+                Some(_) => (),
+            }
+        }
+        for name in current.network.keys() {
+            if !config.network.contains_key(name) {
+                reload.removed.push(name.clone());
+            }
+        }
+        reload.added.sort();
+        reload.changed.sort();
+        reload.removed.sort();
+
+        // refuse removed peers first, so they can't borrow a lock we are about to revoke
+        set_peer_secrets(&self.secrets, &config);
+        self.syncer.set_peers(&config);
+        {
+            let mut index = self.index.lock().expect("lock failed");
+            index.lock_lease = config.tuning.lock_lease();
+            for peer in reload.removed.iter().filter(|_| revoke) {
+                reload.revoked.extend(index.revoke_locks(peer));
+            }
+        }
+        for project in &reload.revoked {
+            info!("revoked the lock on {:?} lent to a removed peer", project);
+        }
+
+        self.tracker
+            .set_grace_periods(GracePeriods::from_config(&config));
+        self.tracker
+            .set_polling_interval(config.tuning.polling_interval());
+        *self.tuning.lock().expect("lock failed") = config.tuning.clone();
+        sources.config = config;
+        reload
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NetworkMachineConfig;
+    use crate::index::LockStatus::{self, *};
+    use rustc_hash::FxHashMap;
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::sync::RwLock;
+    use std::time::Duration;
+
+    const MACHINE: &str =
+        "[machine]\nname = \"a\"\nmode = \"DevelopmentMode\"\nendpoint = \"127.0.0.1:5000\"\n";
+
+    fn discovered(name: &str, endpoint: &str) -> (String, NetworkMachineConfig) {
+        let peer = NetworkMachineConfig {
+            name: Some(name.to_string()),
+            command: "cp -r {HERE} {THERE}".to_string(),
+            endpoint: endpoint.to_string(),
+            storage: None,
+            secret: Some("secret".to_string()),
+            ca: None,
+            fingerprint: None,
+        };
+        (name.to_string(), peer)
+    }
+
+    /// A reloader for machine a, reading its config from dir
+    struct Fixture {
+        dir: tempfile::TempDir,
+        index: Arc<Mutex<Index>>,
+        quorum: Quorum,
+        reloader: Reloader,
+    }
+
+    impl Fixture {
+        fn new(config: &str) -> Fixture {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("a.toml");
+            std::fs::write(&path, config).unwrap();
+            let config = read_config(path.clone()).unwrap();
+            let mut index = Index::new();
+            index.register_project(project());
+            let index = Arc::new(Mutex::new(index));
+            let syncer = Syncer::new(
+                &config,
+                dir.path().to_path_buf(),
+                Arc::new(Mutex::new(FxHashMap::default())),
+            );
+            let tuning = Arc::new(Mutex::new(config.tuning.clone()));
+            let quorum = Quorum::new(
+                "a".to_string(),
+                index.clone(),
+                syncer.clone(),
+                Arc::clone(&tuning),
+            );
+            let tracker = ProjectTracker::spawn(
+                dir.path().join("mount"),
+                index.clone(),
+                Arc::new(Mutex::new(FxHashMap::default())),
+                Duration::from_secs(60),
+            );
+            let reloader = Reloader::new(
+                path,
+                config,
+                index.clone(),
+                tracker,
+                tuning,
+                Arc::new(RwLock::new(HashMap::new())),
+                syncer,
+                quorum.clone(),
+            );
+            Fixture {
+                dir,
+                index,
+                quorum,
+                reloader,
+            }
+        }
+
+        fn rewrite(&self, config: &str) -> Reload {
+            std::fs::write(self.dir.path().join("a.toml"), config).unwrap();
+            self.reloader.reload().unwrap()
+        }
+
+        fn lend(&self, peer: &str) {
+            self.index
+                .lock()
+                .unwrap()
+                .lend_project_lock(&project(), peer.to_string(), Some(1), false)
+                .unwrap();
+        }
+
+        fn holder(&self) -> LockStatus {
+            self.index.lock().unwrap().project_lock[&project()].clone()
+        }
+    }
+
+    fn project() -> CanonicalProjectName {
+        Path::new("project").to_path_buf()
+    }
+
+    fn peer(name: &str, port: u16) -> String {
+        format!(
+            "[network.{}]\ncommand = \"cp -r {{HERE}} {{THERE}}\"\nendpoint = \"127.0.0.1:{}\"\nsecret = \"secret\"\n\n",
+            name, port
+        )
+    }
+
+    fn parse(config: &str) -> Config {
+        toml::from_str(config).unwrap()
+    }
+
+    #[test]
+    fn configured_peers_win_over_discovered_ones() {
+        let file = parse(&format!("{}\n{}", MACHINE, peer("b", 5001)));
+        let discovered: DiscoveredPeers = [
+            discovered("a", "127.0.0.1:5100"),
+            discovered("b", "127.0.0.1:5101"),
+            discovered("c", "127.0.0.1:5102"),
+        ]
+        .into();
+
+        let config = merge(&file, &discovered);
+        let mut names: Vec<_> = config.network.keys().cloned().collect();
+        names.sort();
+        assert_eq!(names, ["b", "c"]);
+        assert_eq!(config.network["b"], file.network["b"]);
+        assert_eq!(config.network["c"].endpoint, "127.0.0.1:5102");
+    }
+
+    #[test]
+    fn only_fixed_settings_need_a_restart() {
+        let current = parse(MACHINE);
+        let tuned = parse(&format!(
+            "{}\n[tuning]\npolling_interval_ms = 5000\n\n{}",
+            MACHINE,
+            peer("b", 5001)
+        ));
+        assert!(restart_needed(&current, &tuned).is_empty());
+
+        let moved = parse(
+            "[machine]\nname = \"a\"\nmode = \"DevelopmentMode\"\nendpoint = \"127.0.0.1:6000\"\n",
+        );
+        assert_eq!(restart_needed(&current, &moved), ["machine.endpoint"]);
+        let renamed = parse(
+            "[machine]\nname = \"z\"\nmode = \"DevelopmentMode\"\nendpoint = \"127.0.0.1:6000\"\n",
+        );
+        assert_eq!(
+            restart_needed(&current, &renamed),
+            ["machine.name", "machine.endpoint"]
+        );
+    }
+
+    #[test]
+    fn reload_reports_added_removed_and_changed_peers() {
+        let fixture = Fixture::new(&format!(
+            "{}\n{}{}",
+            MACHINE,
+            peer("b", 5001),
+            peer("c", 5002)
+        ));
+        let reload = fixture.rewrite(&format!(
+            "{}\n{}{}",
+            MACHINE,
+            peer("c", 5012),
+            peer("d", 5003)
+        ));
+        assert_eq!(
+            reload,
+            Reload {
+                added: vec!["d".to_string()],
+                removed: vec!["b".to_string()],
+                changed: vec!["c".to_string()],
+                ..Reload::default()
+            }
+        );
+        assert_eq!(fixture.quorum.members(), ["c", "d"]);
+
+        // reading the same file again changes nothing
+        let reload = fixture.rewrite(&format!(
+            "{}\n{}{}",
+            MACHINE,
+            peer("c", 5012),
+            peer("d", 5003)
+        ));
+        assert_eq!(reload, Reload::default());
+    }
+
+    #[test]
+    fn removed_peers_lose_their_locks() {
+        let fixture = Fixture::new(&format!("{}\n{}", MACHINE, peer("b", 5001)));
+        fixture.lend("b");
+
+        // a peer that only moved keeps what it borrowed
+        let reload = fixture.rewrite(&format!("{}\n{}", MACHINE, peer("b", 5011)));
+        assert_eq!(reload.changed, ["b"]);
+        assert!(reload.revoked.is_empty());
+        assert_eq!(fixture.holder(), SomeoneHasLock("b".to_string()));
+
+        let reload = fixture.rewrite(MACHINE);
+        assert_eq!(reload.removed, ["b"]);
+        assert_eq!(reload.revoked, [project()]);
+        assert_eq!(fixture.holder(), NobodyHasLock);
+        assert!(fixture.quorum.members().is_empty());
+    }
+
+    #[test]
+    fn restart_only_settings_are_reported_and_kept() {
+        let fixture = Fixture::new(MACHINE);
+        let reload = fixture.rewrite(&format!(
+            "[machine]\nname = \"a\"\nmode = \"DevelopmentMode\"\nendpoint = \"127.0.0.1:6000\"\n\n{}",
+            peer("b", 5001)
+        ));
+        assert_eq!(reload.ignored, ["machine.endpoint"]);
+        // the rest of the file still applies
+        assert_eq!(reload.added, ["b"]);
+        let sources = fixture.reloader.sources.lock().unwrap();
+        assert_eq!(sources.config.machine.endpoint, "127.0.0.1:5000");
+    }
+
+    #[test]
+    fn peers_that_stop_announcing_keep_their_locks() {
+        let fixture = Fixture::new(MACHINE);
+        fixture
+            .reloader
+            .set_discovered([discovered("b", "127.0.0.1:5001")].into());
+        fixture.lend("b");
+
+        let reload = fixture.reloader.set_discovered(DiscoveredPeers::new());
+        assert_eq!(reload.removed, ["b"]);
+        assert!(reload.revoked.is_empty());
+        assert_eq!(fixture.holder(), SomeoneHasLock("b".to_string()));
+        assert_eq!(fixture.quorum.members(), ["b"]);
+    }
+}
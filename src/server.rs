@@ -1,110 +1,694 @@
-use crate::config::{read_config, Config};
-use crate::index::{Index, LockStatus::*};
-use std::net::SocketAddr;
+use crate::auth::{
+    authenticate, AuthenticatedPeer, BadBody, PeerSecrets, Unauthorized, MAX_BODY, SIGNATURE_HEADER,
+};
+use crate::config::{read_config, Config, TlsConfig};
+use crate::error::NimbusError;
+use crate::health::Prober;
+use crate::index::{Index, LockError, LockResponse};
+use crate::status::{Status, SyncState};
+use crate::sync::Syncer;
+use crate::tls::{fingerprint, read_certificates};
+use chrono::prelude::*;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
-use warp::Filter;
+use warp::http::header::CONTENT_TYPE;
+use warp::http::{HeaderValue, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::reject::MethodNotAllowed;
+use warp::reply::Response;
+use warp::{Filter, Rejection};
 
-pub async fn build(index: Arc<Mutex<Index>>, endpoint: String) {
-    // Setup routes
+/// Body of every mutating peer route
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PeerRequest {
+    pub machine: String,
+    /// Epoch the caller believes it holds (checked on release), or proposes (when locking)
+    #[serde(default)]
+    pub epoch: Option<u64>,
+    /// Take the lock even if another peer's lease hasn't lapsed
+    #[serde(default)]
+    pub steal: bool,
+}
+
+/// What a ping is answered with
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Pong {
+    /// The peer's clock
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+// Replies to authenticated peers are signed so they know it's really us answering
+fn reply<T: Serialize>(
+    peer: Option<&AuthenticatedPeer>,
+    value: &T,
+    status: StatusCode,
+) -> Response {
+    let body = serde_json::to_vec(value).expect("Could not serialize reply");
+    let signature = peer.map(|peer| peer.sign(status.as_u16(), &body));
+    let mut response = Response::new(body.into());
+    *response.status_mut() = status;
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    if let Some(signature) = signature {
+        headers.insert(
+            SIGNATURE_HEADER,
+            HeaderValue::from_str(&signature).expect("hex is a valid header value"),
+        );
+    }
+    response
+}
+
+fn error_reply(peer: Option<&AuthenticatedPeer>, error: String, status: StatusCode) -> Response {
+    reply(peer, &ErrorResponse { error }, status)
+}
+
+fn lock_reply(
+    peer: &AuthenticatedPeer,
+    name: &str,
+    result: Result<LockResponse, LockError>,
+) -> Response {
+    match result {
+        Ok(response) => reply(Some(peer), &response, StatusCode::OK),
+        Err(LockError::Held(response)) | Err(LockError::NotHeld(response)) => {
+            reply(Some(peer), &response, StatusCode::CONFLICT)
+        }
+        Err(LockError::UnknownProject) => error_reply(
+            Some(peer),
+            format!("unknown project {}", name),
+            StatusCode::NOT_FOUND,
+        ),
+        Err(LockError::NoQuorum { agreed, needed }) => error_reply(
+            Some(peer),
+            format!("only {} of the {} machines needed agreed", agreed, needed),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+    }
+}
+
+// What a NimbusError looks like to a peer
+fn error_status(error: &NimbusError) -> StatusCode {
+    match error {
+        NimbusError::Io(error) => match error.kind() {
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+            ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        },
+        NimbusError::LockHeld { .. } => StatusCode::CONFLICT,
+        NimbusError::PeerUnreachable { .. } => StatusCode::BAD_GATEWAY,
+        NimbusError::SyncFailed { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        NimbusError::ConfigInvalid(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        NimbusError::InodeNotFound(_) => StatusCode::NOT_FOUND,
+        NimbusError::NotInProject(_) => StatusCode::BAD_REQUEST,
+    }
+}
+
+// Peers may only act on their own behalf
+fn peer_request(
+    peer: &AuthenticatedPeer,
+    body: &Bytes,
+) -> Result<PeerRequest, (String, StatusCode)> {
+    let request: PeerRequest = serde_json::from_slice(body).map_err(|err| {
+        (
+            format!("malformed request: {}", err),
+            StatusCode::BAD_REQUEST,
+        )
+    })?;
+    if request.machine != peer.machine {
+        return Err((
+            format!(
+                "{} is not allowed to act on behalf of {}",
+                peer.machine, request.machine
+            ),
+            StatusCode::FORBIDDEN,
+        ));
+    }
+    Ok(request)
+}
+
+// The peer wants our copy of project pushed over to it
+async fn sync_reply(
+    syncer: Syncer,
+    peer: AuthenticatedPeer,
+    project_name: String,
+    body: Bytes,
+) -> Response {
+    if let Err((error, status)) = peer_request(&peer, &body) {
+        return error_reply(Some(&peer), error, status);
+    }
+    let project_path = PathBuf::from_str(&project_name).expect("Could not convert to PathBuf");
+    match syncer.push(&project_path, &peer.machine).await {
+        Ok(()) => reply(Some(&peer), &SyncState::Synced(Utc::now()), StatusCode::OK),
+        Err(err) => error_reply(Some(&peer), err.to_string(), error_status(&err)),
+    }
+}
+
+async fn handle_rejection(rejection: Rejection) -> Result<Response, Infallible> {
+    if let Some(Unauthorized(error)) = rejection.find() {
+        return Ok(error_reply(None, error.clone(), StatusCode::UNAUTHORIZED));
+    }
+    match rejection.find() {
+        Some(BadBody::TooLarge) => {
+            return Ok(error_reply(
+                None,
+                format!("request body over {} bytes", MAX_BODY),
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ))
+        }
+        Some(BadBody::Unreadable(error)) => {
+            return Ok(error_reply(
+                None,
+                format!("unreadable request body: {}", error),
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+        None => (),
+    }
+    if rejection.find::<MethodNotAllowed>().is_some() {
+        return Ok(error_reply(
+            None,
+            "method not allowed".to_string(),
+            StatusCode::METHOD_NOT_ALLOWED,
+        ));
+    }
+    if rejection.is_not_found() {
+        return Ok(error_reply(
+            None,
+            "not found".to_string(),
+            StatusCode::NOT_FOUND,
+        ));
+    }
+    Ok(error_reply(
+        None,
+        format!("{:?}", rejection),
+        StatusCode::BAD_REQUEST,
+    ))
+}
+
+pub fn routes(
+    index: Arc<Mutex<Index>>,
+    status: Status,
+    prober: Prober,
+    secrets: PeerSecrets,
+    syncer: Syncer,
+) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone {
     let nimbus_index = index.clone();
-    let acquire_project_lock =
-        warp::path!("lock" / "acquire" / String / String).map(move |machine_name: String, project_name: String| {
-            let project_path = PathBuf::from_str(&project_name).expect("Could not convert to PathBuf");
-            let mut index = nimbus_index.lock().expect("lock failed");
-            match index.project_lock.get(&project_path) {
-                Some(WeHaveLock(_)) => "fail",                    // mine, not yours
-                Some(SomeoneHasLock(other)) => {
-                    if other == &machine_name { // you already have the lock
-                        "acquired"
-                    } else {// someone else has the lock and it ain't you
-                        "fail"
-                    }
-                }
-                Some(NobodyHasLock) => {
-                    index
-                        .project_lock
-                        .insert(PathBuf::from_str(&project_name).expect("Could not convert to PathBuf"), SomeoneHasLock(machine_name))
-                        .expect("this should never happen");
-                    "acquired"
-                }
-                None => panic!(
-                    "attempted to acquire lock for {} in {:?}, but does not exist in the index! panic!", machine_name, project_name
-                ),
-            }
-        });
+    let acquire_project_lock = warp::path!("projects" / String / "lock")
+        .and(warp::post())
+        .and(authenticate(secrets.clone()))
+        .map(
+            move |project_name: String, peer: AuthenticatedPeer, body: Bytes| {
+                let request = match peer_request(&peer, &body) {
+                    Ok(request) => request,
+                    Err((error, status)) => return error_reply(Some(&peer), error, status),
+                };
+                let project_path =
+                    PathBuf::from_str(&project_name).expect("Could not convert to PathBuf");
+                let mut index = nimbus_index.lock().expect("lock failed");
+                lock_reply(
+                    &peer,
+                    &project_name,
+                    index.lend_project_lock(
+                        &project_path,
+                        request.machine,
+                        request.epoch,
+                        request.steal,
+                    ),
+                )
+            },
+        );
     let nimbus_index = index.clone();
-    let update_and_release_project_lock =
-        warp::path!("lock" / "release" / String / String).map(move |machine_name: String, project_name: String| {
-            let project_path = PathBuf::from_str(&project_name).expect("Could not convert to PathBuf");
-            let mut index = nimbus_index.lock().expect("lock failed");
-            match index.project_lock.get(&project_path) {
-                Some(WeHaveLock(_)) => "fail", // we have the lock
-                Some(SomeoneHasLock(other)) => {
-                    if other == &machine_name { // you have the lock, good
-                        index
-                            .project_lock
-                            .insert(project_path, NobodyHasLock)
-                            .expect("this should never happen");
-                        "released"
-                    } else { // someone else has the lock and it ain't you
-                        "fail"
-                    }
-                } // you have the lock, good
-                Some(NobodyHasLock) => "fail", // you don't have the lock
-                None => panic!(
-                    "attempted to release lock for {} in {:?}, but does not exist in the index! panic!", machine_name, project_name
-                ),
-            }
-        });
+    let release_project_lock = warp::path!("projects" / String / "release")
+        .and(warp::post())
+        .and(authenticate(secrets.clone()))
+        .map(
+            move |project_name: String, peer: AuthenticatedPeer, body: Bytes| {
+                let request = match peer_request(&peer, &body) {
+                    Ok(request) => request,
+                    Err((error, status)) => return error_reply(Some(&peer), error, status),
+                };
+                let project_path =
+                    PathBuf::from_str(&project_name).expect("Could not convert to PathBuf");
+                let mut index = nimbus_index.lock().expect("lock failed");
+                lock_reply(
+                    &peer,
+                    &project_name,
+                    index.return_project_lock(&project_path, request.machine, request.epoch),
+                )
+            },
+        );
+    let nimbus_index = index.clone();
+    let project_lock = warp::path!("projects" / String / "lock")
+        .and(warp::get())
+        .and(authenticate(secrets.clone()))
+        .map(
+            move |project_name: String, peer: AuthenticatedPeer, _body: Bytes| {
+                let project_path =
+                    PathBuf::from_str(&project_name).expect("Could not convert to PathBuf");
+                let index = nimbus_index.lock().expect("lock failed");
+                lock_reply(
+                    &peer,
+                    &project_name,
+                    index.project_lock_response(&project_path),
+                )
+            },
+        );
     let nimbus_index = index.clone();
-    let acquire_index_lock =
-        warp::path!("index" / "lock" / "acquire" / String).map(move |machine_name: String| {
+    let acquire_index_lock = warp::path!("index" / "lock")
+        .and(warp::post())
+        .and(authenticate(secrets.clone()))
+        .map(move |peer: AuthenticatedPeer, body: Bytes| {
+            let request = match peer_request(&peer, &body) {
+                Ok(request) => request,
+                Err((error, status)) => return error_reply(Some(&peer), error, status),
+            };
             let mut index = nimbus_index.lock().expect("lock failed");
-            match &index.index_lock {
-                WeHaveLock(_) => "fail", // mine, not yours
-                SomeoneHasLock(other) => {
-                    if other == &machine_name {
-                        // you already have the lock
-                        "acquired"
-                    } else {
-                        // someone else has the lock and it ain't you
-                        "fail"
-                    }
-                }
-                NobodyHasLock => {
-                    index.index_lock = SomeoneHasLock(machine_name);
-                    "acquired"
-                }
-            }
+            lock_reply(&peer, "index", index.lend_index_lock(request.machine))
         });
     let nimbus_index = index.clone();
-    let update_and_release_index_lock =
-        warp::path!("index" / "lock" / "release" / String).map(move |machine_name: String| {
+    let release_index_lock = warp::path!("index" / "release")
+        .and(warp::post())
+        .and(authenticate(secrets.clone()))
+        .map(move |peer: AuthenticatedPeer, body: Bytes| {
+            let request = match peer_request(&peer, &body) {
+                Ok(request) => request,
+                Err((error, status)) => return error_reply(Some(&peer), error, status),
+            };
             let mut index = nimbus_index.lock().expect("lock failed");
-            match &index.index_lock {
-                WeHaveLock(_) => "fail", // mine, not yours
-                SomeoneHasLock(other) => {
-                    if other == &machine_name {
-                        // you already have the lock
-                        index.index_lock = NobodyHasLock;
-                        "released"
-                    } else {
-                        // you didn't have the lock
-                        "fail"
-                    }
+            lock_reply(
+                &peer,
+                "index",
+                index.return_index_lock(request.machine, request.epoch),
+            )
+        });
+    let sync_project = warp::path!("projects" / String / "sync")
+        .and(warp::post())
+        .and(authenticate(secrets.clone()))
+        .and_then(
+            move |project_name: String, peer: AuthenticatedPeer, body: Bytes| {
+                let syncer = syncer.clone();
+                async move {
+                    Ok::<_, Rejection>(sync_reply(syncer, peer, project_name, body).await)
                 }
-                NobodyHasLock => "fail",
-            }
+            },
+        );
+    let ping = warp::path!("ping")
+        .and(warp::get())
+        .and(authenticate(secrets.clone()))
+        .map(|peer: AuthenticatedPeer, _body: Bytes| {
+            reply(Some(&peer), &Pong { time: Utc::now() }, StatusCode::OK)
         });
-    let routes = warp::get().and(
-        acquire_project_lock
-            .or(update_and_release_project_lock)
-            .or(acquire_index_lock)
-            .or(update_and_release_index_lock),
-    );
-    warp::serve(routes)
-        .run(SocketAddr::from_str(&endpoint).expect("supplied endpoint failed to parse"))
+    let nimbus_status = status.clone();
+    let list_projects = warp::path!("status" / "projects")
+        .and(warp::get())
+        .map(move || reply(None, &nimbus_status.projects(), StatusCode::OK));
+    let nimbus_status = status.clone();
+    let project_status = warp::path!("status" / "projects" / String)
+        .and(warp::get())
+        .map(
+            move |project_name: String| match nimbus_status.project(&project_name) {
+                Some(project) => reply(None, &project, StatusCode::OK),
+                None => error_reply(
+                    None,
+                    format!("unknown project {}", project_name),
+                    StatusCode::NOT_FOUND,
+                ),
+            },
+        );
+
+    let list_peers = warp::path!("status" / "peers")
+        .and(warp::get())
+        .map(move || reply(None, &prober.peers(), StatusCode::OK));
+
+    let lock_routes = acquire_project_lock
+        .or(release_project_lock)
+        .unify()
+        .or(project_lock)
+        .unify()
+        .or(acquire_index_lock)
+        .unify()
+        .or(release_index_lock)
+        .unify()
+        .or(sync_project)
+        .unify()
+        .or(ping)
+        .unify();
+    let status_routes = list_projects
+        .or(project_status)
+        .unify()
+        .or(list_peers)
+        .unify();
+    warp::path("v1")
+        .and(lock_routes.or(status_routes).unify())
+        .recover(handle_rejection)
+        .unify()
+}
+
+pub async fn build<F>(
+    routes: F,
+    endpoint: String,
+    tls: Option<TlsConfig>,
+    stop: impl Future<Output = ()> + Send + 'static,
+) where
+    F: Filter<Extract = (Response,), Error = Infallible> + Clone + Send + Sync + 'static,
+{
+    // a host name is resolved once, at startup
+    let endpoint: SocketAddr = endpoint
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addresses| addresses.next())
+        .expect("supplied endpoint failed to resolve");
+    match tls {
+        Some(tls) => {
+            // peers pin this in their [network.X].fingerprint
+            match read_certificates(&tls.cert) {
+                Ok(certificates) => info!(
+                    "serving TLS on {} with certificate fingerprint {}",
+                    endpoint,
+                    fingerprint(&certificates[0])
+                ),
+                Err(err) => error!("unable to read certificate {:?}: {:?}", tls.cert, err),
+            }
+            let (_, server) = warp::serve(routes)
+                .tls()
+                .cert_path(&tls.cert)
+                .key_path(&tls.key)
+                .bind_with_graceful_shutdown(endpoint, stop);
+            server.await;
+        }
+        None => {
+            let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(endpoint, stop);
+            server.await;
+        }
+    }
+    info!("server on {} stopped", endpoint);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{
+        nonce, sign_request, verify_response, MACHINE_HEADER, NONCE_HEADER, TIMESTAMP_HEADER,
+    };
+    use crate::config::TuningConfig;
+    use crate::index::LockStatus::*;
+    use crate::inode_table::ROOT_DIR;
+    use rustc_hash::{FxHashMap, FxHashSet};
+    use std::collections::HashMap;
+    use std::io;
+    use std::path::Path;
+    use std::sync::RwLock;
+
+    const SECRET: &str = "server-test-secret";
+
+    // The peer API of machine a, to which "peer" syncs with a command that always fails
+    fn peer_api(
+        dir: &Path,
+        index: Arc<Mutex<Index>>,
+    ) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone + 'static {
+        let config: Config = toml::from_str(&format!(
+            "[machine]\nname = \"a\"\nmode = \"DevelopmentMode\"\nendpoint = \"127.0.0.1:0\"\n\n\
+             [network.peer]\ncommand = \"exit 3\"\nendpoint = \"127.0.0.1:1\"\nstorage = {:?}\nsecret = \"{}\"\n",
+            dir.join("there"),
+            SECRET
+        ))
+        .unwrap();
+        let activity = Arc::new(Mutex::new(FxHashMap::default()));
+        let status = Status::new(
+            index.clone(),
+            Arc::new(Mutex::new(FxHashMap::default())),
+            Arc::clone(&activity),
+            Arc::new(Mutex::new(FxHashSet::default())),
+            Arc::new(Mutex::new(FxHashMap::default())),
+        );
+        let syncer = Syncer::new(&config, dir.to_path_buf(), activity);
+        let prober = Prober::new(
+            syncer.clone(),
+            Arc::new(Mutex::new(TuningConfig::default())),
+        );
+        let secrets: PeerSecrets = Arc::new(RwLock::new(HashMap::from([(
+            "peer".to_string(),
+            SECRET.to_string(),
+        )])));
+        routes(index, status, prober, secrets, syncer)
+    }
+
+    fn index() -> Arc<Mutex<Index>> {
+        let mut index = Index::new();
+        index.register_project(PathBuf::from("project"));
+        Arc::new(Mutex::new(index))
+    }
+
+    // Sends a request signed by "peer", checking that the reply is signed for it
+    async fn signed(
+        api: &(impl Filter<Extract = (Response,), Error = Infallible> + 'static),
+        method: &str,
+        path: &str,
+        body: &[u8],
+    ) -> (StatusCode, Bytes) {
+        let (timestamp, nonce) = (Utc::now().timestamp(), nonce());
+        let signature = sign_request(SECRET, method, path, "peer", timestamp, &nonce, body);
+        let response = warp::test::request()
+            .method(method)
+            .path(path)
+            .header(MACHINE_HEADER, "peer")
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(NONCE_HEADER, nonce)
+            .header(SIGNATURE_HEADER, &signature)
+            .body(body.to_vec())
+            .reply(api)
+            .await;
+        let status = response.status();
+        let reply_signature = response.headers()[SIGNATURE_HEADER].to_str().unwrap();
+        assert!(verify_response(
+            SECRET,
+            &signature,
+            status.as_u16(),
+            response.body(),
+            reply_signature
+        ));
+        (status, response.body().clone())
+    }
+
+    fn lock(body: &Bytes) -> LockResponse {
+        serde_json::from_slice(body).unwrap()
+    }
+
+    fn error(body: &Bytes) -> String {
+        serde_json::from_slice::<ErrorResponse>(body).unwrap().error
+    }
+
+    #[tokio::test]
+    async fn lock_routes_map_lock_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = index();
+        let api = peer_api(dir.path(), index.clone());
+        let request = br#"{"machine":"peer"}"#;
+
+        let (status, body) = signed(&api, "POST", "/v1/projects/project/lock", request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(lock(&body).holder.as_deref(), Some("peer"));
+        let (status, body) = signed(&api, "GET", "/v1/projects/project/lock", b"").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(lock(&body).epoch, 1);
+
+        // handing back an epoch it doesn't hold
+        let (status, body) = signed(
+            &api,
+            "POST",
+            "/v1/projects/project/release",
+            br#"{"machine":"peer","epoch":7}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(lock(&body).epoch, 1);
+
+        index
+            .lock()
+            .unwrap()
+            .take_project_lock(&PathBuf::from("project"), "a".to_string(), true, None)
+            .unwrap();
+        let (status, body) = signed(&api, "POST", "/v1/projects/project/lock", request).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(lock(&body).holder.as_deref(), Some("a"));
+
+        let (status, body) = signed(&api, "POST", "/v1/projects/unknown/lock", request).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(error(&body), "unknown project unknown");
+
+        let (status, _) = signed(&api, "POST", "/v1/index/lock", request).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = signed(&api, "POST", "/v1/index/release", request).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = signed(&api, "POST", "/v1/index/release", request).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(index.lock().unwrap().index_lock, NobodyHasLock);
+
+        let (status, body) = signed(&api, "GET", "/v1/ping", b"").await;
+        assert_eq!(status, StatusCode::OK);
+        serde_json::from_slice::<Pong>(&body).unwrap();
+    }
+
+    #[tokio::test]
+    async fn peers_only_act_on_their_own_behalf() {
+        let dir = tempfile::tempdir().unwrap();
+        let api = peer_api(dir.path(), index());
+
+        let (status, body) = signed(&api, "POST", "/v1/projects/project/lock", b"{").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(error(&body).starts_with("malformed request"));
+
+        let (status, body) = signed(
+            &api,
+            "POST",
+            "/v1/projects/project/lock",
+            br#"{"machine":"a"}"#,
+        )
         .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(error(&body), "peer is not allowed to act on behalf of a");
+    }
+
+    #[tokio::test]
+    async fn sync_maps_nimbus_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("project")).unwrap();
+        let api = peer_api(dir.path(), index());
+        let request = br#"{"machine":"peer"}"#;
+
+        let (status, _) = signed(&api, "POST", "/v1/projects/missing/sync", request).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = signed(&api, "POST", "/v1/projects/../sync", request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, body) = signed(&api, "POST", "/v1/projects/project/sync", request).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(error(&body).contains("exit 3"));
+    }
+
+    #[tokio::test]
+    async fn status_routes_are_not_signed() {
+        let dir = tempfile::tempdir().unwrap();
+        let api = peer_api(dir.path(), index());
+
+        let response = warp::test::request()
+            .path("/v1/status/projects")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(SIGNATURE_HEADER).is_none());
+        let response = warp::test::request()
+            .path("/v1/status/projects/project")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = warp::test::request()
+            .path("/v1/status/projects/unknown")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = warp::test::request()
+            .path("/v1/status/peers")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rejections_become_error_replies() {
+        let dir = tempfile::tempdir().unwrap();
+        let api = peer_api(dir.path(), index());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/v1/projects/project/lock")
+            .body(r#"{"machine":"peer"}"#)
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response.headers().get(SIGNATURE_HEADER).is_none());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/v1/projects/project/lock")
+            .body(vec![b'x'; MAX_BODY + 1])
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/v1/ping")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let response = warp::test::request().path("/v1/nothing").reply(&api).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(error(response.body()), "not found");
+
+        let response =
+            handle_rejection(warp::reject::custom(BadBody::Unreadable("eof".to_string())))
+                .await
+                .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn nimbus_errors_map_to_statuses() {
+        let io_error = |kind| NimbusError::Io(io::Error::new(kind, "io"));
+        let cases = [
+            (io_error(ErrorKind::NotFound), StatusCode::NOT_FOUND),
+            (io_error(ErrorKind::InvalidInput), StatusCode::BAD_REQUEST),
+            (io_error(ErrorKind::PermissionDenied), StatusCode::FORBIDDEN),
+            (
+                io_error(ErrorKind::Other),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                NimbusError::LockHeld {
+                    project: PathBuf::from("project"),
+                    holder: "b".to_string(),
+                },
+                StatusCode::CONFLICT,
+            ),
+            (
+                NimbusError::PeerUnreachable {
+                    peer: "b".to_string(),
+                    reason: "down".to_string(),
+                },
+                StatusCode::BAD_GATEWAY,
+            ),
+            (
+                NimbusError::SyncFailed {
+                    project: PathBuf::from("project"),
+                    reason: "failed".to_string(),
+                },
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                NimbusError::ConfigInvalid("invalid".to_string()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (NimbusError::InodeNotFound(ROOT_DIR), StatusCode::NOT_FOUND),
+            (
+                NimbusError::NotInProject(PathBuf::from("/storage")),
+                StatusCode::BAD_REQUEST,
+            ),
+        ];
+        for (error, status) in cases {
+            assert_eq!(error_status(&error), status, "{:?}", error);
+        }
+    }
 }
@@ -0,0 +1,141 @@
+use log::{error, info, warn};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+use crate::client::LockReply;
+use crate::files::FileHandlers;
+use crate::index::{CanonicalProjectName, Index, LockStatus::*};
+use crate::journal::Journal;
+use crate::sync::Syncer;
+
+/// How long winding down may take before nimbus unmounts regardless
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long unmounting may take (it blocks while the mount is busy)
+pub const UNMOUNT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Everything that has to be wound down before nimbus unmounts
+pub struct Shutdown {
+    draining: Arc<AtomicBool>,
+    file_handlers: FileHandlers,
+    /// Cleared once every handle is flushed
+    journal: Journal,
+    index: Arc<Mutex<Index>>,
+    /// Where the index is persisted
+    state: PathBuf,
+    /// Knows the peers the locks we hold are handed back to (as of the last reload)
+    syncer: Syncer,
+    /// Stops the warp server once fired
+    server: Option<oneshot::Sender<()>>,
+}
+
+impl Shutdown {
+    pub fn new(
+        syncer: Syncer,
+        draining: Arc<AtomicBool>,
+        file_handlers: FileHandlers,
+        journal: Journal,
+        index: Arc<Mutex<Index>>,
+        state: PathBuf,
+        server: oneshot::Sender<()>,
+    ) -> Shutdown {
+        Shutdown {
+            draining,
+            file_handlers,
+            journal,
+            index,
+            state,
+            syncer,
+            server: Some(server),
+        }
+    }
+
+    // Stops opens, flushes handles, releases locks, persists the index and stops the server
+    pub async fn run(&mut self) {
+        self.draining.store(true, Ordering::SeqCst);
+        self.flush_handlers();
+        self.release_locks().await;
+        match self.index.lock().expect("lock failed").save(&self.state) {
+            Ok(()) => info!("persisted index to {:?}", self.state),
+            Err(err) => error!("unable to persist index to {:?}: {:?}", self.state, err),
+        }
+        self.stop_server();
+    }
+
+    fn flush_handlers(&self) {
+        let handlers: Vec<_> = {
+            let file_handlers = self.file_handlers.lock().expect("lock failed");
+            file_handlers.values().cloned().collect()
+        };
+        info!("flushing {} open file handlers", handlers.len());
+        let mut flushed = true;
+        for handler in handlers {
+            let mut handler = handler.lock().expect("lock failed");
+            if let Err(err) = handler.flush().and_then(|_| handler.sync_all()) {
+                error!("unable to flush file handler: {:?}", err);
+                flushed = false;
+            }
+        }
+        // otherwise the next start replays what could not be flushed
+        if flushed {
+            if let Err(err) = self.journal.clear() {
+                error!("unable to clear journal: {:?}", err);
+            }
+        }
+    }
+
+    // Gives up every lock we hold, handing it back to whichever peer lent it to us
+    async fn release_locks(&self) {
+        let held: Vec<(CanonicalProjectName, u64)> = {
+            let mut index = self.index.lock().expect("lock failed");
+            let held: Vec<_> = index
+                .project_lock
+                .iter()
+                .filter(|(_, lock)| matches!(lock, WeHaveLock(_)))
+                .map(|(project, _)| project.clone())
+                .collect();
+            held.into_iter()
+                .map(|project| {
+                    index.project_lock.insert(project.clone(), NobodyHasLock);
+                    let epoch = index
+                        .project_lease
+                        .get(&project)
+                        .map_or(0, |lease| lease.epoch);
+                    (project, epoch)
+                })
+                .collect()
+        };
+        let peers = self.syncer.clients();
+        for (project, epoch) in held {
+            info!("releasing lock on {:?} (epoch {})", project, epoch);
+            for peer in &peers {
+                match peer
+                    .release_project_lock(&project.to_string_lossy(), epoch)
+                    .await
+                {
+                    Ok(LockReply::Granted(_)) => {
+                        info!("handed {:?} back to {}", project, peer.peer())
+                    }
+                    // the peer never lent it to us
+                    Ok(LockReply::Conflict(_)) | Ok(LockReply::UnknownProject) => (),
+                    Err(err) => warn!(
+                        "unable to hand {:?} back to {}: {:?}",
+                        project,
+                        peer.peer(),
+                        err
+                    ),
+                }
+            }
+        }
+    }
+
+    fn stop_server(&mut self) {
+        if let Some(server) = self.server.take() {
+            // the server may already be gone (e.g. it failed to bind)
+            let _ = server.send(());
+        }
+    }
+}
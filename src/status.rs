@@ -0,0 +1,136 @@
+use chrono::prelude::*;
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use crate::files::FileHandlers;
+use crate::index::{CanonicalProjectName, Index, LockStatus};
+use crate::tracker::{PinnedProjects, ProjectRefs};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum SyncState {
+    NeverSynced,
+    Syncing,
+    Synced(DateTime<Utc>),
+    Failed(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ProjectActivity {
+    pub last_access: Option<DateTime<Utc>>,
+    pub sync: SyncState,
+}
+
+impl Default for ProjectActivity {
+    fn default() -> ProjectActivity {
+        ProjectActivity {
+            last_access: None,
+            sync: SyncState::NeverSynced,
+        }
+    }
+}
+
+pub type ProjectActivities = Arc<Mutex<FxHashMap<CanonicalProjectName, ProjectActivity>>>;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct ProjectStatus {
+    pub name: String,
+    pub lock: LockStatus,
+    pub refs: u64,
+    pub pinned: bool,
+    pub open_handles: u64,
+    pub last_access: Option<DateTime<Utc>>,
+    pub sync: SyncState,
+}
+
+/// Read-only view over the state the filesystem shares with the server
+#[derive(Clone)]
+pub struct Status {
+    index: Arc<Mutex<Index>>,
+    refs: ProjectRefs,
+    activity: ProjectActivities,
+    pinned: PinnedProjects,
+    /// Open handles are counted from here, so they can't drift from what is really open
+    file_handlers: FileHandlers,
+}
+
+impl Status {
+    pub fn new(
+        index: Arc<Mutex<Index>>,
+        refs: ProjectRefs,
+        activity: ProjectActivities,
+        pinned: PinnedProjects,
+        file_handlers: FileHandlers,
+    ) -> Status {
+        Status {
+            index,
+            refs,
+            activity,
+            pinned,
+            file_handlers,
+        }
+    }
+
+    pub fn projects(&self) -> Vec<ProjectStatus> {
+        // snapshot everything first so that no two locks are ever held at once
+        let locks: FxHashMap<CanonicalProjectName, LockStatus> = {
+            let index = self.index.lock().expect("lock failed");
+            index
+                .project_lock
+                .iter()
+                .map(|(project, lock)| (project.clone(), lock.clone()))
+                .collect()
+        };
+        let refs: FxHashMap<CanonicalProjectName, u64> = {
+            let refs = self.refs.lock().expect("lock failed");
+            refs.iter()
+                .map(|(project, counter)| (project.clone(), counter.load(Ordering::SeqCst)))
+                .collect()
+        };
+        let activity = self.activity.lock().expect("lock failed").clone();
+        let pinned = self.pinned.lock().expect("lock failed").clone();
+        let handlers: Vec<_> = {
+            let file_handlers = self.file_handlers.lock().expect("lock failed");
+            file_handlers.values().cloned().collect()
+        };
+        let mut open_handles: FxHashMap<CanonicalProjectName, u64> = FxHashMap::default();
+        for handler in handlers {
+            if let Some(project) = &handler.lock().expect("lock failed").project {
+                *open_handles.entry(project.clone()).or_insert(0) += 1;
+            }
+        }
+
+        let names: BTreeSet<&CanonicalProjectName> = locks
+            .keys()
+            .chain(refs.keys())
+            .chain(activity.keys())
+            .chain(open_handles.keys())
+            .collect();
+        names
+            .into_iter()
+            .map(|project| {
+                let project_activity = activity.get(project).cloned().unwrap_or_default();
+                ProjectStatus {
+                    name: project.to_string_lossy().into_owned(),
+                    lock: locks
+                        .get(project)
+                        .cloned()
+                        .unwrap_or(LockStatus::NobodyHasLock),
+                    refs: *refs.get(project).unwrap_or(&0),
+                    pinned: pinned.contains(project),
+                    open_handles: *open_handles.get(project).unwrap_or(&0),
+                    last_access: project_activity.last_access,
+                    sync: project_activity.sync,
+                }
+            })
+            .collect()
+    }
+
+    pub fn project(&self, name: &str) -> Option<ProjectStatus> {
+        self.projects()
+            .into_iter()
+            .find(|project| project.name == name)
+    }
+}
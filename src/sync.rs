@@ -0,0 +1,209 @@
+use chrono::prelude::*;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::client::{ClientError, PeerClient};
+use crate::config::Config;
+use crate::error::{NimbusError, Result};
+use crate::index::CanonicalProjectName;
+use crate::status::{ProjectActivities, SyncState};
+
+/// How a project gets copied over to a peer
+struct SyncPeer {
+    command: String,
+    storage: Option<PathBuf>,
+    /// None if the peer has no secret configured
+    client: Option<PeerClient>,
+}
+
+/// Runs the copy commands configured in [network.X] and records how each sync went
+#[derive(Clone)]
+pub struct Syncer {
+    local_storage: PathBuf,
+    peers: Arc<RwLock<HashMap<String, Arc<SyncPeer>>>>,
+    activity: ProjectActivities,
+}
+
+// Single-quotes path for sh, since project names come from peers and users
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', "'\\''"))
+}
+
+pub fn expand_command(command: &str, here: &Path, there: &Path) -> String {
+    command
+        .replace("{HERE}", &shell_quote(here))
+        .replace("{THERE}", &shell_quote(there))
+}
+
+// Every peer in [network], including those we can't call (no secret)
+fn sync_peers(config: &Config) -> HashMap<String, Arc<SyncPeer>> {
+    config
+        .network
+        .iter()
+        .map(|(name, peer)| {
+            let client = match PeerClient::new(config, name) {
+                Ok(client) => Some(client),
+                Err(err) => {
+                    warn!("unable to sync from peer {}: {:?}", name, err);
+                    None
+                }
+            };
+            let sync_peer = SyncPeer {
+                command: peer.command.clone(),
+                storage: peer.storage.clone(),
+                client,
+            };
+            (name.clone(), Arc::new(sync_peer))
+        })
+        .collect()
+}
+
+impl Syncer {
+    pub fn new(config: &Config, local_storage: PathBuf, activity: ProjectActivities) -> Syncer {
+        Syncer {
+            local_storage,
+            peers: Arc::new(RwLock::new(sync_peers(config))),
+            activity,
+        }
+    }
+
+    // Picks up a reloaded [network]; syncs already running finish with the old settings
+    pub fn set_peers(&self, config: &Config) {
+        *self.peers.write().expect("lock failed") = sync_peers(config);
+    }
+
+    pub fn peers(&self) -> Vec<String> {
+        let peers = self.peers.read().expect("lock failed");
+        peers.keys().cloned().collect()
+    }
+
+    // Clients for every peer we can call
+    pub fn clients(&self) -> Vec<PeerClient> {
+        let peers = self.peers.read().expect("lock failed");
+        peers
+            .values()
+            .filter_map(|peer| peer.client.clone())
+            .collect()
+    }
+
+    fn peer(&self, peer: &str) -> Result<Arc<SyncPeer>, Error> {
+        let peers = self.peers.read().expect("lock failed");
+        peers
+            .get(peer)
+            .cloned()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("unknown peer {}", peer)))
+    }
+
+    // Projects are top-level directories of local_storage, nothing else may be synced
+    fn project_path(&self, project: &CanonicalProjectName) -> Result<PathBuf, Error> {
+        let mut components = project.components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => (),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{:?} is not a project name", project),
+                ))
+            }
+        }
+        Ok(self.local_storage.join(project))
+    }
+
+    fn record(&self, project: &CanonicalProjectName, sync: SyncState) {
+        let mut activity = self.activity.lock().expect("lock failed");
+        activity.entry(project.clone()).or_default().sync = sync;
+    }
+
+    fn record_result(&self, project: &CanonicalProjectName, result: &Result<()>) {
+        match result {
+            Ok(()) => self.record(project, SyncState::Synced(Utc::now())),
+            Err(err) => self.record(project, SyncState::Failed(err.to_string())),
+        }
+    }
+
+    // Copies our copy of project over to peer
+    pub async fn push(&self, project: &CanonicalProjectName, peer: &str) -> Result<()> {
+        let here = self.project_path(project)?;
+        if !here.exists() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("unknown project {:?}", project),
+            )
+            .into());
+        }
+        let sync_peer = self.peer(peer)?;
+        let there = sync_peer
+            .storage
+            .as_ref()
+            .ok_or_else(|| {
+                NimbusError::ConfigInvalid(format!("[network.{}] has no storage to sync to", peer))
+            })?
+            .join(project);
+        let command = expand_command(&sync_peer.command, &here, &there);
+
+        self.record(project, SyncState::Syncing);
+        info!("syncing {:?} to {}: {}", project, peer, command);
+        let result = match tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .status()
+            .await
+        {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(NimbusError::SyncFailed {
+                project: project.clone(),
+                reason: format!("`{}` failed with {}", command, status),
+            }),
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = &result {
+            error!("syncing {:?} to {} failed: {}", project, peer, err);
+        }
+        self.record_result(project, &result);
+        result
+    }
+
+    // Pushes project to every peer, trying all of them even if some fail
+    pub async fn push_all(&self, project: &CanonicalProjectName) -> Result<()> {
+        let mut result = Ok(());
+        for peer in self.peers() {
+            if let Err(err) = self.push(project, &peer).await {
+                result = Err(err);
+            }
+        }
+        result
+    }
+
+    // Asks peer to push its copy of project over to us
+    pub async fn pull(&self, project: &CanonicalProjectName, peer: &str) -> Result<()> {
+        self.project_path(project)?;
+        let sync_peer = self.peer(peer)?;
+        let client = sync_peer.client.as_ref().ok_or_else(|| {
+            NimbusError::ConfigInvalid(format!("[network.{}] has no secret", peer))
+        })?;
+
+        self.record(project, SyncState::Syncing);
+        let result = client
+            .request_sync(&project.to_string_lossy())
+            .await
+            .map(|_| ())
+            .map_err(|err| match err {
+                ClientError::Unreachable(err) => NimbusError::PeerUnreachable {
+                    peer: peer.to_string(),
+                    reason: err.to_string(),
+                },
+                err => NimbusError::SyncFailed {
+                    project: project.clone(),
+                    reason: format!("{:?}", err),
+                },
+            });
+        if let Err(err) = &result {
+            error!("syncing {:?} from {} failed: {}", project, peer, err);
+        }
+        self.record_result(project, &result);
+        result
+    }
+}
@@ -0,0 +1,122 @@
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, Error, RootCertStore, ServerName};
+use sha2::{Digest, Sha256};
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::config::NetworkMachineConfig;
+
+/// How long connecting to a peer may take
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a peer may take to answer a request (syncs get longer, see PeerClient)
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Hex SHA-256 of a DER certificate, which is what [network.X].fingerprint pins
+pub fn fingerprint(der: &[u8]) -> String {
+    hex::encode(Sha256::digest(der))
+}
+
+// Fingerprints may be written with colons and in either case
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| *c != ':')
+        .collect::<String>()
+        .to_lowercase()
+}
+
+pub fn read_certificates(path: &Path) -> io::Result<Vec<Vec<u8>>> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    let certificates = rustls_pemfile::certs(&mut reader)?;
+    if certificates.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no certificates in {:?}", path),
+        ));
+    }
+    Ok(certificates)
+}
+
+pub fn uses_tls(peer: &NetworkMachineConfig) -> bool {
+    peer.ca.is_some() || peer.fingerprint.is_some()
+}
+
+/// Checks the peer's certificate against its CA and/or pinned fingerprint (both, if both are set)
+struct PeerVerifier {
+    ca: Option<WebPkiVerifier>,
+    fingerprint: Option<String>,
+}
+
+impl ServerCertVerifier for PeerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        if let Some(ca) = &self.ca {
+            ca.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            )?;
+        }
+        if let Some(pinned) = &self.fingerprint {
+            let actual = fingerprint(&end_entity.0);
+            if &actual != pinned {
+                return Err(Error::General(format!(
+                    "certificate fingerprint {} does not match the pinned {}",
+                    actual, pinned
+                )));
+            }
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+fn client_config(peer: &NetworkMachineConfig) -> io::Result<ClientConfig> {
+    let ca = match &peer.ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for der in read_certificates(path)? {
+                roots
+                    .add(&Certificate(der))
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            }
+            Some(WebPkiVerifier::new(roots, None))
+        }
+        None => None,
+    };
+    let verifier = PeerVerifier {
+        ca,
+        fingerprint: peer.fingerprint.as_deref().map(normalize_fingerprint),
+    };
+    Ok(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth())
+}
+
+// HTTP client for talking to peer; only trusts what [network.X] pins if it uses TLS
+pub fn peer_client(peer: &NetworkMachineConfig) -> io::Result<reqwest::Client> {
+    // a peer that stopped answering must not hold up the locks of everyone else
+    let builder = reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT);
+    let builder = if uses_tls(peer) {
+        builder.use_preconfigured_tls(client_config(peer)?)
+    } else {
+        builder
+    };
+    builder
+        .build()
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+}
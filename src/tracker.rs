@@ -0,0 +1,595 @@
+use log::{error, info, warn};
+use procfs::process::{FDInfo, FDTarget, MMapPath, Process};
+use procfs::ProcError::*;
+use procfs::ProcResult;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+
+use crate::config::{default_grace_period, Config};
+use crate::index::{release_project_lock, CanonicalProjectName, Index, LockStatus::*};
+use crate::quorum::Quorum;
+
+/// How often watched processes are polled unless tuned otherwise
+pub const PID_POLLING_INTERVAL: Duration = Duration::new(1, 0); // maybe too long?
+
+/// Where the processes are inspected
+const PROC_ROOT: &str = "/proc";
+
+enum Probe {
+    Cwd(PathBuf),
+    /// procfs was not readable this time around
+    Retry,
+    /// The process is gone (or can't be inspected)
+    Gone,
+}
+
+struct Watch {
+    /// Project reference counter this watch holds one reference of
+    counter: Arc<AtomicU64>,
+    /// When the watch was registered (procfs may lag behind the lookup)
+    since: Instant,
+}
+
+/// Reference counts of every project, shared between the filesystem and the tracker
+pub type ProjectRefs = Arc<Mutex<FxHashMap<CanonicalProjectName, Arc<AtomicU64>>>>;
+
+/// Projects pinned from the control socket, each holding one reference on its project
+pub type PinnedProjects = Arc<Mutex<FxHashSet<CanonicalProjectName>>>;
+
+/// How long a project lock is kept after the project's last reference is dropped
+#[derive(Clone, Debug)]
+pub struct GracePeriods {
+    pub default: Duration,
+    pub projects: FxHashMap<CanonicalProjectName, Duration>,
+}
+
+impl GracePeriods {
+    pub fn from_config(config: &Config) -> GracePeriods {
+        GracePeriods {
+            default: Duration::from_secs(config.machine.grace_period),
+            projects: config
+                .projects
+                .iter()
+                .filter_map(|(name, project)| {
+                    project
+                        .grace_period
+                        .map(|secs| (PathBuf::from(name), Duration::from_secs(secs)))
+                })
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, project: &CanonicalProjectName) -> Duration {
+        *self.projects.get(project).unwrap_or(&self.default)
+    }
+}
+
+impl Default for GracePeriods {
+    fn default() -> GracePeriods {
+        GracePeriods {
+            default: Duration::from_secs(default_grace_period()),
+            projects: FxHashMap::default(),
+        }
+    }
+}
+
+/// Tracks which processes are working inside which project from a single
+/// polling thread, dropping their project reference once the process neither
+/// has its cwd in the project nor holds any file in it open or mmapped.
+/// The same thread releases project locks that stayed idle for their grace period.
+#[derive(Clone)]
+pub struct ProjectTracker {
+    watched: Arc<Mutex<FxHashMap<(u32, CanonicalProjectName), Watch>>>,
+    refs: ProjectRefs,
+    index: Arc<Mutex<Index>>,
+    grace_periods: Arc<Mutex<GracePeriods>>,
+    pinned: PinnedProjects,
+    /// When each locked project dropped to zero references
+    idle_since: Arc<Mutex<FxHashMap<CanonicalProjectName, Instant>>>,
+    polling_interval: Arc<Mutex<Duration>>,
+    /// Hands released locks back to the peers, from the runtime the quorum lives on
+    quorum: Arc<Mutex<Option<(Quorum, Handle)>>>,
+}
+
+impl ProjectTracker {
+    pub fn spawn(
+        mount_directory: PathBuf,
+        index: Arc<Mutex<Index>>,
+        refs: ProjectRefs,
+        polling_interval: Duration,
+    ) -> ProjectTracker {
+        let tracker = ProjectTracker {
+            watched: Arc::new(Mutex::new(FxHashMap::default())),
+            refs,
+            index,
+            grace_periods: Arc::new(Mutex::new(GracePeriods::default())),
+            pinned: Arc::new(Mutex::new(FxHashSet::default())),
+            idle_since: Arc::new(Mutex::new(FxHashMap::default())),
+            polling_interval: Arc::new(Mutex::new(polling_interval)),
+            quorum: Arc::new(Mutex::new(None)),
+        };
+        let polling = tracker.clone();
+        std::thread::Builder::new()
+            .name("nimbus-tracker".to_string())
+            .spawn(move || loop {
+                let polling_interval = *polling.polling_interval.lock().expect("lock failed");
+                std::thread::sleep(polling_interval);
+                poll(
+                    Path::new(PROC_ROOT),
+                    &mount_directory,
+                    &polling.watched,
+                    polling_interval,
+                );
+                polling.release_idle(Instant::now());
+            })
+            .expect("Unable to spawn project tracker");
+        tracker
+    }
+
+    pub fn set_grace_periods(&self, grace_periods: GracePeriods) {
+        *self.grace_periods.lock().expect("lock failed") = grace_periods;
+    }
+
+    // Must be called from within the tokio runtime
+    pub fn set_quorum(&self, quorum: Quorum) {
+        *self.quorum.lock().expect("lock failed") = Some((quorum, Handle::current()));
+    }
+
+    // Clears the lock locally, then tells the peers right away instead of at the next renewal
+    fn release(&self, project: &CanonicalProjectName) -> bool {
+        if !release_project_lock(Arc::clone(&self.index), project.clone()) {
+            return false;
+        }
+        if let Some((quorum, runtime)) = self.quorum.lock().expect("lock failed").clone() {
+            let project = project.clone();
+            runtime.spawn(async move { quorum.release(&project).await });
+        }
+        true
+    }
+
+    // Takes effect from the next tick on
+    pub fn set_polling_interval(&self, polling_interval: Duration) {
+        *self.polling_interval.lock().expect("lock failed") = polling_interval;
+    }
+
+    pub fn is_watching(&self, pid: u32, project: &CanonicalProjectName) -> bool {
+        let watched = self.watched.lock().expect("lock failed");
+        watched.contains_key(&(pid, project.clone()))
+    }
+
+    // Takes ownership of one reference on counter, released when pid leaves the project
+    pub fn watch(&self, pid: u32, project: CanonicalProjectName, counter: Arc<AtomicU64>) {
+        let mut watched = self.watched.lock().expect("lock failed");
+        let watch = Watch {
+            counter,
+            since: Instant::now(),
+        };
+        if let Some(previous) = watched.insert((pid, project.clone()), watch) {
+            // should not happen (callers check is_watching first), but don't leak the reference
+            dec_ref(&project, &previous.counter);
+        }
+    }
+
+    pub fn pinned(&self) -> PinnedProjects {
+        Arc::clone(&self.pinned)
+    }
+
+    // Holds a reference on project until it is unpinned, so it never goes idle
+    pub fn pin(&self, project: CanonicalProjectName) -> bool {
+        let mut pinned = self.pinned.lock().expect("lock failed");
+        if !pinned.insert(project.clone()) {
+            return false;
+        }
+        let counter = {
+            let mut refs = self.refs.lock().expect("lock failed");
+            Arc::clone(
+                refs.entry(project.clone())
+                    .or_insert_with(|| Arc::new(AtomicU64::new(0))),
+            )
+        };
+        let prev = counter.fetch_add(1, Ordering::SeqCst);
+        info!(
+            "pinned project {:?}, counter was at {}, now at {}",
+            project,
+            prev,
+            prev + 1
+        );
+        true
+    }
+
+    pub fn unpin(&self, project: &CanonicalProjectName) -> bool {
+        let mut pinned = self.pinned.lock().expect("lock failed");
+        if !pinned.remove(project) {
+            return false;
+        }
+        let refs = self.refs.lock().expect("lock failed");
+        if let Some(counter) = refs.get(project) {
+            dec_ref(project, counter);
+        }
+        true
+    }
+
+    fn ref_count(&self, project: &CanonicalProjectName) -> u64 {
+        let refs = self.refs.lock().expect("lock failed");
+        refs.get(project)
+            .map_or(0, |counter| counter.load(Ordering::SeqCst))
+    }
+
+    // Skips the grace period; refused while the project is still referenced
+    pub fn release_now(&self, project: &CanonicalProjectName) -> bool {
+        let refs = self.ref_count(project);
+        if refs > 0 {
+            warn!(
+                "not releasing lock on {:?}, it still has {} references",
+                project, refs
+            );
+            return false;
+        }
+        self.idle_since.lock().expect("lock failed").remove(project);
+        self.release(project)
+    }
+
+    // Takes the time from the caller, so the grace periods can be tested without waiting
+    fn release_idle(&self, now: Instant) {
+        let counts: Vec<(CanonicalProjectName, u64)> = {
+            let refs = self.refs.lock().expect("lock failed");
+            refs.iter()
+                .map(|(project, counter)| (project.clone(), counter.load(Ordering::SeqCst)))
+                .collect()
+        };
+        let grace_periods = self.grace_periods.lock().expect("lock failed").clone();
+        let mut idle_since = self.idle_since.lock().expect("lock failed");
+        for (project, count) in counts {
+            let locked = {
+                let index = self.index.lock().expect("lock failed");
+                matches!(index.project_lock.get(&project), Some(WeHaveLock(_)))
+            };
+            if count > 0 || !locked {
+                idle_since.remove(&project);
+                continue;
+            }
+            let since = *idle_since.entry(project.clone()).or_insert(now);
+            if now.duration_since(since) >= grace_periods.get(&project) {
+                idle_since.remove(&project);
+                if self.release(&project) {
+                    info!("released lock on {:?} after its grace period", project);
+                }
+            }
+        }
+    }
+}
+
+fn poll(
+    proc_root: &Path,
+    mount_directory: &Path,
+    watched: &Arc<Mutex<FxHashMap<(u32, CanonicalProjectName), Watch>>>,
+    polling_interval: Duration,
+) {
+    // group by pid so every process is only inspected once per tick
+    let mut by_pid: FxHashMap<u32, Vec<CanonicalProjectName>> = FxHashMap::default();
+    {
+        let watched = watched.lock().expect("lock failed");
+        for ((pid, project), watch) in watched.iter() {
+            // give the kernel time to update procfs (hacky!)
+            if watch.since.elapsed() >= polling_interval {
+                by_pid.entry(*pid).or_default().push(project.clone());
+            }
+        }
+    }
+
+    let mut finished = Vec::new();
+    for (pid, projects) in by_pid {
+        match probe(proc_root, pid) {
+            Probe::Cwd(cwd) => {
+                let mut open_paths = None;
+                for project in projects {
+                    let project_dir = mount_directory.join(&project);
+                    if cwd.starts_with(&project_dir) {
+                        continue;
+                    }
+                    // cwd is elsewhere, but the process may still hold files in the project
+                    let open_paths =
+                        open_paths.get_or_insert_with(|| pid_open_paths(proc_root, pid));
+                    if !open_paths.iter().any(|path| path.starts_with(&project_dir)) {
+                        info!("process {} no longer references project {:?}", pid, project);
+                        finished.push((pid, project));
+                    }
+                }
+            }
+            Probe::Retry => (),
+            Probe::Gone => finished.extend(projects.into_iter().map(|project| (pid, project))),
+        }
+    }
+
+    let mut watched = watched.lock().expect("lock failed");
+    for key in finished {
+        if let Some(watch) = watched.remove(&key) {
+            dec_ref(&key.1, &watch.counter);
+        }
+    }
+}
+
+fn process(proc_root: &Path, pid: u32) -> ProcResult<Process> {
+    Process::new_with_root(proc_root.join(pid.to_string()))
+}
+
+fn probe(proc_root: &Path, pid: u32) -> Probe {
+    let cwd = process(proc_root, pid).and_then(|process| process.cwd());
+    match cwd {
+        Ok(cwd) => Probe::Cwd(cwd),
+        Err(Incomplete(path)) => {
+            warn!("proc file at {:?} has incomplete contents", path);
+            Probe::Retry
+        }
+        Err(NotFound(path)) => {
+            info!("process not found at {:?}", path);
+            Probe::Gone
+        }
+        Err(PermissionDenied(path)) => {
+            error!(
+                "permission denied to snoop on process {} at path {:?}",
+                pid, path
+            );
+            Probe::Gone
+        }
+        Err(Io(err, path)) => {
+            error!("io error {:?} at {:?}", err, path);
+            Probe::Gone
+        }
+        Err(InternalError(err)) => {
+            error!("internal error {:?}", err);
+            Probe::Gone
+        }
+        Err(Other(err)) => {
+            error!("other error {:?}", err);
+            Probe::Gone
+        }
+    }
+}
+
+// Files the process holds open or has mmapped
+fn pid_open_paths(proc_root: &Path, pid: u32) -> Vec<PathBuf> {
+    let process = match process(proc_root, pid) {
+        Ok(process) => process,
+        Err(err) => {
+            warn!("unable to inspect process {}: {:?}", pid, err);
+            return Vec::new();
+        }
+    };
+
+    let mut paths = Vec::new();
+    match process.fd() {
+        Ok(fds) => paths.extend(fds.filter_map(|fd| match fd {
+            Ok(FDInfo {
+                target: FDTarget::Path(path),
+                ..
+            }) => Some(path),
+            _ => None,
+        })),
+        Err(err) => warn!("unable to list fds of process {}: {:?}", pid, err),
+    }
+    match process.maps() {
+        Ok(maps) => paths.extend(maps.into_iter().filter_map(|map| match map.pathname {
+            MMapPath::Path(path) => Some(path),
+            _ => None,
+        })),
+        Err(err) => warn!("unable to list mappings of process {}: {:?}", pid, err),
+    }
+    paths
+}
+
+fn dec_ref(project: &CanonicalProjectName, counter: &AtomicU64) {
+    let prev = counter.fetch_sub(1, Ordering::SeqCst);
+    info!(
+        "project counter for {:?} was at {}, now at {}",
+        project,
+        prev,
+        prev.wrapping_sub(1)
+    );
+    if prev == 0 {
+        error!("reference counting decrement failed/overflowed!");
+        counter.store(0, Ordering::SeqCst);
+    } else if prev == 1 {
+        info!("project {:?} is idle now", project);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    // A /proc/<pid> with a cwd, open files and mappings that point wherever the test says
+    fn fake_process(proc_root: &Path, pid: u32, cwd: &str, fds: &[&str], maps: &[&str]) {
+        let dir = proc_root.join(pid.to_string());
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("fd")).unwrap();
+        symlink(cwd, dir.join("cwd")).unwrap();
+        for (fd, target) in fds.iter().enumerate() {
+            symlink(target, dir.join("fd").join(fd.to_string())).unwrap();
+        }
+        let maps: String = maps
+            .iter()
+            .map(|path| {
+                format!(
+                    "7f0000000000-7f0000001000 r--p 00000000 00:2a 42 {}\n",
+                    path
+                )
+            })
+            .collect();
+        std::fs::write(dir.join("maps"), maps).unwrap();
+    }
+
+    type Watched = Arc<Mutex<FxHashMap<(u32, CanonicalProjectName), Watch>>>;
+
+    // Watches every pid in project, each with a reference on the returned counter
+    fn watch(pids: &[u32]) -> (Watched, Arc<AtomicU64>) {
+        let counter = Arc::new(AtomicU64::new(pids.len() as u64));
+        let watched = pids
+            .iter()
+            .map(|pid| {
+                let watch = Watch {
+                    counter: Arc::clone(&counter),
+                    since: Instant::now(),
+                };
+                ((*pid, PathBuf::from("project")), watch)
+            })
+            .collect();
+        (Arc::new(Mutex::new(watched)), counter)
+    }
+
+    fn watching(watched: &Watched) -> Vec<u32> {
+        let mut pids: Vec<u32> = watched
+            .lock()
+            .unwrap()
+            .keys()
+            .map(|(pid, _)| *pid)
+            .collect();
+        pids.sort();
+        pids
+    }
+
+    #[test]
+    fn processes_are_watched_while_their_cwd_is_in_the_project() {
+        let proc_root = tempfile::tempdir().unwrap();
+        let proc_root = proc_root.path();
+        let mount = Path::new("/mnt");
+        fake_process(proc_root, 100, "/mnt/project/src", &[], &[]);
+        fake_process(proc_root, 101, "/mnt/project-old", &[], &[]);
+        let (watched, counter) = watch(&[100, 101, 102]);
+
+        // 102 is gone, 101 is in a project that only shares a prefix
+        poll(proc_root, mount, &watched, Duration::ZERO);
+        assert_eq!(watching(&watched), [100]);
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+
+        fake_process(proc_root, 100, "/home", &[], &[]);
+        poll(proc_root, mount, &watched, Duration::ZERO);
+        assert!(watching(&watched).is_empty());
+        assert_eq!(counter.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn open_and_mapped_files_keep_processes_watched() {
+        let proc_root = tempfile::tempdir().unwrap();
+        let proc_root = proc_root.path();
+        let mount = Path::new("/mnt");
+        fake_process(
+            proc_root,
+            200,
+            "/home",
+            &["/dev/null", "/mnt/project/file"],
+            &[],
+        );
+        fake_process(proc_root, 201, "/home", &[], &["/mnt/project/lib.so"]);
+        fake_process(
+            proc_root,
+            202,
+            "/home",
+            &["/mnt/other/file"],
+            &["/usr/lib/libc.so"],
+        );
+        let (watched, counter) = watch(&[200, 201, 202]);
+
+        poll(proc_root, mount, &watched, Duration::ZERO);
+        assert_eq!(watching(&watched), [200, 201]);
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn new_watches_wait_for_procfs_to_catch_up() {
+        let proc_root = tempfile::tempdir().unwrap();
+        let (watched, counter) = watch(&[300]);
+
+        // the process looks gone, but it was only looked up a moment ago
+        poll(
+            proc_root.path(),
+            Path::new("/mnt"),
+            &watched,
+            Duration::from_secs(3600),
+        );
+        assert_eq!(watching(&watched), [300]);
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    }
+
+    fn tracker(projects: &[&str]) -> ProjectTracker {
+        let mut index = Index::new();
+        let refs = projects
+            .iter()
+            .map(|project| {
+                index.register_project(PathBuf::from(project));
+                index
+                    .project_lock
+                    .insert(PathBuf::from(project), WeHaveLock("a".to_string()));
+                (PathBuf::from(project), Arc::new(AtomicU64::new(0)))
+            })
+            .collect();
+        // never polls on its own, the test calls release_idle
+        ProjectTracker::spawn(
+            PathBuf::from("/mnt"),
+            Arc::new(Mutex::new(index)),
+            Arc::new(Mutex::new(refs)),
+            Duration::from_secs(3600),
+        )
+    }
+
+    fn locked(tracker: &ProjectTracker, project: &str) -> bool {
+        matches!(
+            tracker.index.lock().unwrap().project_lock[&PathBuf::from(project)],
+            WeHaveLock(_)
+        )
+    }
+
+    #[test]
+    fn idle_locks_are_released_after_their_grace_period() {
+        let tracker = tracker(&["idle", "busy", "pinned", "patient"]);
+        tracker.set_grace_periods(GracePeriods {
+            default: Duration::from_secs(10),
+            projects: [(PathBuf::from("patient"), Duration::from_secs(60))]
+                .into_iter()
+                .collect(),
+        });
+        tracker.refs.lock().unwrap()[&PathBuf::from("busy")].store(1, Ordering::SeqCst);
+        assert!(tracker.pin(PathBuf::from("pinned")));
+
+        let start = Instant::now();
+        tracker.release_idle(start);
+        tracker.release_idle(start + Duration::from_secs(9));
+        assert!(locked(&tracker, "idle"));
+
+        tracker.release_idle(start + Duration::from_secs(10));
+        assert!(!locked(&tracker, "idle"));
+        assert!(locked(&tracker, "busy"));
+        assert!(locked(&tracker, "pinned"));
+        assert!(locked(&tracker, "patient"));
+
+        tracker.release_idle(start + Duration::from_secs(60));
+        assert!(!locked(&tracker, "patient"));
+    }
+
+    #[test]
+    fn grace_periods_start_over_once_a_project_is_used() {
+        let tracker = tracker(&["project"]);
+        tracker.set_grace_periods(GracePeriods {
+            default: Duration::from_secs(10),
+            ..GracePeriods::default()
+        });
+        let counter = Arc::clone(&tracker.refs.lock().unwrap()[&PathBuf::from("project")]);
+
+        let start = Instant::now();
+        tracker.release_idle(start);
+        counter.store(1, Ordering::SeqCst);
+        tracker.release_idle(start + Duration::from_secs(5));
+        counter.store(0, Ordering::SeqCst);
+        tracker.release_idle(start + Duration::from_secs(6));
+        tracker.release_idle(start + Duration::from_secs(15));
+        assert!(locked(&tracker, "project"));
+
+        tracker.release_idle(start + Duration::from_secs(16));
+        assert!(!locked(&tracker, "project"));
+    }
+}
@@ -0,0 +1,158 @@
+use nimbus::auth::sign_announcement;
+use nimbus::config::{read_config, Config};
+use nimbus::discovery::{DiscoveredPeers, Discovery};
+use std::net::UdpSocket;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::watch;
+
+const SECRET: &str = "discovery-test-secret";
+
+// Any port nobody else is using right now
+fn free_port() -> u16 {
+    UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn config(dir: &Path, machine: &str, port: u16, cluster: &str, secret: &str) -> Config {
+    let path = dir.join(format!("{}.toml", machine));
+    std::fs::write(
+        &path,
+        format!(
+            r#"
+[machine]
+name = "{}"
+mode = "DevelopmentMode"
+endpoint = "0.0.0.0:5000"
+
+[discovery]
+cluster = "{}"
+secret = "{}"
+command = "cp -r {{HERE}} {{THERE}}"
+address = "239.255.77.77:{}"
+interface = "127.0.0.1"
+interval = 1
+"#,
+            machine, cluster, secret, port
+        ),
+    )
+    .unwrap();
+    read_config(path).unwrap()
+}
+
+// Starts an instance on loopback, returning what it discovers
+fn spawn(
+    dir: &Path,
+    machine: &str,
+    port: u16,
+    cluster: &str,
+    secret: &str,
+) -> watch::Receiver<DiscoveredPeers> {
+    let config = config(dir, machine, port, cluster, secret);
+    let discovery = Discovery::new(
+        config.discovery.as_ref().unwrap(),
+        &config.machine,
+        dir.join(machine),
+    )
+    .unwrap();
+    let discovered = discovery.subscribe();
+    tokio::spawn(discovery.run());
+    discovered
+}
+
+async fn wait_for(
+    discovered: &mut watch::Receiver<DiscoveredPeers>,
+    done: impl Fn(&DiscoveredPeers) -> bool,
+) -> DiscoveredPeers {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            if done(&discovered.borrow()) {
+                return discovered.borrow().clone();
+            }
+            discovered.changed().await.unwrap();
+        }
+    })
+    .await
+    .expect("peers were not discovered in time")
+}
+
+#[tokio::test]
+async fn discovers_instances_of_the_same_cluster() {
+    let dir = tempfile::tempdir().unwrap();
+    let port = free_port();
+    let mut first = spawn(dir.path(), "first", port, "test", SECRET);
+    let mut second = spawn(dir.path(), "second", port, "test", SECRET);
+    spawn(dir.path(), "stranger", port, "other", SECRET);
+    spawn(dir.path(), "impostor", port, "test", "wrong-secret");
+
+    let peers = wait_for(&mut first, |peers| peers.contains_key("second")).await;
+    let peer = &peers["second"];
+    // announced as 0.0.0.0, reachable where the announcement came from
+    assert_eq!(peer.endpoint, "127.0.0.1:5000");
+    assert_eq!(peer.secret.as_deref(), Some(SECRET));
+    assert_eq!(peer.storage, Some(dir.path().join("second")));
+    let peers = wait_for(&mut second, |peers| peers.contains_key("first")).await;
+    assert!(!peers.contains_key("second"));
+
+    // give the others a few announcements to (not) get through
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    for discovered in [&first, &second] {
+        let peers = discovered.borrow();
+        assert!(!peers.contains_key("stranger"));
+        assert!(!peers.contains_key("impostor"));
+    }
+}
+
+// What machine would announce at timestamp, listening on 0.0.0.0
+fn announcement(machine: &str, timestamp: i64) -> Vec<u8> {
+    let payload = serde_json::json!({
+        "cluster": "test",
+        "machine": machine,
+        "endpoint": "0.0.0.0:5000",
+        "storage": null,
+        "tls": false,
+        "timestamp": timestamp,
+    })
+    .to_string();
+    serde_json::to_vec(&serde_json::json!({
+        "signature": sign_announcement(SECRET, payload.as_bytes()),
+        "payload": payload,
+    }))
+    .unwrap()
+}
+
+#[tokio::test]
+async fn replayed_announcements_do_not_redirect_peers() {
+    let dir = tempfile::tempdir().unwrap();
+    let port = free_port();
+    let mut first = spawn(dir.path(), "first", port, "test", SECRET);
+    let send = |packet: &[u8], from: &str| {
+        UdpSocket::bind((from, 0))
+            .unwrap()
+            .send_to(packet, ("127.0.0.1", port))
+            .unwrap();
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    let packet = announcement("second", now);
+    send(&packet, "127.0.0.2");
+    let peers = wait_for(&mut first, |peers| peers.contains_key("second")).await;
+    assert_eq!(peers["second"].endpoint, "127.0.0.2:5000");
+
+    // the same announcement (or an older one) sent again from elsewhere is ignored
+    send(&packet, "127.0.0.3");
+    send(&announcement("second", now - 1), "127.0.0.3");
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(first.borrow()["second"].endpoint, "127.0.0.2:5000");
+
+    // while a fresh one moves the peer
+    send(&announcement("second", now + 1), "127.0.0.3");
+    let peers = wait_for(&mut first, |peers| {
+        peers["second"].endpoint == "127.0.0.3:5000"
+    })
+    .await;
+    assert_eq!(peers.len(), 1);
+}
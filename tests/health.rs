@@ -0,0 +1,154 @@
+use nimbus::auth::PeerSecrets;
+use nimbus::config::{read_config, TuningConfig};
+use nimbus::health::{Prober, Reachability};
+use nimbus::index::Index;
+use nimbus::server;
+use nimbus::status::Status;
+use nimbus::sync::Syncer;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+const SECRET: &str = "health-test-secret";
+
+// A port nothing listens on (the OS picks it, so parallel tests never collide)
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+// Waits for the server spawned on port to accept connections
+async fn listening(port: u16) {
+    for _ in 0..500 {
+        if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("nothing listens on port {}", port);
+}
+
+// A prober on "client" knowing an up peer, a down one and one without a secret
+fn prober(dir: &Path, up: u16, down: u16) -> Prober {
+    let path = dir.join("client.toml");
+    std::fs::write(
+        &path,
+        format!(
+            r#"
+[machine]
+name = "client"
+mode = "DevelopmentMode"
+endpoint = "127.0.0.1:0"
+
+[network.up]
+command = "cp -r {{HERE}} {{THERE}}"
+endpoint = "127.0.0.1:{}"
+secret = "{}"
+
+[network.down]
+command = "cp -r {{HERE}} {{THERE}}"
+endpoint = "127.0.0.1:{}"
+secret = "{}"
+
+[network.silent]
+command = "cp -r {{HERE}} {{THERE}}"
+endpoint = "127.0.0.1:{}"
+"#,
+            up, SECRET, down, SECRET, down
+        ),
+    )
+    .unwrap();
+    let config = read_config(path).unwrap();
+    let tuning = TuningConfig {
+        probe_timeout_ms: 500,
+        ..TuningConfig::default()
+    };
+    let syncer = Syncer::new(
+        &config,
+        dir.to_path_buf(),
+        Arc::new(Mutex::new(FxHashMap::default())),
+    );
+    Prober::new(syncer, Arc::new(Mutex::new(tuning)))
+}
+
+// Serves the peer API on 127.0.0.1:port, answering only "client"
+async fn serve(dir: &Path, port: u16) {
+    let index = Arc::new(Mutex::new(Index::new()));
+    let activity = Arc::new(Mutex::new(FxHashMap::default()));
+    let status = Status::new(
+        index.clone(),
+        Arc::new(Mutex::new(FxHashMap::default())),
+        Arc::clone(&activity),
+        Arc::new(Mutex::new(FxHashSet::default())),
+        Arc::new(Mutex::new(FxHashMap::default())),
+    );
+    let syncer = Syncer::new(
+        &read_config(dir.join("client.toml")).unwrap(),
+        dir.to_path_buf(),
+        activity,
+    );
+    let secrets: PeerSecrets = Arc::new(RwLock::new(HashMap::from([(
+        "client".to_string(),
+        SECRET.to_string(),
+    )])));
+    let prober = Prober::new(
+        syncer.clone(),
+        Arc::new(Mutex::new(TuningConfig::default())),
+    );
+    tokio::spawn(server::build(
+        server::routes(index, status, prober, secrets, syncer),
+        format!("127.0.0.1:{}", port),
+        None,
+        std::future::pending(),
+    ));
+    listening(port).await;
+}
+
+#[tokio::test]
+async fn tracks_which_peers_answer() {
+    let dir = tempfile::tempdir().unwrap();
+    let (up, down) = (free_port(), free_port());
+    let prober = prober(dir.path(), up, down);
+    serve(dir.path(), up).await;
+
+    for peer in prober.peers() {
+        assert_eq!(peer.reachability, Reachability::Unknown);
+    }
+    prober.probe().await;
+
+    let peers: HashMap<String, _> = prober
+        .peers()
+        .into_iter()
+        .map(|peer| (peer.name.clone(), peer))
+        .collect();
+    assert_eq!(peers["up"].reachability, Reachability::Up);
+    assert!(peers["up"].latency_ms.is_some());
+    assert!(peers["up"].last_seen.is_some());
+    assert_eq!(peers["down"].reachability, Reachability::Down);
+    assert!(peers["down"].error.is_some());
+    // without a secret it can't be asked
+    assert_eq!(peers["silent"].reachability, Reachability::Unknown);
+    assert_eq!(prober.reachability("up"), Reachability::Up);
+    assert_eq!(prober.reachability("nobody"), Reachability::Unknown);
+}
+
+#[tokio::test]
+async fn probes_a_single_peer_on_demand() {
+    let dir = tempfile::tempdir().unwrap();
+    let (up, down) = (free_port(), free_port());
+    let prober = prober(dir.path(), up, down);
+    serve(dir.path(), up).await;
+
+    assert_eq!(prober.probe_peer("up").await, Reachability::Up);
+    assert_eq!(prober.reachability("up"), Reachability::Up);
+    // the others wait for the next round
+    assert_eq!(prober.reachability("down"), Reachability::Unknown);
+    assert_eq!(prober.probe_peer("down").await, Reachability::Down);
+    assert_eq!(prober.probe_peer("silent").await, Reachability::Unknown);
+}
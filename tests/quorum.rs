@@ -0,0 +1,618 @@
+use nimbus::auth::PeerSecrets;
+use nimbus::config::{read_config, TuningConfig};
+use nimbus::control::{self, Control, ControlRequest, ControlResponse};
+use nimbus::discovery::DiscoveredPeers;
+use nimbus::health::Prober;
+use nimbus::index::{Index, LockError, LockStatus, LockStatus::*};
+use nimbus::quorum::Quorum;
+use nimbus::reload::Reloader;
+use nimbus::server;
+use nimbus::status::Status;
+use nimbus::sync::Syncer;
+use nimbus::tracker::{GracePeriods, ProjectTracker};
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::collections::HashMap;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, Notify};
+
+const SECRET: &str = "quorum-test-secret";
+const MACHINES: [&str; 3] = ["a", "b", "c"];
+
+// A port nothing listens on (the OS picks it, so parallel tests never collide)
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+// Waits for the server spawned on port to accept connections
+async fn listening(port: u16) {
+    for _ in 0..500 {
+        if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("nothing listens on port {}", port);
+}
+
+// Forwards a fresh 127.0.0.1 port (returned along with the switch) to 127.0.0.1:target
+// while the link is up, and drops every connection (open ones included) while it is down
+async fn link(target: u16) -> (watch::Sender<bool>, u16) {
+    let (up, _) = watch::channel(true);
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let state = up.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let (mut inbound, _) = listener.accept().await.unwrap();
+            let mut state = state.clone();
+            if !*state.borrow_and_update() {
+                continue;
+            }
+            tokio::spawn(async move {
+                let mut outbound = match TcpStream::connect(("127.0.0.1", target)).await {
+                    Ok(outbound) => outbound,
+                    Err(_) => return,
+                };
+                tokio::select! {
+                    _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound) => (),
+                    _ = state.wait_for(|up| !*up) => (),
+                }
+            });
+        }
+    });
+    (up, port)
+}
+
+// Accepts connections on a fresh 127.0.0.1 port and never answers on them
+async fn blackhole() -> u16 {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        loop {
+            let (inbound, _) = listener.accept().await.unwrap();
+            held.push(inbound);
+        }
+    });
+    port
+}
+
+/// Three machines on loopback, every one of them talking to the others through a link
+/// that can be cut
+struct Cluster {
+    indexes: HashMap<&'static str, Arc<Mutex<Index>>>,
+    quorums: HashMap<&'static str, Quorum>,
+    reloaders: HashMap<&'static str, Reloader>,
+    trackers: HashMap<&'static str, ProjectTracker>,
+    controls: HashMap<&'static str, Control>,
+    probers: HashMap<&'static str, Prober>,
+    /// What each machine finds on the network, when peers are discovered rather than configured
+    discovered: HashMap<&'static str, DiscoveredPeers>,
+    links: HashMap<(&'static str, &'static str), watch::Sender<bool>>,
+    _dir: tempfile::TempDir,
+}
+
+impl Cluster {
+    // Every machine serves on a free port and reaches each other one through its own link;
+    // discovering leaves [network] empty, for discover() to fill in
+    async fn start(discovering: bool) -> Cluster {
+        let dir = tempfile::tempdir().unwrap();
+        let ports: Vec<u16> = MACHINES.iter().map(|_| free_port()).collect();
+        let mut indexes = HashMap::new();
+        let mut quorums = HashMap::new();
+        let mut reloaders = HashMap::new();
+        let mut trackers = HashMap::new();
+        let mut controls = HashMap::new();
+        let mut probers = HashMap::new();
+        let mut discovered = HashMap::new();
+        let mut links = HashMap::new();
+        for (i, machine) in MACHINES.iter().enumerate() {
+            let mut network = String::new();
+            for (j, peer) in MACHINES.iter().enumerate() {
+                if i == j {
+                    continue;
+                }
+                let (up, port) = link(ports[j]).await;
+                links.insert((*machine, *peer), up);
+                network.push_str(&format!(
+                    "[network.{}]\ncommand = \"cp -r {{HERE}} {{THERE}}\"\nendpoint = \"127.0.0.1:{}\"\nsecret = \"{}\"\n\n",
+                    peer, port, SECRET
+                ));
+            }
+            let path = dir.path().join(format!("{}.toml", machine));
+            std::fs::write(
+                &path,
+                format!(
+                    "[machine]\nname = \"{}\"\nmode = \"DevelopmentMode\"\nendpoint = \"127.0.0.1:{}\"\n\n{}",
+                    machine, ports[i], network
+                ),
+            )
+            .unwrap();
+            let mut config = read_config(path.clone()).unwrap();
+            if discovering {
+                discovered.insert(*machine, std::mem::take(&mut config.network));
+                std::fs::write(
+                    &path,
+                    format!(
+                        "[machine]\nname = \"{}\"\nmode = \"DevelopmentMode\"\nendpoint = \"127.0.0.1:{}\"\n",
+                        machine, ports[i]
+                    ),
+                )
+                .unwrap();
+            }
+
+            let mut index = Index::new();
+            index.register_project(project());
+            let index = Arc::new(Mutex::new(index));
+            let activity = Arc::new(Mutex::new(FxHashMap::default()));
+            // the project was used before, and nothing uses it now
+            let refs = Arc::new(Mutex::new(
+                [(project(), Arc::new(AtomicU64::new(0)))]
+                    .into_iter()
+                    .collect::<FxHashMap<_, _>>(),
+            ));
+            let status = Status::new(
+                index.clone(),
+                Arc::clone(&refs),
+                Arc::clone(&activity),
+                Arc::new(Mutex::new(FxHashSet::default())),
+                Arc::new(Mutex::new(FxHashMap::default())),
+            );
+            let syncer = Syncer::new(&config, dir.path().to_path_buf(), activity);
+            let secrets: PeerSecrets = Arc::new(RwLock::new(
+                MACHINES
+                    .iter()
+                    .filter(|peer| *peer != machine)
+                    .map(|peer| (peer.to_string(), SECRET.to_string()))
+                    .collect(),
+            ));
+            let tuning = Arc::new(Mutex::new(TuningConfig {
+                probe_timeout_ms: 500,
+                ..TuningConfig::default()
+            }));
+            let prober = Prober::new(syncer.clone(), Arc::clone(&tuning));
+            tokio::spawn(server::build(
+                server::routes(
+                    index.clone(),
+                    status.clone(),
+                    prober.clone(),
+                    secrets.clone(),
+                    syncer.clone(),
+                ),
+                format!("127.0.0.1:{}", ports[i]),
+                None,
+                std::future::pending(),
+            ));
+            let quorum = Quorum::new(
+                machine.to_string(),
+                index.clone(),
+                syncer.clone(),
+                Arc::clone(&tuning),
+            );
+            let tracker = ProjectTracker::spawn(
+                dir.path().join("mount"),
+                index.clone(),
+                refs,
+                Duration::from_millis(100),
+            );
+            tracker.set_quorum(quorum.clone());
+            trackers.insert(*machine, tracker.clone());
+            let reloader = Reloader::new(
+                path,
+                config,
+                index.clone(),
+                tracker.clone(),
+                tuning,
+                secrets,
+                syncer.clone(),
+                quorum.clone(),
+            );
+            controls.insert(
+                *machine,
+                Control::new(
+                    quorum.clone(),
+                    index.clone(),
+                    tracker,
+                    status,
+                    syncer,
+                    reloader.clone(),
+                    prober.clone(),
+                    Arc::new(Notify::new()),
+                ),
+            );
+            probers.insert(*machine, prober);
+            reloaders.insert(*machine, reloader);
+            quorums.insert(*machine, quorum);
+            indexes.insert(*machine, index);
+        }
+        for port in ports {
+            listening(port).await;
+        }
+        Cluster {
+            indexes,
+            quorums,
+            reloaders,
+            trackers,
+            controls,
+            probers,
+            discovered,
+            links,
+            _dir: dir,
+        }
+    }
+
+    // Cuts machine off from every other one, both ways
+    async fn isolate(&self, machine: &str) {
+        for ((from, to), up) in &self.links {
+            if *from == machine || *to == machine {
+                up.send_replace(false);
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    async fn heal(&self) {
+        for up in self.links.values() {
+            up.send_replace(true);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    // Machine sees every other one announce itself
+    fn discover(&self, machine: &str) {
+        self.reloaders[machine].set_discovered(self.discovered[machine].clone());
+    }
+
+    fn lock(&self, machine: &str) -> LockStatus {
+        self.indexes[machine].lock().unwrap().project_lock[&project()].clone()
+    }
+}
+
+fn project() -> PathBuf {
+    PathBuf::from("project")
+}
+
+fn holder(result: Result<impl Sized, LockError>) -> Option<String> {
+    match result {
+        Err(LockError::Held(response)) => response.holder,
+        _ => None,
+    }
+}
+
+#[tokio::test]
+async fn majority_grants_the_lock_with_a_machine_offline() {
+    let cluster = Cluster::start(false).await;
+    cluster.isolate("c").await;
+
+    let response = cluster.quorums["a"]
+        .acquire(&project(), false)
+        .await
+        .unwrap();
+    assert_eq!(response.holder.as_deref(), Some("a"));
+    assert_eq!(response.epoch, 1);
+    assert_eq!(cluster.lock("a"), WeHaveLock("a".to_string()));
+    assert_eq!(cluster.lock("b"), SomeoneHasLock("a".to_string()));
+    assert_eq!(cluster.lock("c"), NobodyHasLock);
+
+    // on its own, c is a minority, even when stealing
+    for steal in [false, true] {
+        assert_eq!(
+            cluster.quorums["c"].acquire(&project(), steal).await,
+            Err(LockError::NoQuorum {
+                agreed: 1,
+                needed: 2
+            })
+        );
+    }
+    assert_eq!(cluster.lock("c"), NobodyHasLock);
+
+    // once back, it learns who holds the lock
+    cluster.heal().await;
+    assert_eq!(
+        holder(cluster.quorums["c"].acquire(&project(), false).await).as_deref(),
+        Some("a")
+    );
+}
+
+#[tokio::test]
+async fn minority_cannot_lock() {
+    let cluster = Cluster::start(false).await;
+    cluster.isolate("a").await;
+    cluster.isolate("b").await;
+
+    assert!(matches!(
+        cluster.quorums["a"].acquire(&project(), false).await,
+        Err(LockError::NoQuorum { .. })
+    ));
+    for machine in MACHINES {
+        assert_eq!(cluster.lock(machine), NobodyHasLock);
+    }
+}
+
+#[tokio::test]
+async fn epochs_fence_a_holder_that_was_cut_off() {
+    let cluster = Cluster::start(false).await;
+    assert_eq!(
+        cluster.quorums["a"]
+            .acquire(&project(), false)
+            .await
+            .unwrap()
+            .epoch,
+        1
+    );
+
+    // a disappears while holding the lock, and the others agree to steal it
+    cluster.isolate("a").await;
+    assert_eq!(
+        holder(cluster.quorums["b"].acquire(&project(), false).await).as_deref(),
+        Some("a")
+    );
+    let stolen = cluster.quorums["b"]
+        .acquire(&project(), true)
+        .await
+        .unwrap();
+    assert_eq!(stolen.holder.as_deref(), Some("b"));
+    assert_eq!(stolen.epoch, 2);
+    assert_eq!(cluster.lock("c"), SomeoneHasLock("b".to_string()));
+
+    // cut off, a can't renew, but holds on until its lease would run out
+    cluster.quorums["a"].renew().await;
+    assert_eq!(cluster.lock("a"), WeHaveLock("a".to_string()));
+
+    // back, its renewal at epoch 1 is refused and it learns who moved on
+    cluster.heal().await;
+    cluster.quorums["a"].renew().await;
+    assert_eq!(cluster.lock("a"), SomeoneHasLock("b".to_string()));
+    assert_eq!(
+        holder(cluster.quorums["a"].acquire(&project(), false).await).as_deref(),
+        Some("b")
+    );
+    for machine in ["b", "c"] {
+        let index = cluster.indexes[machine].lock().unwrap();
+        assert_eq!(index.project_lease[&project()].epoch, 2);
+    }
+}
+
+#[tokio::test]
+async fn released_locks_are_handed_back() {
+    let cluster = Cluster::start(false).await;
+    cluster.quorums["a"]
+        .acquire(&project(), false)
+        .await
+        .unwrap();
+
+    // the peers hear of it right away, not at the next renewal
+    assert!(cluster.trackers["a"].release_now(&project()));
+    for _ in 0..500 {
+        if MACHINES
+            .iter()
+            .all(|machine| cluster.lock(machine) == NobodyHasLock)
+        {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    for machine in MACHINES {
+        assert_eq!(cluster.lock(machine), NobodyHasLock);
+    }
+
+    let response = cluster.quorums["c"]
+        .acquire(&project(), false)
+        .await
+        .unwrap();
+    assert_eq!(response.holder.as_deref(), Some("c"));
+    assert_eq!(response.epoch, 2);
+}
+
+#[tokio::test]
+async fn locks_taken_from_the_cli_outlast_the_grace_period() {
+    let cluster = Cluster::start(false).await;
+    cluster.trackers["a"].set_grace_periods(GracePeriods {
+        default: Duration::from_millis(100),
+        ..GracePeriods::default()
+    });
+    let control = &cluster.controls["a"];
+    let lock = || ControlRequest::Lock {
+        project: "project".to_string(),
+    };
+    let release = || ControlRequest::Release {
+        project: "project".to_string(),
+    };
+
+    assert!(matches!(
+        control.handle(lock()).await,
+        ControlResponse::Lock(_)
+    ));
+    // nothing uses the project, the tracker had several chances to give it back
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(cluster.lock("a"), WeHaveLock("a".to_string()));
+
+    assert!(matches!(
+        control.handle(release()).await,
+        ControlResponse::Done(_)
+    ));
+    assert_eq!(cluster.lock("a"), NobodyHasLock);
+    assert_eq!(cluster.lock("b"), NobodyHasLock);
+    assert!(matches!(
+        control.handle(release()).await,
+        ControlResponse::Error(_)
+    ));
+
+    // a lock pinned on its own stays pinned once released
+    assert!(matches!(
+        control
+            .handle(ControlRequest::Pin {
+                project: "project".to_string()
+            })
+            .await,
+        ControlResponse::Done(_)
+    ));
+    assert!(matches!(
+        control.handle(lock()).await,
+        ControlResponse::Lock(_)
+    ));
+    assert!(matches!(
+        control.handle(release()).await,
+        ControlResponse::Error(_)
+    ));
+    assert_eq!(cluster.lock("a"), WeHaveLock("a".to_string()));
+}
+
+#[tokio::test]
+async fn only_holders_that_are_down_are_stolen_from() {
+    let cluster = Cluster::start(false).await;
+    cluster.quorums["b"]
+        .acquire(&project(), false)
+        .await
+        .unwrap();
+    let steal = |confirmed| ControlRequest::Steal {
+        project: "project".to_string(),
+        confirmed,
+    };
+    let control = &cluster.controls["a"];
+
+    // b was never pinged, so it is pinged first, and answers
+    assert!(matches!(
+        control.handle(steal(true)).await,
+        ControlResponse::Error(err) if err.contains("reachable")
+    ));
+
+    cluster.isolate("b").await;
+    cluster.probers["a"].probe().await;
+    assert!(matches!(
+        control.handle(steal(false)).await,
+        ControlResponse::Confirm(_)
+    ));
+    match control.handle(steal(true)).await {
+        ControlResponse::Lock(response) => assert_eq!(response.holder.as_deref(), Some("a")),
+        response => panic!("not stolen: {:?}", response),
+    }
+
+    // a holder that can't be pinged at all is never stolen from
+    cluster.indexes["c"]
+        .lock()
+        .unwrap()
+        .lend_project_lock(&project(), "z".to_string(), Some(9), true)
+        .unwrap();
+    assert!(matches!(
+        cluster.controls["c"].handle(steal(true)).await,
+        ControlResponse::Error(err) if err.contains("unable to tell")
+    ));
+}
+
+#[tokio::test]
+async fn control_socket_is_private_from_the_start() {
+    let cluster = Cluster::start(false).await;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("nimbus.sock");
+    // a socket left behind by an earlier run
+    std::os::unix::net::UnixListener::bind(&path).unwrap();
+    tokio::spawn(control::serve(cluster.controls["a"].clone(), path.clone()));
+
+    let request = path.clone();
+    let response = tokio::task::spawn_blocking(move || {
+        for _ in 0..100 {
+            if let Ok(response) = control::request(&request, &ControlRequest::Peers) {
+                return response;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("nobody listens on {:?}", request);
+    })
+    .await
+    .unwrap();
+    assert!(matches!(response, ControlResponse::Peers(peers) if peers.len() == 2));
+
+    let metadata = std::fs::symlink_metadata(&path).unwrap();
+    assert!(metadata.file_type().is_socket());
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    // nothing is left of where it was bound
+    let entries: Vec<_> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(entries, ["nimbus.sock"]);
+}
+
+#[tokio::test]
+async fn discovered_peers_keep_their_vote_when_partitioned() {
+    let cluster = Cluster::start(true).await;
+    for machine in MACHINES {
+        cluster.discover(machine);
+    }
+    assert_eq!(cluster.quorums["a"].members(), ["b", "c"]);
+
+    // cut off, a stops hearing b and c announce themselves and forgets them
+    cluster.isolate("a").await;
+    cluster.reloaders["a"].set_discovered(DiscoveredPeers::new());
+    assert_eq!(cluster.quorums["a"].members(), ["b", "c"]);
+    for steal in [false, true] {
+        assert_eq!(
+            cluster.quorums["a"].acquire(&project(), steal).await,
+            Err(LockError::NoQuorum {
+                agreed: 1,
+                needed: 2
+            })
+        );
+    }
+    assert_eq!(cluster.lock("a"), NobodyHasLock);
+
+    // while the majority goes on locking
+    let response = cluster.quorums["b"]
+        .acquire(&project(), false)
+        .await
+        .unwrap();
+    assert_eq!(response.holder.as_deref(), Some("b"));
+
+    // only an explicit reload drops them from the vote
+    cluster.reloaders["a"].reload().unwrap();
+    assert!(cluster.quorums["a"].members().is_empty());
+}
+
+#[tokio::test]
+async fn blackholed_peers_do_not_stall_the_lock() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("a.toml");
+    std::fs::write(
+        &path,
+        format!(
+            "[machine]\nname = \"a\"\nmode = \"DevelopmentMode\"\nendpoint = \"127.0.0.1:0\"\n\n\
+             [network.b]\ncommand = \"cp -r {{HERE}} {{THERE}}\"\nendpoint = \"127.0.0.1:{}\"\nsecret = \"{}\"\n",
+            blackhole().await,
+            SECRET
+        ),
+    )
+    .unwrap();
+    let config = read_config(path).unwrap();
+    let mut index = Index::new();
+    index.register_project(project());
+    let index = Arc::new(Mutex::new(index));
+    let syncer = Syncer::new(
+        &config,
+        dir.path().to_path_buf(),
+        Arc::new(Mutex::new(FxHashMap::default())),
+    );
+    let tuning = Arc::new(Mutex::new(TuningConfig {
+        probe_timeout_ms: 200,
+        ..TuningConfig::default()
+    }));
+    let quorum = Quorum::new("a".to_string(), index.clone(), syncer, tuning);
+
+    // with two machines, a peer that doesn't answer doesn't refuse either
+    let taken = tokio::time::timeout(Duration::from_secs(2), quorum.acquire(&project(), false))
+        .await
+        .expect("acquire waited on the silent peer");
+    assert_eq!(taken.unwrap().holder.as_deref(), Some("a"));
+    tokio::time::timeout(Duration::from_secs(2), quorum.release(&project()))
+        .await
+        .expect("release waited on the silent peer");
+}
@@ -1,43 +1,99 @@
 use fuser::{BackgroundSession, MountOption, Session};
+use nimbus::config::TuningConfig;
 use nimbus::files::NimbusFS;
+use nimbus::index::Index;
 use nimbus::server;
+use nimbus::status::Status;
+use nix::errno::Errno;
+use nix::fcntl::{renameat2, RenameFlags};
+use nix::unistd::{access, setfsgid, setfsuid, truncate, AccessFlags, Gid, Uid};
+use std::ffi::CString;
 use std::fs;
-use std::path::PathBuf;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirEntryExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tempfile::TempDir;
 
 struct NimbusTester {
     bg: BackgroundSession,
     local_storage: PathBuf,
     mount_directory: PathBuf,
+    index: Arc<Mutex<Index>>,
+    status: Status,
 }
 
 impl NimbusTester {
     fn new() -> NimbusTester {
+        NimbusTester::mount(true)
+    }
+
+    // Without default_permissions the kernel leaves access() and the mode bits to nimbus,
+    // for every user
+    fn mount(default_permissions: bool) -> NimbusTester {
         let local_storage: TempDir = tempfile::tempdir().unwrap();
         let mount_directory: TempDir = tempfile::tempdir().unwrap();
 
         let (store_p, mount_p) = (local_storage.into_path(), mount_directory.into_path());
-        let nimbus = NimbusFS::default(store_p.clone(), mount_p.clone());
-
-        let session = Session::new(
-            nimbus,
-            &mount_p,
-            &[
-                MountOption::DefaultPermissions,
-                MountOption::DirSync,
-                MountOption::Sync,
-                MountOption::NoAtime,
-            ],
-        )
-        .expect("Could not create session");
+        // the tracker never runs during a test, so the refs only move with the calls made
+        let tuning = TuningConfig {
+            polling_interval_ms: 3_600_000,
+            ..TuningConfig::default()
+        };
+        let mut nimbus = NimbusFS::new(store_p.clone(), mount_p.clone(), &tuning).unwrap();
+        nimbus.set_default_permissions(default_permissions);
+        let (index, status) = (nimbus.index(), nimbus.status());
+
+        let mut options = vec![MountOption::NoAtime];
+        if default_permissions {
+            options.push(MountOption::DefaultPermissions);
+        } else {
+            // or fuser itself turns away every other user
+            options.push(MountOption::AllowOther);
+        }
+        let session = Session::new(nimbus, &mount_p, &options).expect("Could not create session");
         let mut bg = session.spawn().expect("Session failed to spawn");
 
         NimbusTester {
             bg: bg,
             local_storage: store_p,
             mount_directory: mount_p,
+            index,
+            status,
         }
     }
+
+    fn mounted(&self, path: &str) -> PathBuf {
+        self.mount_directory.join(path)
+    }
+
+    // As if machine had taken the lock on project
+    fn lend(&self, project: &str, machine: &str) {
+        self.index
+            .lock()
+            .unwrap()
+            .lend_project_lock(&PathBuf::from(project), machine.to_string(), None, false)
+            .unwrap();
+    }
+
+    fn refs(&self, project: &str) -> u64 {
+        self.status
+            .project(project)
+            .map_or(0, |project| project.refs)
+    }
+
+    // Release reaches the filesystem after close returns
+    fn wait_for_refs(&self, project: &str, refs: u64) {
+        for _ in 0..100 {
+            if self.refs(project) == refs {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(self.refs(project), refs);
+    }
 }
 
 impl Drop for NimbusTester {
@@ -47,3 +103,297 @@ impl Drop for NimbusTester {
         fs::remove_dir_all(self.mount_directory.clone());
     }
 }
+
+// Runs f on a thread whose file system accesses are made as nobody
+fn as_nobody<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    std::thread::spawn(move || {
+        setfsgid(Gid::from_raw(65534));
+        setfsuid(Uid::from_raw(65534));
+        f()
+    })
+    .join()
+    .unwrap()
+}
+
+// access() checks as the real user, this as the one the thread acts as (see as_nobody)
+fn eaccess(path: &Path, mode: AccessFlags) -> nix::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let res =
+        unsafe { libc::faccessat(libc::AT_FDCWD, path.as_ptr(), mode.bits(), libc::AT_EACCESS) };
+    Errno::result(res).map(drop)
+}
+
+fn errno<T>(result: std::io::Result<T>) -> Option<Errno> {
+    result
+        .err()
+        .and_then(|err| err.raw_os_error())
+        .map(Errno::from_i32)
+}
+
+#[test]
+fn releasing_a_file_renamed_over_drops_its_ref() {
+    let tester = NimbusTester::new();
+    fs::create_dir(tester.mounted("project")).unwrap();
+    fs::write(tester.mounted("project/a"), b"a").unwrap();
+    fs::write(tester.mounted("project/b"), b"b").unwrap();
+    tester.wait_for_refs("project", 1);
+
+    let file = fs::File::open(tester.mounted("project/a")).unwrap();
+    assert_eq!(tester.refs("project"), 2);
+    fs::rename(tester.mounted("project/b"), tester.mounted("project/a")).unwrap();
+    drop(file);
+    tester.wait_for_refs("project", 1);
+    assert_eq!(fs::read(tester.mounted("project/a")).unwrap(), b"b");
+}
+
+#[test]
+fn access_refuses_writes_to_projects_locked_elsewhere() {
+    let tester = NimbusTester::mount(false);
+    fs::create_dir(tester.mounted("project")).unwrap();
+    fs::write(tester.mounted("project/file"), b"").unwrap();
+    access(&tester.mounted("project/file"), AccessFlags::W_OK).unwrap();
+
+    tester.lend("project", "b");
+    assert_eq!(
+        access(&tester.mounted("project/file"), AccessFlags::W_OK),
+        Err(Errno::EACCES)
+    );
+    access(&tester.mounted("project/file"), AccessFlags::R_OK).unwrap();
+}
+
+#[test]
+fn projects_locked_elsewhere_are_read_only() {
+    let tester = NimbusTester::new();
+    fs::create_dir_all(tester.mounted("project/dir")).unwrap();
+    fs::write(tester.mounted("project/file"), b"data").unwrap();
+    tester.lend("project", "b");
+
+    let file = tester.mounted("project/file");
+    assert_eq!(errno(fs::remove_file(&file)), Some(Errno::EROFS));
+    assert_eq!(
+        errno(fs::remove_dir(tester.mounted("project/dir"))),
+        Some(Errno::EROFS)
+    );
+    assert_eq!(
+        errno(fs::create_dir(tester.mounted("project/new"))),
+        Some(Errno::EROFS)
+    );
+    assert_eq!(
+        errno(std::os::unix::fs::symlink(
+            "file",
+            tester.mounted("project/link")
+        )),
+        Some(Errno::EROFS)
+    );
+    assert_eq!(truncate(&file, 0), Err(Errno::EROFS));
+    assert_eq!(
+        errno(fs::set_permissions(
+            &file,
+            fs::Permissions::from_mode(0o600)
+        )),
+        Some(Errno::EROFS)
+    );
+    assert_eq!(fs::read(&file).unwrap(), b"data");
+    assert!(tester.mounted("project/dir").is_dir());
+}
+
+#[test]
+fn mode_bits_are_checked_without_default_permissions() {
+    let tester = NimbusTester::mount(false);
+    fs::create_dir_all(tester.mounted("project/dir")).unwrap();
+    fs::write(tester.mounted("project/file"), b"data").unwrap();
+
+    // the project belongs to root and is only writable by it
+    let project = tester.mounted("project");
+    let failures = as_nobody(move || {
+        let file = project.join("file");
+        [
+            errno(fs::remove_file(&file)),
+            errno(fs::remove_dir(project.join("dir"))),
+            errno(fs::create_dir(project.join("new"))),
+            errno(std::os::unix::fs::symlink("file", project.join("link"))),
+            errno(fs::rename(&file, project.join("moved"))),
+            truncate(&file, 0).err(),
+            errno(fs::set_permissions(
+                &file,
+                fs::Permissions::from_mode(0o666),
+            )),
+        ]
+    });
+    assert_eq!(
+        failures,
+        [
+            Some(Errno::EACCES),
+            Some(Errno::EACCES),
+            Some(Errno::EACCES),
+            Some(Errno::EACCES),
+            Some(Errno::EACCES),
+            Some(Errno::EACCES),
+            Some(Errno::EPERM),
+        ]
+    );
+    assert_eq!(fs::read(tester.mounted("project/file")).unwrap(), b"data");
+}
+
+#[test]
+fn removed_names_come_back_as_new_inodes() {
+    let tester = NimbusTester::new();
+    let (dir, file) = (
+        tester.mounted("project/dir"),
+        tester.mounted("project/file"),
+    );
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(&file, b"old").unwrap();
+    let (dir_ino, file_ino) = (
+        fs::metadata(&dir).unwrap().ino(),
+        fs::metadata(&file).unwrap().ino(),
+    );
+
+    fs::remove_dir(&dir).unwrap();
+    fs::remove_file(&file).unwrap();
+    fs::create_dir(&dir).unwrap();
+    fs::write(&file, b"new").unwrap();
+    assert_ne!(fs::metadata(&dir).unwrap().ino(), dir_ino);
+    assert_ne!(fs::metadata(&file).unwrap().ino(), file_ino);
+    assert_eq!(fs::read(&file).unwrap(), b"new");
+}
+
+#[test]
+fn rename_noreplace_and_exchange() {
+    let _inode_numbers = INODE_NUMBERS.lock().unwrap();
+    let tester = NimbusTester::new();
+    let (a, b) = (tester.mounted("project/a"), tester.mounted("project/b"));
+    fs::create_dir(tester.mounted("project")).unwrap();
+    fs::write(&a, b"a").unwrap();
+    fs::write(&b, b"b").unwrap();
+    let (a_ino, b_ino) = (
+        fs::metadata(&a).unwrap().ino(),
+        fs::metadata(&b).unwrap().ino(),
+    );
+
+    assert_eq!(
+        renameat2(None, &a, None, &b, RenameFlags::RENAME_NOREPLACE),
+        Err(Errno::EEXIST)
+    );
+    assert_eq!(fs::read(&b).unwrap(), b"b");
+
+    let opened = fs::File::open(&a).unwrap();
+    renameat2(None, &a, None, &b, RenameFlags::RENAME_EXCHANGE).unwrap();
+    assert_eq!(fs::read(&a).unwrap(), b"b");
+    assert_eq!(fs::read(&b).unwrap(), b"a");
+    assert_eq!(fs::metadata(&a).unwrap().ino(), b_ino);
+    assert_eq!(fs::metadata(&b).unwrap().ino(), a_ino);
+    assert_eq!(opened.metadata().unwrap().ino(), a_ino);
+
+    let c = tester.mounted("project/c");
+    renameat2(None, &a, None, &c, RenameFlags::RENAME_NOREPLACE).unwrap();
+    assert_eq!(fs::read(&c).unwrap(), b"b");
+    assert!(!a.exists());
+}
+
+#[test]
+fn fsync_writes_buffered_data_through() {
+    let tester = NimbusTester::new();
+    fs::create_dir(tester.mounted("project")).unwrap();
+    let stored = tester.local_storage.join("project/file");
+
+    let mut file = fs::File::create(tester.mounted("project/file")).unwrap();
+    file.write_all(b"data").unwrap();
+    file.sync_data().unwrap();
+    assert_eq!(fs::read(&stored).unwrap(), b"data");
+    file.write_all(b"more").unwrap();
+    file.sync_all().unwrap();
+    assert_eq!(fs::read(&stored).unwrap(), b"datamore");
+
+    let dir = fs::File::open(tester.mounted("project")).unwrap();
+    dir.sync_all().unwrap();
+    dir.sync_data().unwrap();
+}
+
+#[test]
+fn access_checks_the_mode_bits() {
+    let tester = NimbusTester::mount(false);
+    fs::create_dir(tester.mounted("project")).unwrap();
+    let (file, script) = (
+        tester.mounted("project/file"),
+        tester.mounted("project/script"),
+    );
+    fs::write(&file, b"").unwrap();
+    fs::write(&script, b"").unwrap();
+    fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+    // root may do anything but execute what nobody may execute
+    access(&file, AccessFlags::R_OK | AccessFlags::W_OK).unwrap();
+    assert_eq!(access(&file, AccessFlags::X_OK), Err(Errno::EACCES));
+    access(&script, AccessFlags::X_OK).unwrap();
+    assert_eq!(
+        access(&tester.mounted("project/missing"), AccessFlags::F_OK),
+        Err(Errno::ENOENT)
+    );
+
+    let checks = as_nobody(move || {
+        [
+            eaccess(&file, AccessFlags::F_OK),
+            eaccess(&file, AccessFlags::R_OK),
+            eaccess(&file, AccessFlags::W_OK),
+            eaccess(&file, AccessFlags::X_OK),
+            eaccess(&script, AccessFlags::R_OK | AccessFlags::X_OK),
+        ]
+    });
+    assert_eq!(
+        checks,
+        [
+            Ok(()),
+            Ok(()),
+            Err(Errno::EACCES),
+            Err(Errno::EACCES),
+            Ok(())
+        ]
+    );
+}
+
+/// Held by the tests that compare inode numbers, which dropping the caches renumbers
+static INODE_NUMBERS: Mutex<()> = Mutex::new(());
+
+// Makes the kernel forget every dentry and inode it doesn't need (of every mount)
+fn drop_caches() {
+    fs::write("/proc/sys/vm/drop_caches", b"2").unwrap();
+    // forgets reach the filesystem after the write returns
+    std::thread::sleep(Duration::from_millis(100));
+}
+
+#[test]
+fn readdirplus_entries_are_counted_as_lookups() {
+    let _inode_numbers = INODE_NUMBERS.lock().unwrap();
+    let tester = NimbusTester::new();
+    fs::create_dir(tester.mounted("project")).unwrap();
+    let names: Vec<String> = (0..20).map(|i| format!("file{}", i)).collect();
+    for name in &names {
+        fs::write(tester.mounted(&format!("project/{}", name)), b"").unwrap();
+    }
+    drop_caches();
+
+    // listing hands out entries (and their attributes) the kernel needs no lookup for
+    let listed: Vec<(String, u64)> = fs::read_dir(tester.mounted("project"))
+        .unwrap()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            (entry.file_name().into_string().unwrap(), entry.ino())
+        })
+        .collect();
+    assert_eq!(listed.len(), names.len());
+    for (name, ino) in &listed {
+        let path = tester.mounted(&format!("project/{}", name));
+        assert_eq!(fs::metadata(path).unwrap().ino(), *ino);
+    }
+
+    // once the kernel forgets them, so does nimbus: the names come back as new inodes
+    // (with the directory held open, so they aren't pruned along with it)
+    let _project = fs::File::open(tester.mounted("project")).unwrap();
+    drop_caches();
+    for (name, ino) in &listed {
+        let path = tester.mounted(&format!("project/{}", name));
+        assert_ne!(fs::metadata(path).unwrap().ino(), *ino);
+    }
+}
@@ -0,0 +1,209 @@
+use nimbus::auth::PeerSecrets;
+use nimbus::client::{ClientError, LockReply, PeerClient};
+use nimbus::config::{read_config, Config, TlsConfig, TuningConfig};
+use nimbus::health::Prober;
+use nimbus::index::Index;
+use nimbus::server;
+use nimbus::status::Status;
+use nimbus::sync::Syncer;
+use nimbus::tls::{fingerprint, read_certificates};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, SanType};
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::net::TcpStream;
+
+const SECRET: &str = "tls-test-secret";
+
+// A port nothing listens on (the OS picks it, so parallel tests never collide)
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+// Waits for the server spawned on port to accept connections
+async fn listening(port: u16) {
+    for _ in 0..500 {
+        if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("nothing listens on port {}", port);
+}
+
+struct Certificates {
+    dir: TempDir,
+    ca: PathBuf,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+// A throwaway CA and a server certificate for 127.0.0.1 signed by it
+fn generate_certificates() -> Certificates {
+    let mut ca_params = CertificateParams::new(Vec::new());
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = Certificate::from_params(ca_params).unwrap();
+
+    let mut params = CertificateParams::new(Vec::new());
+    params.subject_alt_names = vec![SanType::IpAddress(IpAddr::V4(Ipv4Addr::LOCALHOST))];
+    let cert = Certificate::from_params(params).unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let certificates = Certificates {
+        ca: dir.path().join("ca.pem"),
+        cert: dir.path().join("cert.pem"),
+        key: dir.path().join("key.pem"),
+        dir,
+    };
+    std::fs::write(&certificates.ca, ca.serialize_pem().unwrap()).unwrap();
+    std::fs::write(
+        &certificates.cert,
+        cert.serialize_pem_with_signer(&ca).unwrap(),
+    )
+    .unwrap();
+    std::fs::write(&certificates.key, cert.serialize_private_key_pem()).unwrap();
+    certificates
+}
+
+fn client_config(dir: &Path, port: u16, trust: &str) -> Config {
+    let path = dir.join(format!("client-{}.toml", port));
+    std::fs::write(
+        &path,
+        format!(
+            r#"
+[machine]
+name = "client"
+mode = "DevelopmentMode"
+endpoint = "127.0.0.1:0"
+
+[network.server]
+command = "cp -r {{HERE}} {{THERE}}"
+endpoint = "127.0.0.1:{}"
+secret = "{}"
+{}
+"#,
+            port, SECRET, trust
+        ),
+    )
+    .unwrap();
+    read_config(path).unwrap()
+}
+
+// Serves a single unlocked project "project" over TLS on 127.0.0.1, returning the port
+async fn serve(certificates: &Certificates) -> u16 {
+    let port = free_port();
+    let index = Arc::new(Mutex::new(Index::new()));
+    index
+        .lock()
+        .unwrap()
+        .register_project(PathBuf::from("project"));
+    let activity = Arc::new(Mutex::new(FxHashMap::default()));
+    let status = Status::new(
+        index.clone(),
+        Arc::new(Mutex::new(FxHashMap::default())),
+        Arc::clone(&activity),
+        Arc::new(Mutex::new(FxHashSet::default())),
+        Arc::new(Mutex::new(FxHashMap::default())),
+    );
+    let syncer = Syncer::new(
+        &client_config(certificates.dir.path(), port, ""),
+        certificates.dir.path().to_path_buf(),
+        activity,
+    );
+    let secrets: PeerSecrets = Arc::new(RwLock::new(HashMap::from([(
+        "client".to_string(),
+        SECRET.to_string(),
+    )])));
+    let tls = TlsConfig {
+        cert: certificates.cert.clone(),
+        key: certificates.key.clone(),
+    };
+    let prober = Prober::new(
+        syncer.clone(),
+        Arc::new(Mutex::new(TuningConfig::default())),
+    );
+    tokio::spawn(server::build(
+        server::routes(index, status, prober, secrets, syncer),
+        format!("127.0.0.1:{}", port),
+        Some(tls),
+        std::future::pending(),
+    ));
+    listening(port).await;
+    port
+}
+
+#[tokio::test]
+async fn trusts_peer_signed_by_pinned_ca() {
+    let certificates = generate_certificates();
+    let port = serve(&certificates).await;
+
+    let trust = format!("ca = {:?}", certificates.ca);
+    let config = client_config(certificates.dir.path(), port, &trust);
+    let client = PeerClient::new(&config, "server").unwrap();
+    assert!(matches!(
+        client.acquire_project_lock("project").await,
+        Ok(LockReply::Granted(_))
+    ));
+}
+
+#[tokio::test]
+async fn trusts_peer_with_pinned_fingerprint() {
+    let certificates = generate_certificates();
+    let port = serve(&certificates).await;
+
+    let der = read_certificates(&certificates.cert).unwrap().remove(0);
+    let trust = format!("fingerprint = {:?}", fingerprint(&der).to_uppercase());
+    let config = client_config(certificates.dir.path(), port, &trust);
+    let client = PeerClient::new(&config, "server").unwrap();
+    assert!(matches!(
+        client.acquire_project_lock("project").await,
+        Ok(LockReply::Granted(_))
+    ));
+}
+
+#[tokio::test]
+async fn rejects_peer_with_other_fingerprint() {
+    let certificates = generate_certificates();
+    let port = serve(&certificates).await;
+
+    let trust = format!("fingerprint = {:?}", "00".repeat(32));
+    let config = client_config(certificates.dir.path(), port, &trust);
+    let client = PeerClient::new(&config, "server").unwrap();
+    assert!(matches!(
+        client.acquire_project_lock("project").await,
+        Err(ClientError::Unreachable(_))
+    ));
+}
+
+#[tokio::test]
+async fn rejects_peer_signed_by_other_ca() {
+    let certificates = generate_certificates();
+    let other = generate_certificates();
+    let port = serve(&certificates).await;
+
+    let trust = format!("ca = {:?}", other.ca);
+    let config = client_config(certificates.dir.path(), port, &trust);
+    let client = PeerClient::new(&config, "server").unwrap();
+    assert!(matches!(
+        client.acquire_project_lock("project").await,
+        Err(ClientError::Unreachable(_))
+    ));
+}
+
+#[tokio::test]
+async fn plain_http_is_refused() {
+    let certificates = generate_certificates();
+    let port = serve(&certificates).await;
+
+    let config = client_config(certificates.dir.path(), port, "");
+    let client = PeerClient::new(&config, "server").unwrap();
+    assert!(client.acquire_project_lock("project").await.is_err());
+}